@@ -25,7 +25,7 @@ fn main() {
 	};
 
 	match imap_socket.select("INBOX") {
-		Ok(IMAPMailbox{flags, exists, recent, unseen, permanent_flags, uid_next, uid_validity}) => {
+		Ok(IMAPMailbox{flags, exists, recent, unseen, permanent_flags, uid_next, uid_validity, ..}) => {
 			println!("flags: {}, exists: {}, recent: {}, unseen: {:?}, permanent_flags: {:?}, uid_next: {:?}, uid_validity: {:?}", flags, exists, recent, unseen, permanent_flags, uid_next, uid_validity);
 		},
 		Err(_) => println!("Error selecting INBOX")
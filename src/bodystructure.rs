@@ -0,0 +1,297 @@
+use error::{ImapError, Result};
+use parser::Token;
+
+/// The MIME structure of a message, as returned by `FETCH BODYSTRUCTURE`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyStructure {
+	/// A multipart body, e.g. `multipart/mixed`, made up of child parts.
+	Multipart {
+		subtype: String,
+		params: Vec<(String, String)>,
+		parts: Vec<BodyStructure>
+	},
+	/// A single, non-multipart body part.
+	Single(BodyStructurePart)
+}
+
+/// A single body part. The section is the part specifier to pass to `BODY[<section>]` to fetch
+/// just this part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyStructurePart {
+	pub section: String,
+	pub media_type: String,
+	pub subtype: String,
+	pub params: Vec<(String, String)>,
+	pub id: Option<String>,
+	pub description: Option<String>,
+	pub encoding: String,
+	pub size: u32,
+	/// The number of lines, for `text/*` and `message/rfc822` parts.
+	pub lines: Option<u32>,
+	/// The Content-Disposition type, e.g. `attachment` or `inline`, if the server sent one.
+	pub disposition: Option<String>,
+	pub disposition_params: Vec<(String, String)>,
+	/// The structure of the encapsulated message of a `message/rfc822` part.
+	pub body: Option<Box<BodyStructure>>
+}
+
+impl BodyStructure {
+	/// Builds a body structure from the parenthesized list following the BODYSTRUCTURE data item.
+	pub fn from_token(token: &Token) -> Option<BodyStructure> {
+		parse_body(token, "1".to_string(), None)
+	}
+
+	/// Returns every single part in the structure in depth-first order, including the parts of
+	/// encapsulated messages.
+	pub fn parts(&self) -> Vec<&BodyStructurePart> {
+		let mut parts = Vec::new();
+		self.collect_parts(&mut parts);
+		parts
+	}
+
+	fn collect_parts<'a>(&'a self, parts: &mut Vec<&'a BodyStructurePart>) {
+		match *self {
+			BodyStructure::Multipart { parts: ref children, .. } => {
+				for child in children.iter() {
+					child.collect_parts(parts);
+				}
+			},
+			BodyStructure::Single(ref part) => {
+				parts.push(part);
+				if let Some(ref body) = part.body {
+					body.collect_parts(parts);
+				}
+			}
+		}
+	}
+}
+
+impl BodyStructurePart {
+	/// Returns the file name of the part from its disposition or content type parameters.
+	pub fn filename(&self) -> Option<&str> {
+		find_param(&self.disposition_params, "FILENAME").or_else(|| find_param(&self.params, "NAME"))
+	}
+
+	/// Decodes the fetched contents of the part according to its transfer encoding. Base64 and
+	/// quoted-printable are decoded; 7bit, 8bit and binary data is returned as it is.
+	pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+		if self.encoding.eq_ignore_ascii_case("BASE64") {
+			let encoded: Vec<u8> = data.iter().cloned().filter(|b| !b.is_ascii_whitespace()).collect();
+			match base64::decode(&encoded) {
+				Ok(decoded) => Ok(decoded),
+				Err(e) => Err(ImapError::Parse(format!("Invalid base64 in part {}: {}", self.section, e)))
+			}
+		} else if self.encoding.eq_ignore_ascii_case("QUOTED-PRINTABLE") {
+			match decode_quoted_printable(data) {
+				Some(decoded) => Ok(decoded),
+				None => Err(ImapError::Parse(format!("Invalid quoted-printable data in part {}", self.section)))
+			}
+		} else if ["7BIT", "8BIT", "BINARY"].iter().any(|encoding| self.encoding.eq_ignore_ascii_case(encoding)) {
+			Ok(data.to_vec())
+		} else {
+			Err(ImapError::Parse(format!("Unknown transfer encoding {} of part {}", self.encoding, self.section)))
+		}
+	}
+}
+
+/// Decodes quoted-printable data, joining soft line breaks. Returns `None` for an invalid escape.
+fn decode_quoted_printable(data: &[u8]) -> Option<Vec<u8>> {
+	let mut decoded = Vec::with_capacity(data.len());
+	let mut index = 0;
+	while index < data.len() {
+		if data[index] != b'=' {
+			decoded.push(data[index]);
+			index += 1;
+			continue;
+		}
+
+		let rest = &data[index + 1..];
+		if rest.starts_with(b"\r\n") {
+			index += 3;
+		} else if rest.starts_with(b"\n") {
+			index += 2;
+		} else if rest.is_empty() {
+			index += 1;
+		} else {
+			let hex = match rest.get(..2).and_then(|hex| ::std::str::from_utf8(hex).ok()) {
+				Some(hex) => hex,
+				None => return None
+			};
+			match u8::from_str_radix(hex, 16) {
+				Ok(byte) => decoded.push(byte),
+				Err(_) => return None
+			}
+			index += 3;
+		}
+	}
+	Some(decoded)
+}
+
+/// Parses a body. `section` is the part number of the body itself; `child_prefix` is the part
+/// number its children are numbered under, which is `None` for the message's top-level body.
+fn parse_body(token: &Token, section: String, child_prefix: Option<String>) -> Option<BodyStructure> {
+	let items = match *token {
+		Token::List(ref items) if !items.is_empty() => items,
+		_ => return None
+	};
+
+	if let Token::List(_) = items[0] {
+		let mut parts = Vec::new();
+		let mut index = 0;
+		while index < items.len() {
+			if let Token::List(_) = items[index] {
+				let child_section = match child_prefix {
+					Some(ref prefix) => format!("{}.{}", prefix, index + 1),
+					None => (index + 1).to_string()
+				};
+				match parse_body(&items[index], child_section.clone(), Some(child_section)) {
+					Some(part) => parts.push(part),
+					None => return None
+				}
+				index += 1;
+			} else {
+				break;
+			}
+		}
+
+		let subtype = match items.get(index).and_then(Token::as_str) {
+			Some(subtype) => subtype.to_string(),
+			None => return None
+		};
+		let params = items.get(index + 1).map(parse_params).unwrap_or(Vec::new());
+		return Some(BodyStructure::Multipart {
+			subtype: subtype,
+			params: params,
+			parts: parts
+		});
+	}
+
+	if items.len() < 7 {
+		return None;
+	}
+	let media_type = match items[0].as_str() {
+		Some(media_type) => media_type.to_string(),
+		None => return None
+	};
+	let subtype = match items[1].as_str() {
+		Some(subtype) => subtype.to_string(),
+		None => return None
+	};
+	let size = match items[6].as_str().and_then(|s| s.parse::<u32>().ok()) {
+		Some(size) => size,
+		None => return None
+	};
+
+	let is_text = media_type.eq_ignore_ascii_case("TEXT");
+	let is_message = media_type.eq_ignore_ascii_case("MESSAGE") && subtype.eq_ignore_ascii_case("RFC822");
+
+	let (lines, body, extension_start) = if is_message && items.len() >= 10 {
+		let nested_prefix = match child_prefix {
+			Some(prefix) => prefix,
+			None => section.clone()
+		};
+		let body = match parse_body(&items[8], format!("{}.1", nested_prefix), Some(nested_prefix)) {
+			Some(body) => body,
+			None => return None
+		};
+		(items[9].as_str().and_then(|s| s.parse::<u32>().ok()), Some(Box::new(body)), 10)
+	} else if is_text && items.len() >= 8 {
+		(items[7].as_str().and_then(|s| s.parse::<u32>().ok()), None, 8)
+	} else {
+		(None, None, 7)
+	};
+
+	// The extension data starts with the MD5, followed by the disposition.
+	let (disposition, disposition_params) = match items.get(extension_start + 1).and_then(Token::as_list) {
+		Some(disposition) if !disposition.is_empty() => (
+			disposition[0].as_str().map(|s| s.to_string()),
+			disposition.get(1).map(parse_params).unwrap_or(Vec::new())
+		),
+		_ => (None, Vec::new())
+	};
+
+	Some(BodyStructure::Single(BodyStructurePart {
+		section: section,
+		media_type: media_type,
+		subtype: subtype,
+		params: parse_params(&items[2]),
+		id: items[3].as_str().map(|s| s.to_string()),
+		description: items[4].as_str().map(|s| s.to_string()),
+		encoding: items[5].as_str().unwrap_or("7BIT").to_string(),
+		size: size,
+		lines: lines,
+		disposition: disposition,
+		disposition_params: disposition_params,
+		body: body
+	}))
+}
+
+fn parse_params(token: &Token) -> Vec<(String, String)> {
+	let items = token.as_list().unwrap_or(&[]);
+	items.chunks(2).filter_map(|pair| {
+		match (pair.get(0).and_then(Token::as_str), pair.get(1).and_then(Token::as_str)) {
+			(Some(name), Some(value)) => Some((name.to_string(), value.to_string())),
+			_ => None
+		}
+	}).collect()
+}
+
+fn find_param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+	params.iter().find(|param| param.0.eq_ignore_ascii_case(name)).map(|param| &*param.1)
+}
+
+#[test]
+fn parse_nested_bodystructure() {
+    use parser::parse_fetch_responses;
+
+    let lines = vec![
+        "* 1 FETCH (BODYSTRUCTURE (((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"UTF-8\") NIL NIL \"7BIT\" 12 1 NIL NIL NIL)(\"TEXT\" \"HTML\" NIL NIL NIL \"QUOTED-PRINTABLE\" 40 2) \"ALTERNATIVE\") (\"APPLICATION\" \"PDF\" (\"NAME\" \"a.pdf\") NIL NIL \"BASE64\" 3000 NIL (\"ATTACHMENT\" (\"FILENAME\" \"report.pdf\")) NIL) (\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 500 (NIL \"Fwd\" NIL NIL NIL NIL NIL NIL NIL NIL) (\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 20 2) 10) \"MIXED\"))\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let fetches = parse_fetch_responses(&lines).unwrap();
+    let structure = BodyStructure::from_token(&fetches[0].1[1]).unwrap();
+
+    let parts = structure.parts();
+    let sections: Vec<&str> = parts.iter().map(|part| &*part.section).collect();
+    assert_eq!(sections, vec!["1.1", "1.2", "2", "3", "3.1"]);
+    assert_eq!(parts[1].encoding, "QUOTED-PRINTABLE");
+    assert_eq!(parts[2].disposition, Some("ATTACHMENT".to_string()));
+    assert_eq!(parts[2].filename(), Some("report.pdf"));
+    assert_eq!(parts[2].size, 3000);
+    assert_eq!(parts[3].lines, Some(10));
+
+    let single = vec![
+        "* 2 FETCH (BODYSTRUCTURE (\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 5 1))\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let fetches = parse_fetch_responses(&single).unwrap();
+    let structure = BodyStructure::from_token(&fetches[0].1[1]).unwrap();
+    assert_eq!(structure.parts()[0].section, "1");
+}
+
+#[test]
+fn decode_handles_transfer_encodings() {
+    let mut part = BodyStructurePart {
+        section: "2".to_string(),
+        media_type: "TEXT".to_string(),
+        subtype: "PLAIN".to_string(),
+        params: Vec::new(),
+        id: None,
+        description: None,
+        encoding: "BASE64".to_string(),
+        size: 0,
+        lines: None,
+        disposition: None,
+        disposition_params: Vec::new(),
+        body: None
+    };
+    assert_eq!(part.decode(b"SGVsbG8s\r\nIHdvcmxk\r\n").unwrap(), b"Hello, world".to_vec());
+    assert!(part.decode(b"not base64!").is_err());
+
+    part.encoding = "quoted-printable".to_string();
+    assert_eq!(part.decode(b"caf=C3=A9 is a soft=\r\n break").unwrap(), "caf\u{e9} is a soft break".as_bytes().to_vec());
+    assert!(part.decode(b"bad =ZZ escape").is_err());
+
+    part.encoding = "7BIT".to_string();
+    assert_eq!(part.decode(b"as is=").unwrap(), b"as is=".to_vec());
+}
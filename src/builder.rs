@@ -0,0 +1,142 @@
+use openssl::ssl::SslContext;
+use std::io;
+use std::time::Duration;
+
+use client::{GreetingStatus, IMAPStream};
+use error::{ImapError, Result};
+
+/// Builds an `IMAPStream`, taking care of the connection, optional STARTTLS upgrade and login
+/// in a single call.
+///
+/// When a TLS context is given the connection defaults to port 993 with implicit TLS, otherwise
+/// it defaults to plaintext on port 143. Calling `starttls` connects in plaintext on port 143
+/// and upgrades the connection with the given TLS context before logging in.
+#[derive(Default)]
+pub struct IMAPClientBuilder {
+	host: Option<String>,
+	port: Option<u16>,
+	tls: Option<SslContext>,
+	starttls: bool,
+	credentials: Option<(String, String)>,
+	timeout: Option<Duration>,
+	read_buffer_capacity: Option<usize>
+}
+
+impl IMAPClientBuilder {
+	/// Creates a builder with no host set.
+	pub fn new() -> IMAPClientBuilder {
+		IMAPClientBuilder {
+			host: None,
+			port: None,
+			tls: None,
+			starttls: false,
+			credentials: None,
+			timeout: None,
+			read_buffer_capacity: None
+		}
+	}
+
+	/// Sets the host name of the server.
+	pub fn host(mut self, host: &str) -> IMAPClientBuilder {
+		self.host = Some(host.to_string());
+		self
+	}
+
+	/// Sets the port, overriding the default of 993 for TLS and 143 for plaintext and STARTTLS.
+	pub fn port(mut self, port: u16) -> IMAPClientBuilder {
+		self.port = Some(port);
+		self
+	}
+
+	/// Secures the connection with the given TLS context.
+	pub fn tls(mut self, ssl_context: SslContext) -> IMAPClientBuilder {
+		self.tls = Some(ssl_context);
+		self
+	}
+
+	/// Connects in plaintext and upgrades to TLS with STARTTLS. Requires a context set with `tls`.
+	pub fn starttls(mut self) -> IMAPClientBuilder {
+		self.starttls = true;
+		self
+	}
+
+	/// Logs in with the given credentials once connected.
+	pub fn credentials(mut self, username: &str, password: &str) -> IMAPClientBuilder {
+		self.credentials = Some((username.to_string(), password.to_string()));
+		self
+	}
+
+	/// Sets the timeout used for connecting and for every read from the server.
+	pub fn timeout(mut self, timeout: Duration) -> IMAPClientBuilder {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Sets the capacity of the buffer responses are read through. See
+	/// `IMAPStream::set_read_buffer_capacity`.
+	pub fn read_buffer_capacity(mut self, capacity: usize) -> IMAPClientBuilder {
+		self.read_buffer_capacity = Some(capacity);
+		self
+	}
+
+	/// Connects to the server, reading the greeting, upgrading with STARTTLS and logging in as
+	/// configured.
+	pub fn connect(self) -> Result<IMAPStream> {
+		let host = match self.host {
+			Some(host) => host,
+			None => return Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidInput, "No host was given")))
+		};
+
+		let (implicit_tls, starttls) = if self.starttls {
+			match self.tls {
+				Some(ssl_context) => (None, Some(ssl_context)),
+				None => return Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidInput, "STARTTLS requires a TLS context")))
+			}
+		} else {
+			(self.tls, None)
+		};
+		let port = self.port.unwrap_or(if implicit_tls.is_some() { 993 } else { 143 });
+
+		let mut stream = match self.timeout {
+			Some(timeout) => try!(IMAPStream::connect_with_timeout((&*host, port), implicit_tls, timeout)),
+			None => try!(IMAPStream::connect((&*host, port), implicit_tls))
+		};
+		if self.timeout.is_some() {
+			try!(stream.set_read_timeout(self.timeout));
+		}
+		if let Some(capacity) = self.read_buffer_capacity {
+			stream.set_read_buffer_capacity(capacity);
+		}
+
+		if let Some(ssl_context) = starttls {
+			try!(stream.starttls(ssl_context));
+		}
+
+		if let Some((username, password)) = self.credentials {
+			// A PREAUTH greeting means the connection is already authenticated.
+			if stream.greeting().status != GreetingStatus::PreAuth {
+				try!(stream.login(&username, &password));
+			}
+		}
+
+		Ok(stream)
+	}
+}
+
+#[test]
+fn connect_logs_in_with_credentials() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 LOGIN user \"pass word\"", "a1 OK LOGIN completed\r\n")
+    ]);
+
+    IMAPClientBuilder::new()
+        .host("127.0.0.1")
+        .port(server.addr().port())
+        .credentials("user", "pass word")
+        .timeout(Duration::from_secs(5))
+        .connect()
+        .unwrap();
+    server.finish();
+}
@@ -1,20 +1,95 @@
-use std::net::{TcpStream, ToSocketAddrs};
+use chrono::{DateTime, FixedOffset};
+use email::MimeMessage;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(feature = "openssl")]
 use openssl::ssl::{SslContext, SslStream};
-use std::io::{Error, ErrorKind, Read, Result, Write};
+#[cfg(feature = "rustls-tls")]
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "rustls-tls")]
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
 use regex::Regex;
 
-enum IMAPStreamTypes {
-	Basic(TcpStream),
-	Ssl(SslStream<TcpStream>)
+use bodystructure::{BodyStructure, BodyStructurePart};
+use connection::{Connection, IMAPStreamTypes};
+use envelope::Envelope;
+use error::{ImapError, ResponseCode, ResponseText, Result};
+use flag::{Flag, FlagOp};
+use parser::{find_item, literal_length, parse_date_time, parse_fetch_responses, parse_tokens, Token};
+use search::SearchCriteria;
+use sequence::SequenceSet;
+use thread::ThreadNode;
+use utf7::{decode_utf7, encode_utf7};
+
+/// How long `IMAPStream::is_alive` waits for the server to answer.
+const ALIVE_TIMEOUT_SECS: u64 = 5;
+
+/// The most UIDs a UID set in a response is expanded to, so that a set like `1:4294967295` can't
+/// exhaust memory.
+const MAX_EXPANDED_UIDS: usize = 1 << 20;
+
+/// How long dropping an `IMAPStream` may block sending LOGOUT to a server that stopped reading.
+const DROP_WRITE_TIMEOUT_SECS: u64 = 1;
+
+/// How long `IMAPStream::idle_loop` leaves an IDLE command running before renewing it. Servers
+/// may end an IDLE after 30 minutes.
+const IDLE_RENEWAL_SECS: u64 = 29 * 60;
+
+/// How many unsolicited responses are kept for `IMAPStream::take_unsolicited`. Older ones are
+/// dropped so that a caller that never takes them doesn't leak memory.
+const MAX_UNSOLICITED: usize = 1024;
+
+/// How many times `IMAPStream::idle_resilient` tries to reconnect before giving up, and how long
+/// it waits before the second attempt. The wait doubles after each failed attempt.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY_SECS: u64 = 1;
+
+/// How a connection was secured, kept so `reconnect` can secure a new connection the same way.
+enum Security {
+	Plain,
+	#[cfg(feature = "openssl")]
+	Ssl(SslContext),
+	#[cfg(feature = "openssl")]
+	Starttls(SslContext),
+	#[cfg(feature = "rustls-tls")]
+	Rustls(ServerName, Arc<ClientConfig>),
+	#[cfg(feature = "rustls-tls")]
+	StarttlsRustls(ServerName, Arc<ClientConfig>)
 }
 
 /// Stream to interface with the IMAP server. This interface is only for the command stream.
 pub struct IMAPStream {
-	stream: IMAPStreamTypes,
+	connection: Connection,
+	/// Where `reconnect` connects to, `None` for streams created over a caller's socket.
+	addr: Option<SocketAddr>,
+	security: Security,
+	read_timeout: Option<Duration>,
 	tag: u32,
-	tag_prefix: &'static str
+	tag_prefix: &'static str,
+	capabilities: Option<Vec<String>>,
+	logged_out: bool,
+	unsolicited: Vec<Unsolicited>,
+	pipelined_untagged: Vec<String>,
+	pipelined_responses: HashMap<String, Vec<String>>,
+	delimiter: Option<Option<char>>,
+	greeting: Greeting,
+	bye: Option<ResponseText>,
+	dead: bool,
+	mailbox: Option<IMAPMailbox>,
+	/// The name the selected mailbox was opened with.
+	mailbox_name: Option<String>,
+	state: ConnectionState
 }
 
+#[derive(Debug, Clone)]
 pub struct IMAPMailbox {
 	pub flags: String,
 	pub exists: u32,
@@ -22,40 +97,747 @@ pub struct IMAPMailbox {
 	pub unseen: Option<u32>,
 	pub permanent_flags: Option<String>,
 	pub uid_next: Option<u32>,
-	pub uid_validity: Option<u32>
+	pub uid_validity: Option<u32>,
+	/// The highest mod-sequence of the mailbox, sent by CONDSTORE servers. `None` if the server
+	/// sent NOMODSEQ.
+	pub highest_modseq: Option<u64>,
+	/// The server's unique and persistent identifier for the mailbox, from RFC 8474.
+	pub mailbox_id: Option<String>,
+	/// Whether the mailbox was opened read-only, in which case STORE and EXPUNGE will be
+	/// rejected. A server may open a mailbox read-only even when it was selected with SELECT.
+	pub read_only: bool,
+	/// The UIDs of messages expunged since the state passed to `select_qresync`.
+	pub vanished: Vec<u32>,
+	/// The messages changed since the state passed to `select_qresync`, keyed by message
+	/// sequence number.
+	pub changed: HashMap<u32, FetchResult>
+}
+
+impl IMAPMailbox {
+	/// Parses the PERMANENTFLAGS of the mailbox: the flags whose changes are kept after the
+	/// session ends. A `\*` in the list, as `Flag::Custom("\\*")`, means new keywords can be
+	/// created. `None` if the server didn't send PERMANENTFLAGS.
+	pub fn permanent_flag_list(&self) -> Option<Vec<Flag>> {
+		self.permanent_flags.as_ref().map(|flags| {
+			flags.trim_matches(|c| c == '(' || c == ')').split_whitespace().filter_map(|flag| flag.parse().ok()).collect()
+		})
+	}
+
+	/// Whether keywords other than those in PERMANENTFLAGS can be set, which the server signals
+	/// with `\*`.
+	pub fn allows_new_keywords(&self) -> bool {
+		match self.permanent_flag_list() {
+			Some(flags) => flags.contains(&Flag::Custom("\\*".to_string())),
+			None => false
+		}
+	}
+}
+
+/// The data items of a message returned by `IMAPStream::fetch_items` and
+/// `IMAPStream::fetch_changed_since`. Items that weren't fetched are `None` or empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchResult {
+	pub flags: Option<Vec<Flag>>,
+	pub uid: Option<u32>,
+	pub internal_date: Option<DateTime<FixedOffset>>,
+	pub rfc822_size: Option<u32>,
+	pub envelope: Option<Envelope>,
+	/// The structure of the message, from BODYSTRUCTURE or the BODY item fetched by FULL.
+	pub body_structure: Option<BodyStructure>,
+	/// The contents of body sections such as `BODY[TEXT]` or `RFC822.HEADER`, keyed by the item
+	/// name the server sent.
+	pub body_sections: HashMap<String, Vec<u8>>,
+	/// The mod-sequence of the message's metadata.
+	pub modseq: Option<u64>,
+	/// Every data item of the response, as name and value pairs, including MODSEQ.
+	pub items: Vec<Token>
+}
+
+/// The macros FETCH accepts in place of a list of data items.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FetchMacro {
+	/// `FLAGS INTERNALDATE RFC822.SIZE ENVELOPE`, what a message list needs.
+	All,
+	/// `FLAGS INTERNALDATE RFC822.SIZE`.
+	Fast,
+	/// `FLAGS INTERNALDATE RFC822.SIZE ENVELOPE BODY`.
+	Full
+}
+
+impl fmt::Display for FetchMacro {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			FetchMacro::All => write!(f, "ALL"),
+			FetchMacro::Fast => write!(f, "FAST"),
+			FetchMacro::Full => write!(f, "FULL")
+		}
+	}
+}
+
+/// The greeting the server sends when a connection is opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Greeting {
+	pub status: GreetingStatus,
+	/// The capabilities sent in a CAPABILITY response code, if any.
+	pub capabilities: Vec<String>,
+	pub text: String
+}
+
+/// The state of the connection, which decides the commands that can be sent. Commands sent in the
+/// wrong state fail with `ImapError::InvalidState` without reaching the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+	/// Connected but not logged in yet.
+	NotAuthenticated,
+	/// Logged in, or pre-authenticated by the server, with no mailbox selected.
+	Authenticated,
+	/// Logged in with a mailbox selected.
+	Selected
+}
+
+/// The status of a server greeting. A server rejecting the connection with BYE makes connecting
+/// fail with `ImapError::Bye` instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GreetingStatus {
+	/// The connection is not authenticated yet; log in before anything else.
+	Ok,
+	/// The connection is already authenticated, so logging in can be skipped.
+	PreAuth
+}
+
+/// A mailbox change the server reported in an untagged response, possibly during an unrelated
+/// command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unsolicited {
+	/// The mailbox now holds this many messages.
+	Exists(u32),
+	/// This many messages have the \Recent flag set.
+	Recent(u32),
+	/// The message with this sequence number was expunged.
+	Expunge(u32),
+	/// An untagged OK carrying a response code, such as `[ALERT]` or a new `[UIDNEXT n]`.
+	Ok(ResponseText),
+	/// An untagged NO, a warning from the server that doesn't fail the command.
+	No(ResponseText),
+	/// An `[ALERT]` sent on the tagged response of a command, which must be shown to the user.
+	Alert(ResponseText),
+	/// The connection was lost during `idle_resilient` and has been reopened, with the mailbox
+	/// selected again.
+	Reconnected {
+		/// The number of messages in the mailbox after reconnecting.
+		exists: u32,
+		/// The UIDs of the messages that arrived while the connection was down, leaving out
+		/// those the handler was already told of with `Exists`.
+		new_uids: Vec<u32>,
+		/// The mailbox's UID validity changed, so any UIDs cached for it are no longer valid
+		/// and `new_uids` is empty.
+		uid_validity_changed: bool
+	}
+}
+
+/// The usage and limits of a quota root, as returned by GETQUOTAROOT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+	pub root: String,
+	/// The name, usage and limit of each resource, e.g. `STORAGE` in units of 1024 octets.
+	pub resources: Vec<(String, u64, u64)>
+}
+
+/// A message along with its flags and internal date, as returned by `IMAPStream::fetch_full`.
+#[derive(Debug)]
+pub struct Message {
+	pub flags: Vec<Flag>,
+	pub internal_date: Option<DateTime<FixedOffset>>,
+	pub body: MimeMessage
+}
+
+/// Where the messages of a COPY landed, as reported by a UIDPLUS server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyUid {
+	/// The UID validity of the destination mailbox.
+	pub uid_validity: u32,
+	/// Pairs of the UID of each source message and the UID of its copy.
+	pub uids: Vec<(u32, u32)>
+}
+
+/// The result of `IMAPStream::search_return`. Only the values that were asked for are set.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EsearchResult {
+	pub count: Option<u32>,
+	pub min: Option<u32>,
+	pub max: Option<u32>,
+	/// Every matching message, in ascending order.
+	pub all: Vec<u32>
+}
+
+/// Iterator over the messages of a FETCH response, returned by `IMAPStream::fetch_messages_iter`.
+#[derive(Debug)]
+pub struct MessageIter<'a> {
+	stream: &'a mut IMAPStream,
+	start_str: String,
+	current_message: Option<u32>,
+	done: bool
+}
+
+/// A handle that can interrupt a blocking read of an `IMAPStream` from another thread, returned
+/// by `IMAPStream::interrupt_handle`.
+#[derive(Debug)]
+pub struct InterruptHandle {
+	stream: TcpStream,
+	interrupted: Arc<AtomicBool>
+}
+
+/// A cloneable handle on the byte counts of an `IMAPStream`, returned by
+/// `IMAPStream::traffic_counters`, so another thread can report progress while a command runs.
+#[derive(Debug, Clone)]
+pub struct TrafficCounters {
+	sent: Arc<AtomicU64>,
+	received: Arc<AtomicU64>
+}
+
+/// A mailbox as returned by the LIST and LSUB commands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mailbox {
+	pub attributes: Vec<String>,
+	pub delimiter: Option<char>,
+	pub name: String
 }
 
 impl IMAPStream {
 	/// Creates an IMAP Stream.
+	#[cfg(feature = "openssl")]
 	pub fn connect<A: ToSocketAddrs>(addr: A, ssl_context: Option<SslContext>) -> Result<IMAPStream> {
 		match TcpStream::connect(addr) {
-			Ok(stream) => {
-				let mut socket = match ssl_context {
-					Some(context) => IMAPStream { stream: IMAPStreamTypes::Ssl(SslStream::connect(&context, stream).unwrap()), tag: 1, tag_prefix: "a"},
-					None => IMAPStream { stream: IMAPStreamTypes::Basic(stream), tag: 1, tag_prefix: "a"},
+			Ok(stream) => IMAPStream::wrap_tcp_stream(stream, ssl_context),
+			Err(e) => Err(ImapError::Io(e))
+		}
+	}
+
+	/// Creates an IMAP Stream, giving up on each resolved address if the TCP connection can't be
+	/// established within the timeout.
+	#[cfg(feature = "openssl")]
+	pub fn connect_with_timeout<A: ToSocketAddrs>(addr: A, ssl_context: Option<SslContext>, timeout: Duration) -> Result<IMAPStream> {
+		let stream = try!(IMAPStream::tcp_connect_timeout(addr, timeout));
+		IMAPStream::wrap_tcp_stream(stream, ssl_context)
+	}
+
+	/// Creates an IMAP Stream over an already connected socket, such as one opened through a SOCKS
+	/// proxy, securing it with TLS if a context is given, and reads the greeting. Only the caller
+	/// knows how to open such a socket again, so `reconnect` fails on the stream.
+	#[cfg(feature = "openssl")]
+	pub fn from_stream(stream: TcpStream, ssl_context: Option<SslContext>) -> Result<IMAPStream> {
+		let mut socket = try!(IMAPStream::wrap_tcp_stream(stream, ssl_context));
+		socket.addr = None;
+		Ok(socket)
+	}
+
+	/// Creates an unencrypted IMAP Stream. Unlike `connect`, this doesn't need OpenSSL.
+	pub fn connect_plain<A: ToSocketAddrs>(addr: A) -> Result<IMAPStream> {
+		match TcpStream::connect(addr) {
+			Ok(stream) => IMAPStream::from_stream_types(IMAPStreamTypes::Basic(stream), Security::Plain),
+			Err(e) => Err(ImapError::Io(e))
+		}
+	}
+
+	/// Creates an unencrypted IMAP Stream, giving up on each resolved address if the TCP
+	/// connection can't be established within the timeout.
+	pub fn connect_plain_with_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<IMAPStream> {
+		let stream = try!(IMAPStream::tcp_connect_timeout(addr, timeout));
+		IMAPStream::from_stream_types(IMAPStreamTypes::Basic(stream), Security::Plain)
+	}
+
+	/// Creates an unencrypted IMAP Stream over an already connected socket and reads the greeting.
+	/// As with `from_stream`, `reconnect` fails on the stream.
+	pub fn from_plain_stream(stream: TcpStream) -> Result<IMAPStream> {
+		let mut socket = try!(IMAPStream::from_stream_types(IMAPStreamTypes::Basic(stream), Security::Plain));
+		socket.addr = None;
+		Ok(socket)
+	}
+
+	fn tcp_connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<TcpStream> {
+		let addrs = try!(addr.to_socket_addrs());
+
+		let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "Could not resolve to any addresses");
+		for socket_addr in addrs {
+			match TcpStream::connect_timeout(&socket_addr, timeout) {
+				Ok(stream) => return Ok(stream),
+				Err(e) => last_error = e
+			}
+		}
+
+		match last_error.kind() {
+			io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Err(ImapError::TimedOut),
+			_ => Err(ImapError::Io(last_error))
+		}
+	}
+
+	/// Creates an IMAP Stream secured with rustls instead of OpenSSL. The domain is the name the
+	/// server's certificate is verified against.
+	#[cfg(feature = "rustls-tls")]
+	pub fn connect_rustls<A: ToSocketAddrs>(addr: A, domain: &str, config: Arc<ClientConfig>) -> Result<IMAPStream> {
+		let server_name = try!(IMAPStream::server_name(domain));
+		let connection = try!(IMAPStream::rustls_connection(&server_name, &config));
+
+		match TcpStream::connect(addr) {
+			Ok(stream) => IMAPStream::from_stream_types(IMAPStreamTypes::Rustls(StreamOwned::new(connection, stream)), Security::Rustls(server_name, config)),
+			Err(e) => Err(ImapError::Io(e))
+		}
+	}
+
+	#[cfg(feature = "rustls-tls")]
+	fn server_name(domain: &str) -> Result<ServerName> {
+		match ServerName::try_from(domain) {
+			Ok(name) => Ok(name),
+			Err(e) => Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))
+		}
+	}
+
+	#[cfg(feature = "rustls-tls")]
+	fn rustls_connection(server_name: &ServerName, config: &Arc<ClientConfig>) -> Result<ClientConnection> {
+		match ClientConnection::new(config.clone(), server_name.clone()) {
+			Ok(connection) => Ok(connection),
+			Err(e) => Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, e)))
+		}
+	}
+
+	#[cfg(feature = "openssl")]
+	fn wrap_tcp_stream(stream: TcpStream, ssl_context: Option<SslContext>) -> Result<IMAPStream> {
+		match ssl_context {
+			Some(context) => {
+				let ssl_stream = match SslStream::connect(&context, stream) {
+					Ok(ssl_stream) => ssl_stream,
+					Err(e) => return Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, e)))
 				};
+				IMAPStream::from_stream_types(IMAPStreamTypes::Ssl(ssl_stream), Security::Ssl(context))
+			},
+			None => IMAPStream::from_stream_types(IMAPStreamTypes::Basic(stream), Security::Plain),
+		}
+	}
+
+	fn from_stream_types(stream: IMAPStreamTypes, security: Security) -> Result<IMAPStream> {
+		let addr = try!(stream.get_ref().peer_addr());
+		let mut socket = IMAPStream {
+			connection: Connection::new(stream),
+			addr: Some(addr),
+			security: security,
+			read_timeout: None,
+			tag: 1,
+			tag_prefix: "a",
+			capabilities: None,
+			logged_out: false,
+			unsolicited: Vec::new(),
+			pipelined_untagged: Vec::new(),
+			pipelined_responses: HashMap::new(),
+			delimiter: None,
+			greeting: Greeting { status: GreetingStatus::Ok, capabilities: Vec::new(), text: String::new() },
+			bye: None,
+			dead: false,
+			mailbox: None,
+			mailbox_name: None,
+			state: ConnectionState::NotAuthenticated
+		};
+
+		try!(socket.read_greeting());
+		Ok(socket)
+	}
+
+	/// Opens a new connection to the server this stream was connected to, secured the same way,
+	/// and reads its greeting. A connection upgraded with `starttls` is upgraded again. The new
+	/// connection is not logged in; the caller has to log in again. Fails with
+	/// `ImapError::InvalidState` for streams created with `from_stream` or `from_plain_stream`.
+	pub fn reconnect(&mut self) -> Result<()> {
+		let addr = match self.addr {
+			Some(addr) => addr,
+			None => return Err(ImapError::InvalidState("A stream created over an existing socket can't reconnect".to_string()))
+		};
+		let tcp_stream = try!(TcpStream::connect(addr));
+		try!(tcp_stream.set_read_timeout(self.read_timeout));
 
-				try!(socket.read_greeting());
-				Ok(socket)
+		let security = ::std::mem::replace(&mut self.security, Security::Plain);
+		let ret = self.reconnect_stream(tcp_stream, &security);
+		self.security = security;
+		ret
+	}
+
+	fn reconnect_stream(&mut self, tcp_stream: TcpStream, security: &Security) -> Result<()> {
+		let stream = match *security {
+			Security::Plain => IMAPStreamTypes::Basic(tcp_stream),
+			#[cfg(feature = "openssl")]
+			Security::Ssl(ref ssl_context) => match SslStream::connect(ssl_context, tcp_stream) {
+				Ok(ssl_stream) => IMAPStreamTypes::Ssl(ssl_stream),
+				Err(e) => return Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, e)))
 			},
-			Err(e) => Err(e)
+			#[cfg(feature = "openssl")]
+			Security::Starttls(_) => IMAPStreamTypes::Basic(tcp_stream),
+			#[cfg(feature = "rustls-tls")]
+			Security::Rustls(ref server_name, ref config) => IMAPStreamTypes::Rustls(StreamOwned::new(try!(IMAPStream::rustls_connection(server_name, config)), tcp_stream)),
+			#[cfg(feature = "rustls-tls")]
+			Security::StarttlsRustls(..) => IMAPStreamTypes::Basic(tcp_stream)
+		};
+		self.connection.replace_stream(stream);
+		self.tag = 1;
+		self.capabilities = None;
+		self.logged_out = false;
+		self.unsolicited.clear();
+		self.pipelined_untagged.clear();
+		self.pipelined_responses.clear();
+		self.delimiter = None;
+		self.bye = None;
+		self.dead = false;
+		self.mailbox = None;
+		self.mailbox_name = None;
+		self.state = ConnectionState::NotAuthenticated;
+
+		try!(self.read_greeting());
+
+		#[cfg(feature = "openssl")]
+		{
+			if let Security::Starttls(ref ssl_context) = *security {
+				try!(self.upgrade_to_tls(ssl_context));
+			}
+		}
+		#[cfg(feature = "rustls-tls")]
+		{
+			if let Security::StarttlsRustls(ref server_name, ref config) = *security {
+				try!(self.upgrade_to_rustls(server_name, config));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Upgrades a plaintext connection to TLS using the STARTTLS command.
+	#[cfg(feature = "openssl")]
+	pub fn starttls(&mut self, ssl_context: SslContext) -> Result<()> {
+		try!(self.check_starttls());
+		try!(self.upgrade_to_tls(&ssl_context));
+		self.security = Security::Starttls(ssl_context);
+		Ok(())
+	}
+
+	/// Upgrades a plaintext connection to TLS using the STARTTLS command, with rustls instead of
+	/// OpenSSL. The domain is the name the server's certificate is verified against.
+	#[cfg(feature = "rustls-tls")]
+	pub fn starttls_rustls(&mut self, domain: &str, config: Arc<ClientConfig>) -> Result<()> {
+		try!(self.check_starttls());
+		let server_name = try!(IMAPStream::server_name(domain));
+		try!(self.upgrade_to_rustls(&server_name, &config));
+		self.security = Security::StarttlsRustls(server_name, config);
+		Ok(())
+	}
+
+	#[cfg(any(feature = "openssl", feature = "rustls-tls"))]
+	fn check_starttls(&self) -> Result<()> {
+		if self.connection.is_tls() {
+			return Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidInput, "The connection is already using TLS")));
+		}
+		if self.connection.is_compressed() {
+			return Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidInput, "TLS must be negotiated before compression")));
+		}
+		Ok(())
+	}
+
+	#[cfg(feature = "openssl")]
+	fn upgrade_to_tls(&mut self, ssl_context: &SslContext) -> Result<()> {
+		try!(self.run_command_and_check_ok("STARTTLS"));
+		self.capabilities = None;
+		self.connection.upgrade_to_tls(ssl_context)
+	}
+
+	#[cfg(feature = "rustls-tls")]
+	fn upgrade_to_rustls(&mut self, server_name: &ServerName, config: &Arc<ClientConfig>) -> Result<()> {
+		let connection = try!(IMAPStream::rustls_connection(server_name, config));
+		try!(self.run_command_and_check_ok("STARTTLS"));
+		self.capabilities = None;
+		self.connection.upgrade_to_rustls(connection)
+	}
+
+	/// Checks, without blocking, whether the server has sent data that hasn't been read yet, such
+	/// as an unsolicited EXISTS response, or has closed the connection. Data already decrypted or
+	/// decompressed counts as well as bytes still waiting on the socket.
+	pub fn has_pending_data(&mut self) -> Result<bool> {
+		self.connection.has_pending_data()
+	}
+
+	/// Returns the greeting the server sent when the connection was opened.
+	pub fn greeting(&self) -> &Greeting {
+		&self.greeting
+	}
+
+	/// Sets the prefix of the tags of the commands sent from now on, which defaults to `a`. The
+	/// prefix must be a non-empty run of ASCII letters so that tags stay valid IMAP atoms.
+	///
+	/// # Panics
+	///
+	/// Panics if the prefix is empty or contains anything but ASCII letters.
+	pub fn with_tag_prefix(mut self, prefix: &'static str) -> IMAPStream {
+		assert!(!prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_alphabetic()), "Invalid tag prefix: {:?}", prefix);
+		self.tag_prefix = prefix;
+		self
+	}
+
+	/// Compresses all further traffic with DEFLATE using the COMPRESS command. This can be done
+	/// before or after logging in, but only once per connection; a connection opened with
+	/// `reconnect` starts out uncompressed. Requires the COMPRESS=DEFLATE capability.
+	pub fn compress(&mut self) -> Result<()> {
+		if self.connection.is_compressed() {
+			return Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidInput, "The connection is already compressed")));
 		}
+		try!(self.require_capability("COMPRESS=DEFLATE"));
+		try!(self.run_command_and_check_ok("COMPRESS DEFLATE"));
+		self.connection.start_compression()
+	}
+
+	/// Returns a handle that can be sent to another thread to interrupt a command that is blocked
+	/// waiting for the server. The handle only applies to the current connection, not to one
+	/// opened later by `reconnect`.
+	pub fn interrupt_handle(&self) -> Result<InterruptHandle> {
+		Ok(InterruptHandle {
+			stream: try!(self.connection.get_ref().try_clone()),
+			interrupted: self.connection.interrupted_flag()
+		})
+	}
+
+	/// Returns a handle that can be sent to another thread to read `bytes_sent` and
+	/// `bytes_received` while this stream is busy. Unlike an `InterruptHandle`, it keeps counting
+	/// across `reconnect`.
+	pub fn traffic_counters(&self) -> TrafficCounters {
+		let (sent, received) = self.connection.byte_counters();
+		TrafficCounters { sent: sent, received: received }
+	}
+
+	/// Returns the underlying TCP socket, e.g. to look up its addresses or configure it. Reading
+	/// from or writing to it directly would corrupt the IMAP session.
+	pub fn get_ref(&self) -> &TcpStream {
+		self.connection.get_ref()
+	}
+
+	/// Returns the number of bytes sent to the server so far, over every connection opened by this
+	/// stream. Sampled over time along with `bytes_received`, it gives the throughput of a sync.
+	/// Bytes are counted before compression and encryption.
+	pub fn bytes_sent(&self) -> u64 {
+		self.connection.bytes_sent()
+	}
+
+	/// Returns the number of bytes received from the server so far, including the greeting, over
+	/// every connection opened by this stream. Bytes are counted after decryption and
+	/// decompression.
+	pub fn bytes_received(&self) -> u64 {
+		self.connection.bytes_received()
+	}
+
+	/// Enables or disables Nagle's algorithm on the underlying socket. Disabling it lowers the
+	/// latency of commands for interactive clients.
+	pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+		try!(self.connection.get_ref().set_nodelay(nodelay));
+		Ok(())
 	}
 
-	/// Log in to the IMAP server.
+	/// Sets the capacity of the buffer responses are read through, 64 KiB by default. A larger
+	/// buffer cuts down on system calls when fetching large messages.
+	pub fn set_read_buffer_capacity(&mut self, capacity: usize) {
+		self.connection.set_read_buffer_capacity(capacity);
+	}
+
+	/// Sets the read timeout of the underlying socket. Reads that time out fail with
+	/// `ImapError::TimedOut`. Passing `None` makes reads block indefinitely.
+	pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> Result<()> {
+		try!(self.connection.get_ref().set_read_timeout(dur));
+		self.read_timeout = dur;
+		Ok(())
+	}
+
+	/// Log in to the IMAP server. Wrong credentials fail with `ImapError::AuthFailed`, and
+	/// credentials the server refuses for other reasons with `ImapError::AuthorizationFailed` or
+	/// `ImapError::CredentialsExpired`, so they can be told apart from connection failures.
 	pub fn login(&mut self, username: & str, password: & str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("LOGIN {} {}", username, password).to_string())
+		self.capabilities = None;
+		let ret = match self.run_command(&format!("LOGIN {} {}", quote_argument(username), quote_argument(password)).to_string()) {
+			Ok(lines) => self.finish_authentication(&lines),
+			Err(e) => Err(e)
+		};
+
+		match ret {
+			Err(ImapError::No(response_text)) => Err(login_error(response_text)),
+			ret => ret
+		}
 	}
 
-	/// Selects a mailbox
-	pub fn select(&mut self, mailbox_name: &str) -> Result<IMAPMailbox> {
-		match self.run_command(&format!("SELECT {}", mailbox_name).to_string()) {
-			Ok(lines) => IMAPStream::parse_select_or_examine(lines),
+	/// Checks the response to a successful LOGIN or AUTHENTICATE and caches the capabilities the
+	/// server sent with it, since they often change once authenticated. Without them the cache
+	/// stays empty, so the next `has_capability` asks the server again.
+	fn finish_authentication(&mut self, lines: &[String]) -> Result<()> {
+		self.capabilities = None;
+		let response_text = try!(IMAPStream::parse_response_text(lines));
+		self.capabilities = match response_text.code {
+			Some(ResponseCode::Capability(capabilities)) => Some(capabilities),
+			_ => lines.iter().rev().find(|line| line.starts_with("* CAPABILITY ")).map(|line| {
+				line["* CAPABILITY ".len()..].split_whitespace().map(|x| x.to_string()).collect()
+			})
+		};
+		self.state = ConnectionState::Authenticated;
+		Ok(())
+	}
+
+	/// Authenticates with the SASL PLAIN mechanism, for servers that disable the LOGIN command.
+	/// The credentials are sent with the command itself when the server supports SASL-IR.
+	/// Refused credentials fail like `login`.
+	pub fn authenticate_plain(&mut self, username: &str, password: &str) -> Result<()> {
+		let credentials = base64::encode(format!("\0{}\0{}", username, password).as_bytes());
+
+		if try!(self.has_capability("SASL-IR")) {
+			self.capabilities = None;
+			let ret = match self.run_command(&format!("AUTHENTICATE PLAIN {}", credentials).to_string()) {
+				Ok(lines) => self.finish_authentication(&lines),
+				Err(e) => Err(e)
+			};
+			match ret {
+				Err(ImapError::No(response_text)) => Err(login_error(response_text)),
+				ret => ret
+			}
+		} else {
+			self.run_authenticate("PLAIN", &credentials)
+		}
+	}
+
+	/// Authenticates with an OAuth2 bearer token using the XOAUTH2 mechanism, as required by
+	/// Gmail and Office365. A rejected token fails like `login`, e.g. with `ImapError::AuthFailed`,
+	/// and the error the server describes is included in the response text.
+	pub fn authenticate_xoauth2(&mut self, username: &str, access_token: &str) -> Result<()> {
+		let credentials = base64::encode(format!("user={}\x01auth=Bearer {}\x01\x01", username, access_token).as_bytes());
+		self.run_authenticate("XOAUTH2", &credentials)
+	}
+
+	/// Runs an AUTHENTICATE command, sending the base64 encoded response once the server asks
+	/// for it.
+	fn run_authenticate(&mut self, mechanism: &str, response: &str) -> Result<()> {
+		self.capabilities = None;
+		let command = self.create_command(format!("AUTHENTICATE {}", mechanism));
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => match self.read_continuation() {
+				Ok(_) => self.write_authenticate_response(response),
+				Err(e) => Err(e)
+			},
 			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		return ret;
+	}
+
+	/// Sends the client's response to an AUTHENTICATE challenge, then reads the outcome.
+	fn write_authenticate_response(&mut self, response: &str) -> Result<()> {
+		debug!("C: <redacted>");
+		match self.connection.send_line(response) {
+			Ok(_) => self.read_authenticate_response(),
+			Err(_) => Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to write")))
+		}
+	}
+
+	/// Reads the outcome of an AUTHENTICATE command. A failing server may first send another
+	/// continuation carrying a base64 encoded error description, which is acknowledged with an
+	/// empty line and added to the text of the resulting error.
+	fn read_authenticate_response(&mut self) -> Result<()> {
+		let start_str = format!("{}{} ", self.tag_prefix, self.tag);
+		let mut lines = Vec::new();
+		let mut challenge = None;
+
+		loop {
+			let line = String::from_utf8_lossy(&try!(self.read_response_line())).into_owned();
+			if line.starts_with("+") {
+				challenge = Some(line[1..].trim().to_string());
+				match self.connection.send_line("") {
+					Ok(_) => (),
+					Err(_) => return Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to write"))),
+				};
+			} else {
+				let is_tag_line = line.starts_with(&*start_str);
+				lines.push(line);
+				if is_tag_line {
+					break;
+				}
+			}
+		}
+
+		match IMAPStream::parse_response_ok(&lines) {
+			Err(ImapError::No(mut response_text)) => {
+				if let Some(details) = challenge.and_then(|c| base64::decode(&c).ok()) {
+					response_text.text = format!("{} ({})", response_text.text, String::from_utf8_lossy(&details));
+				}
+				Err(login_error(response_text))
+			},
+			Err(e) => Err(e),
+			Ok(_) => self.finish_authentication(&lines)
 		}
 	}
 
-	fn parse_select_or_examine(lines: Vec<String>) -> Result<IMAPMailbox> {
+	/// Selects a mailbox. Its counts are returned rather than kept for `take_unsolicited`, which
+	/// only keeps alerts and warnings from before.
+	pub fn select(&mut self, mailbox_name: &str) -> Result<IMAPMailbox> {
+		self.run_select(mailbox_name, &format!("SELECT {}", quote_mailbox_name(mailbox_name)).to_string(), false)
+	}
+
+	/// Selects a mailbox with the CONDSTORE parameter, which enables mod-sequences for the session
+	/// so that the mailbox's `highest_modseq` is set, unless the mailbox doesn't support them.
+	/// Requires the CONDSTORE capability.
+	pub fn select_condstore(&mut self, mailbox_name: &str) -> Result<IMAPMailbox> {
+		try!(self.require_state("SELECT", ConnectionState::Authenticated));
+		try!(self.require_capability("CONDSTORE"));
+		self.run_select(mailbox_name, &format!("SELECT {} (CONDSTORE)", quote_mailbox_name(mailbox_name)).to_string(), false)
+	}
+
+	/// Selects a mailbox and resynchronizes with it using QRESYNC. `uid_validity` and `modseq` are
+	/// the mailbox's UID validity and highest mod-sequence from the last sync, and `known_uids`
+	/// optionally limits the messages the server reports on. Messages expunged since then are
+	/// returned in `vanished` and changed messages in `changed`. QRESYNC has to be enabled first.
+	pub fn select_qresync(&mut self, mailbox_name: &str, uid_validity: u32, modseq: u64, known_uids: Option<&str>) -> Result<IMAPMailbox> {
+		let known_uids = match known_uids {
+			Some(uids) => format!(" {}", uids),
+			None => String::new()
+		};
+		self.run_select(mailbox_name, &format!("SELECT {} (QRESYNC ({} {}{}))", quote_mailbox_name(mailbox_name), uid_validity, modseq, known_uids).to_string(), false)
+	}
+
+	/// Returns the state of the connection: whether it is logged in and has a mailbox selected.
+	pub fn state(&self) -> ConnectionState {
+		self.state
+	}
+
+	/// Returns the state of the selected mailbox, as returned by the last `select` or `examine`
+	/// and kept up to date with the EXISTS, RECENT and EXPUNGE responses received since.
+	pub fn current_mailbox(&self) -> Option<&IMAPMailbox> {
+		self.mailbox.as_ref()
+	}
+
+	/// Runs SELECT or EXAMINE and remembers the selected mailbox. A failed command leaves no
+	/// mailbox selected.
+	fn run_select(&mut self, mailbox_name: &str, command: &str, read_only: bool) -> Result<IMAPMailbox> {
+		try!(self.require_state(command.split(' ').next().unwrap_or(""), ConnectionState::Authenticated));
+		self.mailbox = None;
+		self.mailbox_name = None;
+		self.state = ConnectionState::Authenticated;
+		let mailbox = match self.run_command(command) {
+			Ok(lines) => try!(IMAPStream::parse_select_or_examine(lines, read_only)),
+			Err(e) => return Err(e)
+		};
+		// The counts and codes of the reply are in the returned mailbox, and those buffered before
+		// were about the previous one, so only alerts and warnings are kept for `take_unsolicited`.
+		self.unsolicited.retain(|unsolicited| match *unsolicited {
+			Unsolicited::Alert(_) | Unsolicited::No(_) => true,
+			Unsolicited::Ok(ref text) => text.code == Some(ResponseCode::Alert),
+			_ => false
+		});
+		self.mailbox = Some(mailbox.clone());
+		self.mailbox_name = Some(mailbox_name.to_string());
+		self.state = ConnectionState::Selected;
+		Ok(mailbox)
+	}
+
+	/// Parses the response to SELECT or EXAMINE. `read_only` is the access the command asks
+	/// for, used when the server doesn't send a READ-ONLY or READ-WRITE response code.
+	fn parse_select_or_examine(lines: Vec<String>, read_only: bool) -> Result<IMAPMailbox> {
 		let exists_regex = match Regex::new(r"^\* (\d+) EXISTS\r\n") {
     		Ok(re) => re,
     		Err(err) => panic!("{}", err),
@@ -71,31 +853,56 @@ impl IMAPStream {
     		Err(err) => panic!("{}", err),
 		};
 
-		let unseen_regex = match Regex::new(r"^OK \[UNSEEN (\d+)\](.*)\r\n") {
+		let unseen_regex = match Regex::new(r"^\* OK \[UNSEEN (\d+)\](.*)\r\n") {
     		Ok(re) => re,
     		Err(err) => panic!("{}", err),
 		};
 
-		let uid_validity_regex = match Regex::new(r"^OK \[UIDVALIDITY (\d+)\](.*)\r\n") {
+		let uid_validity_regex = match Regex::new(r"^\* OK \[UIDVALIDITY (\d+)\](.*)\r\n") {
     		Ok(re) => re,
     		Err(err) => panic!("{}", err),
 		};
 
-		let uid_next_regex = match Regex::new(r"^OK \[UIDNEXT (\d+)\](.*)\r\n") {
+		let uid_next_regex = match Regex::new(r"^\* OK \[UIDNEXT (\d+)\](.*)\r\n") {
     		Ok(re) => re,
     		Err(err) => panic!("{}", err),
 		};
 
-		let permanent_flags_regex = match Regex::new(r"^OK \[PERMANENTFLAGS (.+)\]\r\n") {
+		let permanent_flags_regex = match Regex::new(r"^\* OK \[PERMANENTFLAGS (\([^)]*\))\](.*)\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let highest_modseq_regex = match Regex::new(r"^\* OK \[HIGHESTMODSEQ (\d+)\](.*)\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let no_modseq_regex = match Regex::new(r"^\* OK \[NOMODSEQ\](.*)\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let mailbox_id_regex = match Regex::new(r"^\* OK \[MAILBOXID \(([^)]*)\)\](.*)\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let vanished_regex = match Regex::new(r"^\* VANISHED (?:\(EARLIER\) )?([0-9:,]+)\r\n") {
     		Ok(re) => re,
     		Err(err) => panic!("{}", err),
 		};
 
 		//Check Ok
-		match IMAPStream::parse_response_ok(lines.clone()) {
-			Ok(_) => (),
+		let response_text = match IMAPStream::parse_response_text(&lines) {
+			Ok(response_text) => response_text,
 			Err(e) => return Err(e)
 		};
+		let read_only = match response_text.code {
+			Some(ResponseCode::ReadOnly) => true,
+			Some(ResponseCode::ReadWrite) => false,
+			_ => read_only
+		};
 
 		let mut mailbox = IMAPMailbox{
 			flags: "".to_string(),
@@ -104,31 +911,47 @@ impl IMAPStream {
 			unseen: None,
 			permanent_flags: None,
 			uid_next: None,
-			uid_validity: None
+			uid_validity: None,
+			highest_modseq: None,
+			mailbox_id: None,
+			read_only: read_only,
+			vanished: Vec::new(),
+			changed: try!(IMAPStream::parse_fetch_results(&lines))
 		};
 
 		for line in lines.iter() {
 			if exists_regex.is_match(line) {
 				let cap = exists_regex.captures(line).unwrap();
-				mailbox.exists = cap.at(1).unwrap().parse::<u32>().unwrap();
+				mailbox.exists = try!(parse_number(cap.at(1).unwrap(), line));
 			} else if recent_regex.is_match(line) {
 				let cap = recent_regex.captures(line).unwrap();
-				mailbox.recent = cap.at(1).unwrap().parse::<u32>().unwrap();
+				mailbox.recent = try!(parse_number(cap.at(1).unwrap(), line));
 			} else if flags_regex.is_match(line) {
 				let cap = flags_regex.captures(line).unwrap();
 				mailbox.flags = cap.at(1).unwrap().to_string();
 			} else if unseen_regex.is_match(line) {
 				let cap = unseen_regex.captures(line).unwrap();
-				mailbox.unseen = Some(cap.at(1).unwrap().parse::<u32>().unwrap());
+				mailbox.unseen = Some(try!(parse_number(cap.at(1).unwrap(), line)));
 			} else if uid_validity_regex.is_match(line) {
 				let cap = uid_validity_regex.captures(line).unwrap();
-				mailbox.uid_validity = Some(cap.at(1).unwrap().parse::<u32>().unwrap());
+				mailbox.uid_validity = Some(try!(parse_number(cap.at(1).unwrap(), line)));
 			} else if uid_next_regex.is_match(line) {
 				let cap = uid_next_regex.captures(line).unwrap();
-				mailbox.uid_next = Some(cap.at(1).unwrap().parse::<u32>().unwrap());
+				mailbox.uid_next = Some(try!(parse_number(cap.at(1).unwrap(), line)));
 			} else if permanent_flags_regex.is_match(line) {
 				let cap = permanent_flags_regex.captures(line).unwrap();
 				mailbox.permanent_flags = Some(cap.at(1).unwrap().to_string());
+			} else if let Some(cap) = highest_modseq_regex.captures(line) {
+				mailbox.highest_modseq = cap.at(1).unwrap().parse::<u64>().ok();
+			} else if no_modseq_regex.is_match(line) {
+				mailbox.highest_modseq = None;
+			} else if let Some(cap) = mailbox_id_regex.captures(line) {
+				mailbox.mailbox_id = Some(cap.at(1).unwrap().to_string());
+			} else if let Some(cap) = vanished_regex.captures(line) {
+				match parse_uid_set(cap.at(1).unwrap()) {
+					Some(uids) => mailbox.vanished.extend(uids),
+					None => return Err(ImapError::Parse(line.clone()))
+				}
 			}
 		}
 
@@ -137,222 +960,4594 @@ impl IMAPStream {
 
 	/// Examine is identical to Select, but the selected mailbox is identified as read-only
 	pub fn examine(&mut self, mailbox_name: &str) -> Result<IMAPMailbox> {
-		match self.run_command(&format!("EXAMINE {}", mailbox_name).to_string()) {
-			Ok(lines) => IMAPStream::parse_select_or_examine(lines),
-			Err(e) => Err(e)
-		}
+		self.run_select(mailbox_name, &format!("EXAMINE {}", quote_mailbox_name(mailbox_name)).to_string(), true)
 	}
 
-	/// Fetch retreives data associated with a message in the mailbox.
-	pub fn fetch(&mut self, sequence_set: &str, query: &str) -> Result<Vec<String>> {
+	/// Fetch retreives data associated with a message in the mailbox. Like the other methods taking a
+	/// sequence set, it accepts text such as `1:4,7`, a number, a range such as `3..=5` or `10..`
+	/// for `10:*`, or a `SequenceSet`, and rejects a malformed set without sending it.
+	pub fn fetch<S: Into<SequenceSet>>(&mut self, sequence_set: S, query: &str) -> Result<Vec<String>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
 		self.run_command(&format!("FETCH {} {}", sequence_set, query).to_string())
 	}
 
-	/// Noop always succeeds, and it does nothing.
-	pub fn noop(&mut self) -> Result<()> {
-		self.run_command_and_check_ok("NOOP")
+	/// Fetches the flags of each message in the sequence set, keyed by message sequence number.
+	pub fn fetch_flags<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, Vec<Flag>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} FLAGS", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_flags(lines),
+			Err(e) => Err(e)
+		}
 	}
 
-	/// Logout informs the server that the client is done with the connection.
-	pub fn logout(&mut self) -> Result<()> {
-		self.run_command_and_check_ok("LOGOUT")
-	}
+	fn parse_fetch_flags(lines: Vec<String>) -> Result<HashMap<u32, Vec<Flag>>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
 
-	/// Create creates a mailbox with the given name.
-	pub fn create(&mut self, mailbox_name: &str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("CREATE {}", mailbox_name).to_string())
+		let mut flags = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			if let Some(message_flags) = find_item(&items, "FLAGS").and_then(Token::as_list) {
+				flags.insert(message_number, message_flags.iter().filter_map(Token::as_str).filter_map(|x| x.parse().ok()).collect());
+			}
+		}
+
+		Ok(flags)
 	}
 
-	/// Delete permanently removes the mailbox with the given name.
-	pub fn delete(&mut self, mailbox_name: &str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("DELETE {}", mailbox_name).to_string())
+	/// Whether the selected mailbox allows setting any keyword, rather than only those listed in
+	/// its PERMANENTFLAGS. False when no mailbox is selected or the server sent no PERMANENTFLAGS.
+	pub fn supports_custom_keywords(&self) -> bool {
+		self.mailbox.as_ref().map_or(false, IMAPMailbox::allows_new_keywords)
 	}
 
-	/// Rename changes the name of a mailbox.
-	pub fn rename(&mut self, current_mailbox_name: &str, new_mailbox_name: &str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("RENAME {} {}", current_mailbox_name, new_mailbox_name).to_string())
+	/// Rejects keywords the selected mailbox doesn't allow: those missing from its PERMANENTFLAGS
+	/// when it has no `\*`. Nothing is checked if the server didn't send PERMANENTFLAGS.
+	fn check_keywords(&self, flags: &[Flag]) -> Result<()> {
+		let permanent_flags = match self.mailbox.as_ref().and_then(IMAPMailbox::permanent_flag_list) {
+			Some(permanent_flags) => permanent_flags,
+			None => return Ok(())
+		};
+		if permanent_flags.contains(&Flag::Custom("\\*".to_string())) {
+			return Ok(());
+		}
+
+		for flag in flags {
+			if let Flag::Custom(ref keyword) = *flag {
+				if !keyword.starts_with('\\') && !permanent_flags.iter().any(|allowed| allowed.to_string().eq_ignore_ascii_case(keyword)) {
+					return Err(ImapError::InvalidState(format!("The selected mailbox doesn't allow the keyword {}", keyword)));
+				}
+			}
+		}
+		Ok(())
 	}
 
-	/// Subscribe adds the specified mailbox name to the server's set of "active" or "subscribed"
-	/// mailboxes as returned by the LSUB command.
-	pub fn subscribe(&mut self, mailbox: &str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("SUBSCRIBE {}", mailbox).to_string())
+	/// Replaces, adds or removes flags of the messages in the sequence set. Keywords the selected
+	/// mailbox doesn't allow are rejected without sending the command.
+	pub fn store_flags<S: Into<SequenceSet>>(&mut self, sequence_set: S, op: FlagOp, flags: &[Flag]) -> Result<()> {
+		try!(self.require_state("STORE", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		try!(self.check_keywords(flags));
+		let item = match op {
+			FlagOp::Replace => "FLAGS",
+			FlagOp::Add => "+FLAGS",
+			FlagOp::Remove => "-FLAGS"
+		};
+		let flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+		self.run_command_and_check_ok(&format!("STORE {} {} ({})", sequence_set, item, flags.join(" ")).to_string())
 	}
 
-	/// Unsubscribe removes the specified mailbox name from the server's set of "active" or "subscribed"
-	/// mailboxes as returned by the LSUB command.
-	pub fn unsubscribe(&mut self, mailbox: &str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("UNSUBSCRIBE {}", mailbox).to_string())
+	/// Changes flags like `store_flags`, but only of the messages whose mod-sequence is not greater
+	/// than `modseq`, using CONDSTORE's UNCHANGEDSINCE. Returns the messages that were left alone
+	/// because another client changed them since, so the conflict can be resolved. Requires the
+	/// CONDSTORE capability.
+	pub fn store_unchanged_since<S: Into<SequenceSet>>(&mut self, sequence_set: S, modseq: u64, op: FlagOp, flags: &[Flag]) -> Result<Vec<u32>> {
+		try!(self.require_state("STORE", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		try!(self.require_capability("CONDSTORE"));
+		try!(self.check_keywords(flags));
+		let item = match op {
+			FlagOp::Replace => "FLAGS",
+			FlagOp::Add => "+FLAGS",
+			FlagOp::Remove => "-FLAGS"
+		};
+		let flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+		let lines = try!(self.run_command(&format!("STORE {} (UNCHANGEDSINCE {}) {} ({})", sequence_set, modseq, item, flags.join(" ")).to_string()));
+
+		match try!(IMAPStream::parse_response_text(&lines)).code {
+			Some(ResponseCode::Modified(ref set)) => match parse_uid_set(set) {
+				Some(modified) => Ok(modified),
+				None => Err(ImapError::Parse(lines.last().unwrap().clone()))
+			},
+			_ => Ok(Vec::new())
+		}
 	}
 
-	/// Capability requests a listing of capabilities that the server supports.
-	pub fn capability(&mut self) -> Result<Vec<String>> {
-		match self.run_command(&format!("CAPABILITY").to_string()) {
-			Ok(lines) => IMAPStream::parse_capability(lines),
+	/// Fetches the envelope of each message in the sequence set, keyed by message sequence number.
+	pub fn fetch_envelope<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, Envelope>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} ENVELOPE", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_envelope(lines),
 			Err(e) => Err(e)
 		}
 	}
 
-	fn parse_capability(lines: Vec<String>) -> Result<Vec<String>> {
-		let capability_regex = match Regex::new(r"^\* CAPABILITY (.*)\r\n") {
-    		Ok(re) => re,
-    		Err(err) => panic!("{}", err),
+	fn parse_fetch_envelope(lines: Vec<String>) -> Result<HashMap<u32, Envelope>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
 		};
 
+		let mut envelopes = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			let envelope = match find_item(&items, "ENVELOPE") {
+				Some(token) => match Envelope::from_token(token) {
+					Some(envelope) => envelope,
+					None => return Err(ImapError::Parse(format!("Invalid ENVELOPE for message {}", message_number)))
+				},
+				// Unsolicited FETCH responses, e.g. of flags another client changed, may lack the item.
+				None => continue
+			};
+			envelopes.insert(message_number, envelope);
+		}
+
+		Ok(envelopes)
+	}
+
+	/// Fetches the MIME structure of each message in the sequence set, keyed by message sequence
+	/// number. The sections of the returned parts can be used to fetch individual parts.
+	pub fn fetch_bodystructure<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, BodyStructure>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} BODYSTRUCTURE", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_bodystructure(lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_fetch_bodystructure(lines: Vec<String>) -> Result<HashMap<u32, BodyStructure>> {
 		//Check Ok
-		match IMAPStream::parse_response_ok(lines.clone()) {
+		match IMAPStream::parse_response_ok(&lines) {
 			Ok(_) => (),
 			Err(e) => return Err(e)
 		};
 
-		for line in lines.iter() {
-			if capability_regex.is_match(line) {
-				let cap = capability_regex.captures(line).unwrap();
-				let capabilities_str = cap.at(1).unwrap();
-				return Ok(capabilities_str.split(' ').map(|x| x.to_string()).collect());
-			}
+		let mut structures = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			let structure = match find_item(&items, "BODYSTRUCTURE") {
+				Some(token) => match BodyStructure::from_token(token) {
+					Some(structure) => structure,
+					None => return Err(ImapError::Parse(format!("Invalid BODYSTRUCTURE for message {}", message_number)))
+				},
+				None => continue
+			};
+			structures.insert(message_number, structure);
 		}
 
-		Err(Error::new(ErrorKind::Other, "Error parsing capabilities response"))
+		Ok(structures)
 	}
 
-	/// Expunge permanently removes all messages that have the \Deleted flag set from the currently
-	/// selected mailbox.
-	pub fn expunge(&mut self) -> Result<()> {
-		self.run_command_and_check_ok("CHECK")
+	/// Fetches the Gmail labels of each message in the sequence set, keyed by message sequence
+	/// number. System labels keep their backslash, e.g. `\Inbox` or `\Sent`.
+	#[cfg(feature = "gmail")]
+	pub fn fetch_gmail_labels<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, Vec<String>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} X-GM-LABELS", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_gmail_labels(lines),
+			Err(e) => Err(e)
+		}
 	}
 
-	/// Check requests a checkpoint of the currently selected mailbox.
-	pub fn check(&mut self) -> Result<()> {
-		self.run_command_and_check_ok("CHECK")
+	#[cfg(feature = "gmail")]
+	fn parse_fetch_gmail_labels(lines: Vec<String>) -> Result<HashMap<u32, Vec<String>>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut labels = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			match find_item(&items, "X-GM-LABELS").map(Token::as_list) {
+				Some(Some(list)) => labels.insert(message_number, list.iter().filter_map(Token::as_str).map(decode_utf7).collect()),
+				Some(None) => return Err(ImapError::Parse(format!("Invalid X-GM-LABELS for message {}", message_number))),
+				None => continue
+			};
+		}
+
+		Ok(labels)
 	}
 
-	/// Close permanently removes all messages that have the \Deleted flag set from the currently
-	/// selected mailbox, and returns to the authenticated state from the selected state.
-	pub fn close(&mut self) -> Result<()> {
-		self.run_command_and_check_ok("CLOSE")
+	/// Replaces the Gmail labels of the messages in the sequence set.
+	#[cfg(feature = "gmail")]
+	pub fn set_gmail_labels<S: Into<SequenceSet>>(&mut self, sequence_set: S, labels: &[&str]) -> Result<()> {
+		self.store_gmail_labels(sequence_set, "X-GM-LABELS", labels)
 	}
 
-	/// Copy copies the specified message to the end of the specified destination mailbox.
-	pub fn copy(&mut self, sequence_set: &str, mailbox_name: &str) -> Result<()> {
-		self.run_command_and_check_ok(&format!("COPY {} {}", sequence_set, mailbox_name).to_string())
+	/// Adds Gmail labels to the messages in the sequence set.
+	#[cfg(feature = "gmail")]
+	pub fn add_gmail_labels<S: Into<SequenceSet>>(&mut self, sequence_set: S, labels: &[&str]) -> Result<()> {
+		self.store_gmail_labels(sequence_set, "+X-GM-LABELS", labels)
 	}
 
-	pub fn run_command_and_check_ok(&mut self, command: &str) -> Result<()> {
-		match self.run_command(command) {
-			Ok(lines) => IMAPStream::parse_response_ok(lines),
+	/// Removes Gmail labels from the messages in the sequence set.
+	#[cfg(feature = "gmail")]
+	pub fn remove_gmail_labels<S: Into<SequenceSet>>(&mut self, sequence_set: S, labels: &[&str]) -> Result<()> {
+		self.store_gmail_labels(sequence_set, "-X-GM-LABELS", labels)
+	}
+
+	#[cfg(feature = "gmail")]
+	fn store_gmail_labels<S: Into<SequenceSet>>(&mut self, sequence_set: S, item: &str, labels: &[&str]) -> Result<()> {
+		try!(self.require_state("STORE", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		let labels: Vec<String> = labels.iter().map(|label| quote_mailbox_name(label)).collect();
+		self.run_command_and_check_ok(&format!("STORE {} {} ({})", sequence_set, item, labels.join(" ")).to_string())
+	}
+
+	/// Fetches Gmail's unique message ID of each message in the sequence set, keyed by message
+	/// sequence number.
+	#[cfg(feature = "gmail")]
+	pub fn fetch_gmail_msgid<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, u64>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} X-GM-MSGID", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_u64(lines, "X-GM-MSGID"),
 			Err(e) => Err(e)
 		}
 	}
 
-	pub fn run_command(&mut self, untagged_command: &str) -> Result<Vec<String>> {
-		let command = self.create_command(untagged_command.to_string());
+	/// Fetches Gmail's conversation thread ID of each message in the sequence set, keyed by
+	/// message sequence number.
+	#[cfg(feature = "gmail")]
+	pub fn fetch_gmail_thrid<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, u64>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} X-GM-THRID", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_u64(lines, "X-GM-THRID"),
+			Err(e) => Err(e)
+		}
+	}
+
+	#[cfg(feature = "gmail")]
+	fn parse_fetch_u64(lines: Vec<String>, item: &str) -> Result<HashMap<u32, u64>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut values = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			match find_item(&items, item).map(|token| token.as_str().and_then(|value| value.parse::<u64>().ok())) {
+				Some(Some(value)) => values.insert(message_number, value),
+				Some(None) => return Err(ImapError::Parse(format!("Invalid {} for message {}", item, message_number))),
+				None => continue
+			};
+		}
+
+		Ok(values)
+	}
+
+	/// Fetches the date and time each message in the sequence set arrived on the server, keyed by
+	/// message sequence number.
+	pub fn fetch_internaldate<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, DateTime<FixedOffset>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} INTERNALDATE", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_internaldate(lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_fetch_internaldate(lines: Vec<String>) -> Result<HashMap<u32, DateTime<FixedOffset>>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut dates = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			let date = match find_item(&items, "INTERNALDATE").map(Token::as_str) {
+				Some(Some(date)) => try!(parse_date_time(date)),
+				Some(None) => return Err(ImapError::Parse(format!("Invalid INTERNALDATE for message {}", message_number))),
+				None => continue
+			};
+			dates.insert(message_number, date);
+		}
+
+		Ok(dates)
+	}
+
+	/// Fetches the size in octets of each message in the sequence set, keyed by message sequence
+	/// number, without downloading the messages.
+	pub fn fetch_sizes<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, u32>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} RFC822.SIZE", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_sizes(lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_fetch_sizes(lines: Vec<String>) -> Result<HashMap<u32, u32>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut sizes = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			match find_item(&items, "RFC822.SIZE").map(|token| token.as_str().and_then(|size| size.parse::<u32>().ok())) {
+				Some(Some(size)) => sizes.insert(message_number, size),
+				Some(None) => return Err(ImapError::Parse(format!("Invalid RFC822.SIZE for message {}", message_number))),
+				None => continue
+			};
+		}
+
+		Ok(sizes)
+	}
+
+	/// Fetches and parses each message in the sequence set, keyed by message sequence number.
+	///
+	/// Fetching a message with RFC822 sets its `\Seen` flag, marking it as read for every client.
+	/// Use `fetch_messages_peek` to read messages without changing their flags.
+	pub fn fetch_messages<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, MimeMessage>> {
+		let raw_messages = try!(self.fetch_raw(sequence_set));
+		IMAPStream::parse_raw_messages(raw_messages)
+	}
+
+	/// Like `fetch_messages`, but fetches with `BODY.PEEK[]`, which leaves the `\Seen` flag of the
+	/// messages as it was.
+	pub fn fetch_messages_peek<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, MimeMessage>> {
+		let raw_messages = try!(self.fetch_raw_peek(sequence_set));
+		IMAPStream::parse_raw_messages(raw_messages)
+	}
+
+	fn parse_raw_messages(raw_messages: HashMap<u32, Vec<u8>>) -> Result<HashMap<u32, MimeMessage>> {
+		let mut messages = HashMap::new();
+		for (message_number, raw_message) in raw_messages.into_iter() {
+			match MimeMessage::parse(&String::from_utf8_lossy(&raw_message)) {
+				Ok(message) => messages.insert(message_number, message),
+				Err(_) => return Err(ImapError::Parse(format!("Invalid RFC822 message {}", message_number)))
+			};
+		}
+
+		Ok(messages)
+	}
+
+	/// Like `fetch_messages`, but returns the messages in the order the server sent them, which
+	/// is up to the server but usually follows the sequence set.
+	pub fn fetch_messages_ordered<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<Vec<(u32, MimeMessage)>> {
+		try!(self.fetch_messages_iter(sequence_set)).collect()
+	}
+
+	/// Fetches the exact RFC822 bytes of each message in the sequence set, keyed by message sequence
+	/// number, for parsing with an encoding-aware MIME parser. Sets the `\Seen` flag like
+	/// `fetch_messages`.
+	pub fn fetch_raw<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, Vec<u8>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		self.run_fetch_literal_command(&format!("FETCH {} RFC822", sequence_set).to_string())
+	}
+
+	/// Like `fetch_raw`, but fetches with `BODY.PEEK[]`, which leaves the `\Seen` flag of the
+	/// messages as it was.
+	pub fn fetch_raw_peek<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, Vec<u8>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		self.run_fetch_literal_command(&format!("FETCH {} BODY.PEEK[]", sequence_set).to_string())
+	}
+
+	/// Like `fetch_messages`, but returns an iterator that reads and parses one message at a time
+	/// as it is advanced, instead of holding every message in memory. The stream cannot be used
+	/// for other commands until the iterator is dropped; dropping it early reads and discards the
+	/// rest of the response.
+	pub fn fetch_messages_iter<'a, S: Into<SequenceSet>>(&'a mut self, sequence_set: S) -> Result<MessageIter<'a>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		let command = self.create_command(format!("FETCH {} RFC822", sequence_set).to_string());
+		let start_str = format!("{}{} ", self.tag_prefix, self.tag);
+
+		let ret = self.write_command(&*command);
+
+		self.advance_tag();
+
+		match ret {
+			Ok(_) => Ok(MessageIter { stream: self, start_str: start_str, current_message: None, done: false }),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Fetches the flags, internal date and parsed body of each message in the sequence set in a
+	/// single round trip, keyed by message sequence number. Sets the `\Seen` flag like
+	/// `fetch_messages`.
+	pub fn fetch_full<S: Into<SequenceSet>>(&mut self, sequence_set: S) -> Result<HashMap<u32, Message>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command(&format!("FETCH {} (FLAGS INTERNALDATE RFC822)", sequence_set).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_full(lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_fetch_full(lines: Vec<String>) -> Result<HashMap<u32, Message>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut messages = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(&lines)) {
+			let flags = match find_item(&items, "FLAGS").and_then(Token::as_list) {
+				Some(flags) => flags.iter().filter_map(Token::as_str).filter_map(|flag| flag.parse().ok()).collect(),
+				None => Vec::new()
+			};
+			let internal_date = match find_item(&items, "INTERNALDATE").and_then(Token::as_str) {
+				Some(date) => Some(try!(parse_date_time(date))),
+				None => None
+			};
+			let body = match find_item(&items, "RFC822").and_then(Token::to_string_lossy).map(|body| MimeMessage::parse(&body)) {
+				Some(Ok(body)) => body,
+				_ => return Err(ImapError::Parse(format!("Invalid RFC822 message {}", message_number)))
+			};
+			messages.insert(message_number, Message { flags: flags, internal_date: internal_date, body: body });
+		}
+
+		Ok(messages)
+	}
+
+	/// Fetches the given data items, e.g. `FLAGS` or `(FLAGS UID)`, of the messages in the sequence
+	/// set whose mod-sequence is greater than `modseq`, keyed by message sequence number. Requires
+	/// CONDSTORE to be enabled.
+	pub fn fetch_changed_since<S: Into<SequenceSet>>(&mut self, sequence_set: S, modseq: u64, items: &str) -> Result<HashMap<u32, FetchResult>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		let items = if items.starts_with('(') { items.to_string() } else { format!("({})", items) };
+		match self.run_command_bytes(&format!("FETCH {} {} (CHANGEDSINCE {})", sequence_set, items, modseq).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_results(&lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Fetches any combination of data items, e.g. `(FLAGS UID INTERNALDATE)`, in a single
+	/// command, keyed by message sequence number. The common items are parsed into the fields of
+	/// each `FetchResult`.
+	pub fn fetch_items<S: Into<SequenceSet>>(&mut self, sequence_set: S, items: &str) -> Result<HashMap<u32, FetchResult>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		match self.run_command_bytes(&format!("FETCH {} {}", sequence_set, items).to_string()) {
+			Ok(lines) => IMAPStream::parse_fetch_results(&lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Fetches the data items of a FETCH macro, keyed by message sequence number. Servers may
+	/// send the items in any order, or send others along with them; the items that are missing
+	/// are left empty in each `FetchResult`.
+	pub fn fetch_macro<S: Into<SequenceSet>>(&mut self, sequence_set: S, fetch_macro: FetchMacro) -> Result<HashMap<u32, FetchResult>> {
+		self.fetch_items(sequence_set, &fetch_macro.to_string())
+	}
 
-		match self.write_str(&*command) {
+	fn parse_fetch_results<L: AsRef<[u8]>>(lines: &[L]) -> Result<HashMap<u32, FetchResult>> {
+		//Check Ok
+		let status_line: Vec<String> = lines.last().map(|line| String::from_utf8_lossy(line.as_ref()).into_owned()).into_iter().collect();
+		match IMAPStream::parse_response_ok(&status_line) {
 			Ok(_) => (),
-			Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to write")),
+			Err(e) => return Err(e)
+		};
+
+		let mut results = HashMap::new();
+		for (message_number, items) in try!(parse_fetch_responses(lines)) {
+			let mut result = FetchResult {
+				flags: None,
+				uid: None,
+				internal_date: None,
+				rfc822_size: None,
+				envelope: None,
+				body_structure: None,
+				body_sections: HashMap::new(),
+				modseq: None,
+				items: Vec::new()
+			};
+
+			for pair in items.chunks(2) {
+				let (name, value) = match (pair[0].as_str(), pair.get(1)) {
+					(Some(name), Some(value)) => (name.to_uppercase(), value),
+					_ => continue
+				};
+				match &*name {
+					"FLAGS" => result.flags = value.as_list().map(|flags| flags.iter().filter_map(Token::as_str).filter_map(|flag| flag.parse().ok()).collect()),
+					"UID" => result.uid = value.as_str().and_then(|uid| uid.parse::<u32>().ok()),
+					"INTERNALDATE" => if let Some(date) = value.as_str() {
+						result.internal_date = Some(try!(parse_date_time(date)));
+					},
+					"RFC822.SIZE" => result.rfc822_size = value.as_str().and_then(|size| size.parse::<u32>().ok()),
+					"ENVELOPE" => result.envelope = Envelope::from_token(value),
+					"BODY" | "BODYSTRUCTURE" => result.body_structure = BodyStructure::from_token(value),
+					"MODSEQ" => result.modseq = value.as_list()
+						.and_then(|modseq| modseq.first())
+						.and_then(Token::as_str)
+						.and_then(|modseq| modseq.parse::<u64>().ok()),
+					_ if name.starts_with("BODY[") || name.starts_with("BINARY[") || name.starts_with("RFC822") => {
+						if let Some(data) = value.as_bytes() {
+							result.body_sections.insert(pair[0].as_str().unwrap().to_string(), data.to_vec());
+						}
+					},
+					_ => {}
+				}
+			}
+
+			result.items = items;
+			results.insert(message_number, result);
+		}
+
+		Ok(results)
+	}
+
+	/// Fetches a body section of each message in the sequence set without setting the `\Seen`
+	/// flag, keyed by message sequence number. The section is a part specifier such as `1.2`,
+	/// `HEADER` or `TEXT`, or empty for the whole message. A range of `(start, count)` fetches only
+	/// `count` octets starting at `start`.
+	pub fn fetch_body_section<S: Into<SequenceSet>>(&mut self, sequence_set: S, section: &str, range: Option<(u32, u32)>) -> Result<HashMap<u32, Vec<u8>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		let partial = match range {
+			Some((start, count)) => format!("<{}.{}>", start, count),
+			None => String::new()
 		};
+		self.run_fetch_literal_command(&format!("FETCH {} BODY.PEEK[{}]{}", sequence_set, section, partial).to_string())
+	}
+
+	/// Fetches a single part of a message, as found in its body structure, without setting the
+	/// `\Seen` flag, and decodes its transfer encoding.
+	pub fn fetch_part(&mut self, message_number: u32, part: &BodyStructurePart) -> Result<Vec<u8>> {
+		let mut sections = try!(self.fetch_body_section(message_number, &part.section, None));
+		match sections.remove(&message_number) {
+			Some(data) => part.decode(&data),
+			None => Err(ImapError::Parse(format!("The server sent no BODY[{}] for message {}", part.section, message_number)))
+		}
+	}
 
-		let ret = match self.read_response() {
-			Ok(lines) => Ok(lines),
-			Err(_) => Err(Error::new(ErrorKind::Other, "Failed to read")),
+	/// Fetches a body section like `fetch_body_section`, but writes it to the sink as it is read
+	/// instead of holding it in memory, so large messages and attachments can be saved directly
+	/// to a file. The sections of several messages are written one after another. Returns the
+	/// number of bytes written. A read timing out partway through a section leaves the connection
+	/// unusable, like a failed `is_alive`.
+	pub fn fetch_body_to<W: Write, S: Into<SequenceSet>>(&mut self, sequence_set: S, section: &str, sink: &mut W) -> Result<u64> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		let command = self.create_command(format!("FETCH {} BODY.PEEK[{}]", sequence_set, section));
+		let start_str = format!("{}{} ", self.tag_prefix, self.tag);
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => self.copy_fetch_literals(&start_str, sink),
+			Err(e) => Err(e)
 		};
 
-		self.tag += 1;
+		self.advance_tag();
 
 		return ret;
 	}
 
-	fn parse_response_ok(lines: Vec<String>) -> Result<()> {
-		let ok_regex = match Regex::new(r"^([a-zA-Z0-9]+) ([a-zA-Z0-9]+)(.*)") {
-    		Ok(re) => re,
-    		Err(err) => panic!("{}", err),
+	/// Reads a FETCH response up to the tagged completion line, copying the literal of every
+	/// message into the sink.
+	fn copy_fetch_literals<W: Write>(&mut self, start_str: &str, sink: &mut W) -> Result<u64> {
+		let mut written = 0;
+		loop {
+			let line = try!(self.connection.read_line());
+			try!(self.check_bye(&line));
+			let text = String::from_utf8_lossy(&line).into_owned();
+
+			if text.starts_with(start_str) {
+				try!(IMAPStream::parse_response_ok(&[text]));
+				return Ok(written);
+			}
+			self.record_unsolicited(&text);
+
+			if let Some(length) = literal_length(&line) {
+				match self.connection.copy_literal(length, sink) {
+					Ok(()) => written += length as u64,
+					Err(ImapError::TimedOut) => {
+						// The rest of the literal would be read as the response to the next command.
+						self.dead = true;
+						self.logged_out = true;
+						return Err(ImapError::TimedOut);
+					},
+					Err(e) => return Err(e)
+				}
+			}
+		}
+	}
+
+	/// Search returns the sequence numbers of the messages matching the search criteria, e.g.
+	/// `UNSEEN FROM "jane"`.
+	pub fn search(&mut self, query: &str) -> Result<Vec<u32>> {
+		try!(self.require_state("SEARCH", ConnectionState::Selected));
+		match self.run_command(&format!("SEARCH {}", query).to_string()) {
+			Ok(lines) => IMAPStream::parse_ids(lines, "SEARCH"),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Searches like `search`, with the strings in the criteria in the given charset, e.g.
+	/// `UTF-8`. Quoted or bare strings containing 8-bit text are sent as literals. A server that
+	/// doesn't support the charset fails with `ImapError::No` carrying a `BadCharset` response code
+	/// that lists the charsets it supports, if it sent them.
+	pub fn search_charset(&mut self, charset: &str, criteria: &str) -> Result<Vec<u32>> {
+		try!(self.require_state("SEARCH", ConnectionState::Selected));
+		match self.run_command(&format!("SEARCH CHARSET {} {}", charset, literalize_search_strings(criteria)).to_string()) {
+			Ok(lines) => IMAPStream::parse_ids(lines, "SEARCH"),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Returns the UIDs of every message in the selected mailbox in ascending order, using
+	/// `UID SEARCH ALL`. Servers may split the UIDs of a large mailbox over several SEARCH responses.
+	pub fn all_uids(&mut self) -> Result<Vec<u32>> {
+		try!(self.require_state("UID SEARCH", ConnectionState::Selected));
+		let mut uids = match self.run_command("UID SEARCH ALL") {
+			Ok(lines) => try!(IMAPStream::parse_ids(lines, "SEARCH")),
+			Err(e) => return Err(e)
 		};
-		let last_line = lines.last().unwrap();
+		uids.sort();
+		Ok(uids)
+	}
 
-		for cap in ok_regex.captures_iter(last_line) {
-			let response_type = cap.at(2).unwrap_or("");
-			if response_type == "OK" {
-				return Ok(());
+	/// Returns the UIDs of the messages added since the one with the given UID, in ascending
+	/// order, using `UID SEARCH UID n:*`. Since `*` is the last message, the server returns the UID
+	/// of the last message even when nothing newer exists, so UIDs up to `last_seen_uid` are dropped.
+	pub fn uids_since(&mut self, last_seen_uid: u32) -> Result<Vec<u32>> {
+		try!(self.require_state("UID SEARCH", ConnectionState::Selected));
+		if last_seen_uid == u32::max_value() {
+			return Ok(Vec::new());
+		}
+
+		let mut uids = match self.run_command(&format!("UID SEARCH UID {}:*", last_seen_uid + 1).to_string()) {
+			Ok(lines) => try!(IMAPStream::parse_ids(lines, "SEARCH")),
+			Err(e) => return Err(e)
+		};
+		uids.retain(|&uid| uid > last_seen_uid);
+		uids.sort();
+		Ok(uids)
+	}
+
+	/// Searches like `search`, but returns only the values asked for with the `COUNT`, `MIN`, `MAX`
+	/// and `ALL` options, which saves transferring every matching number. Uses ESEARCH when the
+	/// server supports it and computes the values from a plain SEARCH otherwise.
+	pub fn search_return(&mut self, criteria: &str, options: &[&str]) -> Result<EsearchResult> {
+		try!(self.require_state("SEARCH", ConnectionState::Selected));
+		if try!(self.has_capability("ESEARCH")) {
+			return match self.run_command(&format!("SEARCH RETURN ({}) {}", options.join(" "), criteria).to_string()) {
+				Ok(lines) => IMAPStream::parse_esearch(lines),
+				Err(e) => Err(e)
+			};
+		}
+
+		let mut ids = try!(self.search(criteria));
+		ids.sort();
+		// No options means ALL.
+		let wants = |option: &str| options.iter().any(|o| o.eq_ignore_ascii_case(option)) || (options.is_empty() && option == "ALL");
+		Ok(EsearchResult {
+			count: if wants("COUNT") { Some(ids.len() as u32) } else { None },
+			min: if wants("MIN") { ids.first().cloned() } else { None },
+			max: if wants("MAX") { ids.last().cloned() } else { None },
+			all: if wants("ALL") { ids } else { Vec::new() }
+		})
+	}
+
+	/// Searches like `search`, but returns the matching messages as a compact sequence set such as
+	/// `2,4:7,9`, which can be passed straight to `fetch`, `store` and the other methods taking a
+	/// sequence set. Returns `None` if nothing matches, since an empty set can't be sent. Uses
+	/// `SEARCH RETURN (ALL)` when the server supports ESEARCH, so the matches are never expanded
+	/// into single numbers.
+	pub fn search_all_set(&mut self, criteria: &str) -> Result<Option<String>> {
+		try!(self.require_state("SEARCH", ConnectionState::Selected));
+		if !try!(self.has_capability("ESEARCH")) {
+			let mut ids = try!(self.search(criteria));
+			ids.sort();
+			if ids.is_empty() {
+				return Ok(None);
 			}
+			return Ok(Some(SequenceSet::from(&ids[..]).to_string()));
 		}
 
-		return Err(Error::new(ErrorKind::Other, format!("Invalid Response: {}", last_line).to_string()));
+		match self.run_command(&format!("SEARCH RETURN (ALL) {}", criteria).to_string()) {
+			Ok(lines) => IMAPStream::parse_esearch_raw(&lines).map(|(_, all)| all),
+			Err(e) => Err(e)
+		}
 	}
 
-	fn write_str(&mut self, s: &str) -> Result<()> {
-		match self.stream {
-			IMAPStreamTypes::Ssl(ref mut stream) => stream.write_fmt(format_args!("{}", s)),
-			IMAPStreamTypes::Basic(ref mut stream) => stream.write_fmt(format_args!("{}", s)),
+	fn parse_esearch(lines: Vec<String>) -> Result<EsearchResult> {
+		let (mut result, all) = try!(IMAPStream::parse_esearch_raw(&lines));
+		if let Some(all) = all {
+			result.all = match parse_uid_set(&all) {
+				Some(ids) => ids,
+				None => return Err(ImapError::Parse(all))
+			};
 		}
+		Ok(result)
 	}
 
-	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-		match self.stream {
-			IMAPStreamTypes::Ssl(ref mut stream) => stream.read(buf),
-			IMAPStreamTypes::Basic(ref mut stream) => stream.read(buf),
+	/// Parses an ESEARCH response into its COUNT, MIN and MAX values and the ALL sequence set as
+	/// the server sent it, which may be too large to expand.
+	fn parse_esearch_raw(lines: &[String]) -> Result<(EsearchResult, Option<String>)> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut result = EsearchResult::default();
+		let mut all = None;
+		for line in lines.iter().filter(|line| line.starts_with("* ESEARCH")) {
+			let tokens = try!(parse_tokens(&line["* ESEARCH".len()..]));
+			let mut tokens = tokens.iter().skip_while(|token| token.as_list().is_some() || token.as_str().map_or(false, |s| s.eq_ignore_ascii_case("UID")));
+			while let (Some(name), Some(value)) = (tokens.next(), tokens.next()) {
+				let (name, value) = match (name.as_str(), value.as_str()) {
+					(Some(name), Some(value)) => (name.to_uppercase(), value),
+					_ => return Err(ImapError::Parse(line.clone()))
+				};
+				match &*name {
+					"COUNT" => result.count = Some(try!(parse_number(value, line))),
+					"MIN" => result.min = Some(try!(parse_number(value, line))),
+					"MAX" => result.max = Some(try!(parse_number(value, line))),
+					"ALL" => all = Some(value.to_string()),
+					_ => {}
+				}
+			}
 		}
+
+		Ok((result, all))
 	}
 
-	fn read_response(&mut self) -> Result<Vec<String>> {
-		//Carriage return
-		let cr = 0x0d;
-		//Line Feed
-		let lf = 0x0a;
-		let mut found_tag_line = false;
-		let start_str = format!("a{} ", self.tag);
-		let mut lines: Vec<String> = Vec::new();
+	/// Like `search`, but builds the query from typed criteria so that strings and dates are
+	/// always sent in a valid form. Criteria with 8-bit text are searched with `CHARSET UTF-8`.
+	pub fn search_typed(&mut self, criteria: &SearchCriteria) -> Result<Vec<u32>> {
+		let query = try!(criteria.render());
+		if query.is_ascii() {
+			self.search(&query)
+		} else {
+			self.search_charset("UTF-8", &query)
+		}
+	}
 
-		while !found_tag_line {
-			let mut line_buffer: Vec<u8> = Vec::new();
-			while line_buffer.len() < 2 || (line_buffer[line_buffer.len()-1] != lf && line_buffer[line_buffer.len()-2] != cr) {
-					let byte_buffer: &mut [u8] = &mut [0];
-					match self.read(byte_buffer) {
-						Ok(_) => {},
-						Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to read the response")),
+	/// Sort returns the sequence numbers of the messages matching the search criteria, ordered by
+	/// the server. The sort criteria are one or more keys such as `DATE SUBJECT`, each optionally
+	/// preceded by `REVERSE`; surrounding parentheses are optional. Requires the SORT capability.
+	pub fn sort(&mut self, sort_criteria: &str, charset: &str, search_criteria: &str) -> Result<Vec<u32>> {
+		try!(self.require_state("SORT", ConnectionState::Selected));
+		try!(self.require_capability("SORT"));
+
+		let sort_criteria = sort_criteria.trim();
+		let sort_criteria = if sort_criteria.starts_with('(') && sort_criteria.ends_with(')') {
+			&sort_criteria[1..sort_criteria.len() - 1]
+		} else {
+			sort_criteria
+		};
+
+		match self.run_command(&format!("SORT ({}) {} {}", sort_criteria, charset, search_criteria).to_string()) {
+			Ok(lines) => IMAPStream::parse_ids(lines, "SORT"),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Parses the numbers of the untagged SEARCH or SORT responses, in the order they were sent.
+	fn parse_ids(lines: Vec<String>, name: &str) -> Result<Vec<u32>> {
+		let ids_regex = match Regex::new(&format!(r"^\* {}((?: \d+)*) ?\r\n", name)) {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut ids = Vec::new();
+		for line in lines.iter() {
+			if let Some(cap) = ids_regex.captures(line) {
+				for id in cap.at(1).unwrap().split_whitespace() {
+					match id.parse::<u32>() {
+						Ok(id) => ids.push(id),
+						Err(_) => return Err(ImapError::Parse(line.clone()))
 					}
-					line_buffer.push(byte_buffer[0]);
+				}
 			}
+		}
 
-			let line = String::from_utf8(line_buffer).unwrap();
+		Ok(ids)
+	}
 
-			lines.push(line.clone());
+	/// Thread groups the messages matching the search criteria into conversations using the given
+	/// algorithm, `REFERENCES` or `ORDEREDSUBJECT`. Requires the matching THREAD capability.
+	pub fn thread(&mut self, algorithm: &str, charset: &str, search: &str) -> Result<Vec<ThreadNode>> {
+		try!(self.require_state("THREAD", ConnectionState::Selected));
+		try!(self.require_capability(&format!("THREAD={}", algorithm)));
 
-			if (&*line).starts_with(&*start_str) {
-				found_tag_line = true;
+		match self.run_command(&format!("THREAD {} {} {}", algorithm, charset, search).to_string()) {
+			Ok(lines) => IMAPStream::parse_thread(lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_thread(lines: Vec<String>) -> Result<Vec<ThreadNode>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut threads = Vec::new();
+		for line in lines.iter() {
+			if !line.starts_with("* THREAD") {
+				continue;
+			}
+			for token in try!(parse_tokens(&line["* THREAD".len()..])).iter() {
+				match ThreadNode::from_token(token) {
+					Some(thread) => threads.push(thread),
+					None => return Err(ImapError::Parse(line.clone()))
+				}
 			}
 		}
 
-		Ok(lines)
+		Ok(threads)
 	}
 
-	fn read_greeting(&mut self) -> Result<()> {
-		//Carriage return
-		let cr = 0x0d;
-		//Line Feed
-		let lf = 0x0a;
-
-		let mut line_buffer: Vec<u8> = Vec::new();
-		while line_buffer.len() < 2 || (line_buffer[line_buffer.len()-1] != lf && line_buffer[line_buffer.len()-2] != cr) {
-				let byte_buffer: &mut [u8] = &mut [0];
-				match self.read(byte_buffer) {
-					Ok(_) => {},
-					Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to read the response")),
+	/// Returns the quotas of every quota root the mailbox belongs to. Requires the QUOTA
+	/// capability.
+	pub fn get_quota_root(&mut self, mailbox: &str) -> Result<Vec<Quota>> {
+		try!(self.require_state("GETQUOTAROOT", ConnectionState::Authenticated));
+		try!(self.require_capability("QUOTA"));
+
+		match self.run_command(&format!("GETQUOTAROOT {}", quote_mailbox_name(mailbox)).to_string()) {
+			Ok(lines) => IMAPStream::parse_quota(lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_quota(lines: Vec<String>) -> Result<Vec<Quota>> {
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut quotas = Vec::new();
+		for line in lines.iter() {
+			if !line.starts_with("* QUOTA ") {
+				continue;
+			}
+
+			let tokens = try!(parse_tokens(&line["* QUOTA ".len()..]));
+			let (root, list) = match (tokens.get(0).and_then(Token::as_str), tokens.get(1).and_then(Token::as_list)) {
+				(Some(root), Some(list)) => (root, list),
+				_ => return Err(ImapError::Parse(line.clone()))
+			};
+
+			let mut resources = Vec::new();
+			for resource in list.chunks(3) {
+				let name = resource.get(0).and_then(Token::as_str);
+				let usage = resource.get(1).and_then(Token::as_str).and_then(|s| s.parse::<u64>().ok());
+				let limit = resource.get(2).and_then(Token::as_str).and_then(|s| s.parse::<u64>().ok());
+				match (name, usage, limit) {
+					(Some(name), Some(usage), Some(limit)) => resources.push((name.to_string(), usage, limit)),
+					_ => return Err(ImapError::Parse(line.clone()))
 				}
-				line_buffer.push(byte_buffer[0]);
+			}
+
+			quotas.push(Quota { root: decode_utf7(root), resources: resources });
 		}
 
-		Ok(())
+		Ok(quotas)
 	}
 
-	fn create_command(&mut self, command: String) -> String {
-		let command = format!("{}{} {}\r\n", self.tag_prefix, self.tag, command);
-		return command;
+	/// Fetches the verbatim header text of each message in the sequence set without setting the
+	/// `\Seen` flag, keyed by message sequence number. Only the named header fields are fetched,
+	/// or the whole header when no fields are given.
+	pub fn fetch_headers<S: Into<SequenceSet>>(&mut self, sequence_set: S, fields: &[&str]) -> Result<HashMap<u32, String>> {
+		let section = if fields.is_empty() {
+			"HEADER".to_string()
+		} else {
+			format!("HEADER.FIELDS ({})", fields.join(" "))
+		};
+
+		let raw_headers = try!(self.fetch_body_section(sequence_set, &section, None));
+		Ok(raw_headers.into_iter().map(|(message_number, header)| (message_number, String::from_utf8_lossy(&header).into_owned())).collect())
 	}
-}
 
-#[test]
-fn connect() {
-    let imap = IMAPStream::connect(("this-is-not-an-imap-server", 143), None);
-    assert!(imap.is_err());
+	/// Fetches a body part decoded by the server from its content transfer encoding, keyed by
+	/// message sequence number, without setting the `\Seen` flag. A part the server can't decode
+	/// fails with a NO response carrying `ResponseCode::UnknownCte`. Requires the BINARY capability.
+	pub fn fetch_binary<S: Into<SequenceSet>>(&mut self, sequence_set: S, section: &str) -> Result<HashMap<u32, Vec<u8>>> {
+		try!(self.require_state("FETCH", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		try!(self.require_capability("BINARY"));
+		self.run_fetch_literal_command(&format!("FETCH {} BINARY.PEEK[{}]", sequence_set, section).to_string())
+	}
+
+	/// Runs a FETCH command whose data item is returned as a literal, collecting the literal's
+	/// bytes for each message.
+	fn run_fetch_literal_command(&mut self, untagged_command: &str) -> Result<HashMap<u32, Vec<u8>>> {
+		let command = self.create_command(untagged_command.to_string());
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => self.read_fetch_literals(),
+			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		return ret;
+	}
+
+	/// Reads a FETCH response up to the tagged completion line, returning the first literal sent
+	/// for each message. Any further literals are read and discarded to keep the stream in sync.
+	fn read_fetch_literals(&mut self) -> Result<HashMap<u32, Vec<u8>>> {
+		let start_str = format!("{}{} ", self.tag_prefix, self.tag);
+
+		let mut literals = HashMap::new();
+		let mut current_message = None;
+		while let Some((message_number, literal)) = try!(self.read_next_fetch_literal(&start_str, &mut current_message)) {
+			literals.entry(message_number).or_insert(literal);
+		}
+		Ok(literals)
+	}
+
+	/// Reads a FETCH response until the next literal belonging to a message, returning the message
+	/// number and the literal. Returns `None` once the tagged completion line, starting with
+	/// `start_str`, has been read and checked. `current_message` tracks the message whose response
+	/// is being read between calls.
+	fn read_next_fetch_literal(&mut self, start_str: &str, current_message: &mut Option<u32>) -> Result<Option<(u32, Vec<u8>)>> {
+		let fetch_regex = match Regex::new(r"^\* (\d+) FETCH ") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		loop {
+			let line = try!(self.connection.read_line());
+			try!(self.check_bye(&line));
+			let text = String::from_utf8_lossy(&line).into_owned();
+
+			if text.starts_with(start_str) {
+				try!(IMAPStream::parse_response_ok(&[text]));
+				return Ok(None);
+			}
+			self.record_unsolicited(&text);
+
+			if let Some(cap) = fetch_regex.captures(&text) {
+				*current_message = cap.at(1).unwrap().parse::<u32>().ok();
+			}
+
+			if let Some(length) = literal_length(&line) {
+				let literal = try!(self.connection.read_literal(length));
+				if let Some(message_number) = *current_message {
+					return Ok(Some((message_number, literal)));
+				}
+			}
+		}
+	}
+
+	/// Noop always succeeds, and it does nothing.
+	pub fn noop(&mut self) -> Result<()> {
+		self.run_command_and_check_ok("NOOP")
+	}
+
+	/// Checks whether the connection is still usable by sending a NOOP, waiting at most a few
+	/// seconds for the answer. Any failure is reported as `false`, after which every command fails
+	/// without being sent, so a dead connection isn't used again by mistake.
+	pub fn is_alive(&mut self) -> bool {
+		if self.dead || self.bye.is_some() {
+			return false;
+		}
+
+		let alive = self.connection.get_ref().set_read_timeout(Some(Duration::from_secs(ALIVE_TIMEOUT_SECS))).is_ok()
+			&& self.noop().is_ok()
+			&& self.connection.get_ref().set_read_timeout(self.read_timeout).is_ok();
+		if !alive {
+			self.dead = true;
+			self.logged_out = true;
+		}
+		alive
+	}
+
+	/// Watches the selected mailbox with IDLE, calling the handler with each EXISTS, RECENT and
+	/// EXPUNGE response the server pushes until it returns `ControlFlow::Break`. The IDLE command
+	/// is ended with DONE and sent again every 29 minutes, before servers time it out. Responses
+	/// left over when the handler breaks are kept for `take_unsolicited`. Requires the IDLE
+	/// capability.
+	pub fn idle_loop<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, handler: F) -> Result<()> {
+		self.idle_loop_renewing(handler, Duration::from_secs(IDLE_RENEWAL_SECS))
+	}
+
+	fn idle_loop_renewing<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, mut handler: F, renewal: Duration) -> Result<()> {
+		try!(self.require_state("IDLE", ConnectionState::Authenticated));
+		try!(self.require_capability("IDLE"));
+
+		let ret = self.run_idle(&mut handler, renewal);
+		match self.connection.get_ref().set_read_timeout(self.read_timeout) {
+			Ok(_) => ret,
+			Err(e) => ret.and(Err(ImapError::Io(e)))
+		}
+	}
+
+	fn run_idle<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, handler: &mut F, renewal: Duration) -> Result<()> {
+		loop {
+			let command = self.create_command("IDLE".to_string());
+
+			let ret = match self.write_command(&*command) {
+				Ok(_) => self.idle_until_done(handler, renewal),
+				Err(e) => Err(e)
+			};
+
+			self.advance_tag();
+
+			if let ControlFlow::Break(()) = try!(ret) {
+				return Ok(());
+			}
+		}
+	}
+
+	/// Waits for responses to a running IDLE command until the handler breaks or it is time to
+	/// renew the command, then ends it with DONE. Returns whether the handler broke.
+	fn idle_until_done<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, handler: &mut F, renewal: Duration) -> Result<ControlFlow<()>> {
+		try!(self.read_continuation());
+		let deadline = Instant::now() + renewal;
+
+		let mut flow = ControlFlow::Continue(());
+		loop {
+			if let ControlFlow::Break(()) = self.dispatch_unsolicited(handler) {
+				flow = ControlFlow::Break(());
+				break;
+			}
+			let now = Instant::now();
+			if now >= deadline {
+				break;
+			}
+			try!(self.connection.get_ref().set_read_timeout(Some(deadline - now)));
+			match self.read_response_line() {
+				Ok(line) => self.record_unsolicited(&String::from_utf8_lossy(&line)),
+				Err(ImapError::TimedOut) => {},
+				Err(e) => return Err(e)
+			}
+		}
+
+		debug!("C: DONE");
+		try!(self.connection.get_ref().set_read_timeout(self.read_timeout));
+		try!(self.connection.send_line("DONE"));
+		let lines = try!(self.read_response());
+		try!(IMAPStream::parse_response_ok(&lines));
+		Ok(flow)
+	}
+
+	/// Selects the mailbox and watches it like `idle_loop`, but recovers from a dropped
+	/// connection: when the connection fails or the server sends BYE, it reconnects, logs in
+	/// again with the given credentials, selects the mailbox again and goes back to IDLE. The
+	/// handler is then called with `Unsolicited::Reconnected`, listing the messages that arrived
+	/// in the meantime. To tell those apart from messages the handler already heard of, new
+	/// messages briefly end the IDLE to look up their UIDs before the handler is called with
+	/// `Exists`. Gives up after a few failed attempts to reconnect in a row, and returns any other
+	/// error, such as failing to log in, right away.
+	pub fn idle_resilient<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, username: &str, password: &str, mailbox_name: &str, handler: F) -> Result<()> {
+		self.idle_resilient_with(username, password, mailbox_name, handler, Duration::from_secs(IDLE_RENEWAL_SECS), Duration::from_secs(RECONNECT_DELAY_SECS))
+	}
+
+	fn idle_resilient_with<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, username: &str, password: &str, mailbox_name: &str, mut handler: F, renewal: Duration, retry_delay: Duration) -> Result<()> {
+		let selected = try!(self.select(mailbox_name));
+		let mut uid_validity = selected.uid_validity;
+		let mut known_exists = selected.exists;
+		// The highest UID the handler already knows of, from the selected mailbox or from the
+		// EXISTS responses since, so that `Reconnected` lists only the messages it missed.
+		let mut last_seen_uid = selected.uid_next.unwrap_or(1).saturating_sub(1);
+		loop {
+			let ret = self.idle_until_arrival(&mut handler, &mut known_exists, renewal).and_then(|arrival| match arrival {
+				Some(exists) => self.uids_since(last_seen_uid).map(|uids| Some((exists, uids))),
+				None => Ok(None)
+			});
+			match ret {
+				Ok(Some((exists, uids))) => {
+					last_seen_uid = uids.last().map_or(last_seen_uid, |&uid| cmp::max(uid, last_seen_uid));
+					if let ControlFlow::Break(()) = handler(exists) {
+						return Ok(());
+					}
+					continue;
+				},
+				Ok(None) => return Ok(()),
+				Err(ImapError::Io(_)) | Err(ImapError::Bye(_)) | Err(ImapError::TimedOut) => {},
+				Err(e) => return Err(e)
+			}
+
+			let reselected = try!(self.reconnect_and_select(username, password, mailbox_name, retry_delay));
+			let uid_validity_changed = reselected.uid_validity != uid_validity;
+			let new_uids = match reselected.uid_next {
+				Some(uid_next) if !uid_validity_changed && uid_next.saturating_sub(1) > last_seen_uid => try!(self.uids_since(last_seen_uid)),
+				_ => Vec::new()
+			};
+			last_seen_uid = match new_uids.last() {
+				Some(&uid) => uid,
+				None if uid_validity_changed => reselected.uid_next.unwrap_or(1).saturating_sub(1),
+				None => last_seen_uid
+			};
+			uid_validity = reselected.uid_validity;
+			known_exists = reselected.exists;
+
+			let reconnected = Unsolicited::Reconnected { exists: reselected.exists, new_uids: new_uids, uid_validity_changed: uid_validity_changed };
+			if let ControlFlow::Break(()) = handler(reconnected) {
+				return Ok(());
+			}
+		}
+	}
+
+	/// Runs IDLE like `idle_loop` until the handler breaks, returning `None`, or until an EXISTS
+	/// response announces new messages, returning it without passing it to the handler yet so the
+	/// UIDs of the new messages can be looked up first.
+	fn idle_until_arrival<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, handler: &mut F, known_exists: &mut u32, renewal: Duration) -> Result<Option<Unsolicited>> {
+		let mut arrival = None;
+		try!(self.idle_loop_renewing(|unsolicited| {
+			match unsolicited {
+				Unsolicited::Exists(exists) if exists > *known_exists => {
+					*known_exists = exists;
+					arrival = Some(unsolicited);
+					return ControlFlow::Break(());
+				},
+				Unsolicited::Exists(exists) => *known_exists = exists,
+				Unsolicited::Expunge(_) => *known_exists = known_exists.saturating_sub(1),
+				_ => {}
+			}
+			handler(unsolicited)
+		}, renewal));
+		Ok(arrival)
+	}
+
+	/// Reconnects, logs in unless the server greets with PREAUTH, and selects the mailbox,
+	/// retrying with a growing delay while the connection keeps failing.
+	fn reconnect_and_select(&mut self, username: &str, password: &str, mailbox_name: &str, retry_delay: Duration) -> Result<IMAPMailbox> {
+		let mut delay = retry_delay;
+		let mut attempt = 1;
+		loop {
+			let ret = self.reconnect().and_then(|_| {
+				match self.state {
+					ConnectionState::NotAuthenticated => self.login(username, password),
+					_ => Ok(())
+				}
+			}).and_then(|_| self.select(mailbox_name));
+
+			match ret {
+				Ok(mailbox) => return Ok(mailbox),
+				Err(ImapError::Io(_)) | Err(ImapError::Bye(_)) | Err(ImapError::TimedOut) if attempt < RECONNECT_ATTEMPTS => {},
+				Err(e) => return Err(e)
+			}
+			debug!("Reconnect attempt {} failed, retrying in {:?}", attempt, delay);
+			thread::sleep(delay);
+			delay = delay * 2;
+			attempt += 1;
+		}
+	}
+
+	/// Passes the unsolicited responses received so far to the handler, keeping the rest once it
+	/// breaks.
+	fn dispatch_unsolicited<F: FnMut(Unsolicited) -> ControlFlow<()>>(&mut self, handler: &mut F) -> ControlFlow<()> {
+		let mut pending = ::std::mem::replace(&mut self.unsolicited, Vec::new()).into_iter();
+		while let Some(unsolicited) = pending.next() {
+			if let ControlFlow::Break(()) = handler(unsolicited) {
+				self.unsolicited.extend(pending);
+				return ControlFlow::Break(());
+			}
+		}
+		ControlFlow::Continue(())
+	}
+
+	/// Logout informs the server that the client is done with the connection.
+	pub fn logout(&mut self) -> Result<()> {
+		self.logged_out = true;
+		self.run_command_and_check_ok("LOGOUT")
+	}
+
+	/// Create creates a mailbox with the given name.
+	pub fn create(&mut self, mailbox_name: &str) -> Result<()> {
+		try!(self.require_state("CREATE", ConnectionState::Authenticated));
+		self.run_command_and_check_ok(&format!("CREATE {}", quote_mailbox_name(mailbox_name)).to_string())
+	}
+
+	/// Creates a mailbox and marks it with the given special-use attributes, such as `\Archive` or
+	/// `\Sent`, in the same command. Requires the CREATE-SPECIAL-USE capability.
+	pub fn create_special_use(&mut self, mailbox_name: &str, special_use: &[&str]) -> Result<()> {
+		try!(self.require_state("CREATE", ConnectionState::Authenticated));
+		try!(self.require_capability("CREATE-SPECIAL-USE"));
+
+		self.run_command_and_check_ok(&format!("CREATE {} (USE ({}))", quote_mailbox_name(mailbox_name), special_use.join(" ")))
+	}
+
+	/// Creates a mailbox along with any of its parents that don't exist yet, one level of the
+	/// hierarchy at a time, for servers that don't create them implicitly. Parents the server
+	/// reports as already existing are skipped.
+	pub fn create_with_parents(&mut self, mailbox_name: &str) -> Result<()> {
+		let delimiter = match try!(self.hierarchy_delimiter()) {
+			Some(delimiter) => delimiter,
+			None => return self.create(mailbox_name)
+		};
+
+		let mut end = 0;
+		while let Some(offset) = mailbox_name[end..].find(delimiter) {
+			end += offset;
+			if end > 0 {
+				match self.create(&mailbox_name[..end]) {
+					Ok(()) => {},
+					Err(ImapError::No(ResponseText { code: Some(ResponseCode::AlreadyExists), .. })) => {},
+					Err(e) => return Err(e)
+				}
+			}
+			end += delimiter.len_utf8();
+		}
+		self.create(mailbox_name)
+	}
+
+	/// Delete permanently removes the mailbox with the given name.
+	pub fn delete(&mut self, mailbox_name: &str) -> Result<()> {
+		try!(self.require_state("DELETE", ConnectionState::Authenticated));
+		self.run_command_and_check_ok(&format!("DELETE {}", quote_mailbox_name(mailbox_name)).to_string())
+	}
+
+	/// Rename changes the name of a mailbox. Both names may contain spaces and non-ASCII
+	/// characters.
+	///
+	/// Renaming INBOX is special: the server moves all of its messages into a new mailbox with the
+	/// new name and leaves INBOX in place, empty. Servers that don't allow it fail with
+	/// `ImapError::No` and the `CANNOT` response code, and the text then says that INBOX can't be
+	/// renamed. Other failures, such as the new name being taken, are returned as sent.
+	///
+	/// ```no_run
+	/// # use imap::client::IMAPStream;
+	/// fn archive_inbox(imap: &mut IMAPStream) -> imap::error::Result<()> {
+	/// 	// Moves everything in INBOX to "Archive 2016", leaving INBOX empty.
+	/// 	imap.rename("INBOX", "Archive 2016")
+	/// }
+	/// ```
+	pub fn rename(&mut self, current_mailbox_name: &str, new_mailbox_name: &str) -> Result<()> {
+		try!(self.require_state("RENAME", ConnectionState::Authenticated));
+		match self.run_command_and_check_ok(&format!("RENAME {} {}", quote_mailbox_name(current_mailbox_name), quote_mailbox_name(new_mailbox_name)).to_string()) {
+			Err(ImapError::No(response_text)) if response_text.code == Some(ResponseCode::Cannot) && current_mailbox_name.eq_ignore_ascii_case("INBOX") => Err(ImapError::No(ResponseText {
+				text: format!("The server doesn't allow renaming INBOX: {}", response_text.text),
+				code: response_text.code
+			})),
+			ret => ret
+		}
+	}
+
+	/// Subscribe adds the specified mailbox name to the server's set of "active" or "subscribed"
+	/// mailboxes as returned by the LSUB command.
+	pub fn subscribe(&mut self, mailbox: &str) -> Result<()> {
+		try!(self.require_state("SUBSCRIBE", ConnectionState::Authenticated));
+		self.run_command_and_check_ok(&format!("SUBSCRIBE {}", quote_mailbox_name(mailbox)).to_string())
+	}
+
+	/// Unsubscribe removes the specified mailbox name from the server's set of "active" or "subscribed"
+	/// mailboxes as returned by the LSUB command.
+	pub fn unsubscribe(&mut self, mailbox: &str) -> Result<()> {
+		try!(self.require_state("UNSUBSCRIBE", ConnectionState::Authenticated));
+		self.run_command_and_check_ok(&format!("UNSUBSCRIBE {}", quote_mailbox_name(mailbox)).to_string())
+	}
+
+	/// List returns the mailboxes matching the given pattern, relative to the reference name.
+	pub fn list(&mut self, reference_name: &str, mailbox_search_pattern: &str) -> Result<Vec<Mailbox>> {
+		try!(self.require_state("LIST", ConnectionState::Authenticated));
+		match self.run_command(&format!("LIST {} {}", quote_mailbox_name(reference_name), quote_mailbox_name(mailbox_search_pattern)).to_string()) {
+			Ok(lines) => IMAPStream::parse_list(lines, "LIST"),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Returns the character the server separates levels of the mailbox hierarchy with, such as
+	/// `/` or `.`, or `None` for a flat hierarchy. It is requested once and cached.
+	pub fn hierarchy_delimiter(&mut self) -> Result<Option<char>> {
+		if let Some(delimiter) = self.delimiter {
+			return Ok(delimiter);
+		}
+
+		// LIST with an empty mailbox name returns just the delimiter and root name.
+		let delimiter = match try!(self.list("", "")).first() {
+			Some(mailbox) => mailbox.delimiter,
+			None => return Err(ImapError::Parse("No LIST response for the hierarchy delimiter".to_string()))
+		};
+		self.delimiter = Some(delimiter);
+		Ok(delimiter)
+	}
+
+	/// Runs an extended LIST with the given selection options, such as `SUBSCRIBED` or
+	/// `SPECIAL-USE`, and return options, such as `SPECIAL-USE` or `CHILDREN`. Either set of options
+	/// may be empty. Requires the LIST-EXTENDED capability.
+	pub fn list_extended(&mut self, selection_options: &str, reference_name: &str, mailbox_search_pattern: &str, return_options: &str) -> Result<Vec<Mailbox>> {
+		try!(self.require_state("LIST", ConnectionState::Authenticated));
+		try!(self.require_capability("LIST-EXTENDED"));
+
+		let mut command = "LIST ".to_string();
+		if !selection_options.is_empty() {
+			command.push_str(&format!("({}) ", selection_options));
+		}
+		command.push_str(&format!("{} {}", quote_mailbox_name(reference_name), quote_mailbox_name(mailbox_search_pattern)));
+		if !return_options.is_empty() {
+			command.push_str(&format!(" RETURN ({})", return_options));
+		}
+
+		match self.run_command(&command) {
+			Ok(lines) => IMAPStream::parse_list(lines, "LIST"),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Returns the name of the mailbox with the given special-use attribute, such as `\Sent`,
+	/// `\Trash` or `\Drafts`, if there is one. Requires the SPECIAL-USE and LIST-EXTENDED
+	/// capabilities.
+	pub fn find_special_use(&mut self, attribute: &str) -> Result<Option<String>> {
+		try!(self.require_capability("SPECIAL-USE"));
+
+		let mailboxes = try!(self.list_extended("", "", "*", "SPECIAL-USE"));
+		Ok(mailboxes.into_iter()
+			.find(|mailbox| mailbox.attributes.iter().any(|a| a.eq_ignore_ascii_case(attribute)))
+			.map(|mailbox| mailbox.name))
+	}
+
+	/// Returns the access control list of the mailbox as pairs of identifier and rights.
+	/// Requires the ACL capability.
+	pub fn get_acl(&mut self, mailbox: &str) -> Result<Vec<(String, String)>> {
+		try!(self.require_state("GETACL", ConnectionState::Authenticated));
+		try!(self.require_capability("ACL"));
+
+		let lines = try!(self.run_command(&format!("GETACL {}", quote_mailbox_name(mailbox)).to_string()));
+
+		//Check Ok
+		try!(IMAPStream::parse_response_ok(&lines));
+
+		let mut acl = Vec::new();
+		for tokens in try!(IMAPStream::parse_untagged_data(&lines, "ACL")) {
+			// The mailbox name comes first, followed by identifier and rights pairs.
+			for pair in tokens[1..].chunks(2) {
+				match (pair.get(0).and_then(Token::as_str), pair.get(1).and_then(Token::as_str)) {
+					(Some(identifier), Some(rights)) => acl.push((identifier.to_string(), rights.to_string())),
+					_ => return Err(ImapError::Parse("Invalid ACL response".to_string()))
+				}
+			}
+		}
+
+		Ok(acl)
+	}
+
+	/// Grants the identifier the given rights on the mailbox, replacing its current rights. The
+	/// rights may start with `+` or `-` to add or remove rights instead. Requires the ACL
+	/// capability.
+	pub fn set_acl(&mut self, mailbox: &str, identifier: &str, rights: &str) -> Result<()> {
+		try!(self.require_state("SETACL", ConnectionState::Authenticated));
+		try!(self.require_capability("ACL"));
+		self.run_command_and_check_ok(&format!("SETACL {} {} {}", quote_mailbox_name(mailbox), quote_argument(identifier), quote_argument(rights)).to_string())
+	}
+
+	/// Returns the rights the logged in user has on the mailbox. Requires the ACL capability.
+	pub fn my_rights(&mut self, mailbox: &str) -> Result<String> {
+		try!(self.require_state("MYRIGHTS", ConnectionState::Authenticated));
+		try!(self.require_capability("ACL"));
+
+		let lines = try!(self.run_command(&format!("MYRIGHTS {}", quote_mailbox_name(mailbox)).to_string()));
+
+		//Check Ok
+		try!(IMAPStream::parse_response_ok(&lines));
+
+		for tokens in try!(IMAPStream::parse_untagged_data(&lines, "MYRIGHTS")) {
+			if let Some(rights) = tokens.get(1).and_then(Token::as_str) {
+				return Ok(rights.to_string());
+			}
+		}
+
+		Err(ImapError::Parse("Error parsing MYRIGHTS response".to_string()))
+	}
+
+	/// Returns the number of messages in the mailbox without the \Seen flag, using STATUS.
+	pub fn unread_count(&mut self, mailbox: &str) -> Result<u32> {
+		self.status_item(mailbox, "UNSEEN")
+	}
+
+	/// Returns the number of messages in the mailbox, using STATUS.
+	pub fn message_count(&mut self, mailbox: &str) -> Result<u32> {
+		self.status_item(mailbox, "MESSAGES")
+	}
+
+	/// Requests a single STATUS data item of a mailbox.
+	fn status_item(&mut self, mailbox: &str, item: &str) -> Result<u32> {
+		try!(self.require_state("STATUS", ConnectionState::Authenticated));
+
+		let lines = try!(self.run_command(&format!("STATUS {} ({})", quote_mailbox_name(mailbox), item).to_string()));
+
+		//Check Ok
+		try!(IMAPStream::parse_response_ok(&lines));
+
+		for tokens in try!(IMAPStream::parse_untagged_data(&lines, "STATUS")) {
+			let value = tokens.get(1).and_then(Token::as_list).and_then(|items| find_item(items, item)).and_then(Token::as_str);
+			if let Some(value) = value {
+				return parse_number(value, &format!("{} {}", item, value));
+			}
+		}
+		Err(ImapError::Parse("Error parsing STATUS response".to_string()))
+	}
+
+	/// Parses the data of every `* <name> ...` response into tokens.
+	fn parse_untagged_data(lines: &[String], name: &str) -> Result<Vec<Vec<Token>>> {
+		let prefix = format!("* {} ", name);
+		let mut responses = Vec::new();
+		for line in lines.iter() {
+			if line.starts_with(&*prefix) {
+				let tokens = try!(parse_tokens(&line[prefix.len()..]));
+				if tokens.is_empty() {
+					return Err(ImapError::Parse(line.clone()));
+				}
+				responses.push(tokens);
+			}
+		}
+		Ok(responses)
+	}
+
+	/// Lsub returns the subscribed mailboxes matching the given pattern, relative to the
+	/// reference name.
+	pub fn lsub(&mut self, reference_name: &str, mailbox_search_pattern: &str) -> Result<Vec<Mailbox>> {
+		try!(self.require_state("LSUB", ConnectionState::Authenticated));
+		match self.run_command(&format!("LSUB {} {}", quote_mailbox_name(reference_name), quote_mailbox_name(mailbox_search_pattern)).to_string()) {
+			Ok(lines) => IMAPStream::parse_list(lines, "LSUB"),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn parse_list(lines: Vec<String>, command: &str) -> Result<Vec<Mailbox>> {
+		let list_regex = match Regex::new(&format!(r#"(?s)^\* {} \(([^)]*)\) (NIL|"(?:[^"\\]|\\.)*") (.*)\r\n$"#, command)) {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut mailboxes = Vec::new();
+		for line in lines.iter() {
+			let cap = match list_regex.captures(line) {
+				Some(cap) => cap,
+				None => continue
+			};
+
+			let attributes = cap.at(1).unwrap().split_whitespace().map(|x| x.to_string()).collect();
+			let delimiter = match cap.at(2).unwrap() {
+				"NIL" => None,
+				quoted => unquote(quoted).chars().next()
+			};
+			// The name may be followed by LIST-EXTENDED data, e.g. ("CHILDINFO" ("SUBSCRIBED")).
+			let name = match try!(parse_tokens(cap.at(3).unwrap())).first().and_then(Token::as_str) {
+				Some(name) => name.to_string(),
+				None => return Err(ImapError::Parse(line.to_string()))
+			};
+
+			mailboxes.push(Mailbox {
+				attributes: attributes,
+				delimiter: delimiter,
+				name: decode_utf7(&name)
+			});
+		}
+
+		Ok(mailboxes)
+	}
+
+	/// Capability requests a listing of capabilities that the server supports.
+	pub fn capability(&mut self) -> Result<Vec<String>> {
+		match self.run_command(&format!("CAPABILITY").to_string()) {
+			Ok(lines) => {
+				let capabilities = try!(IMAPStream::parse_capability(lines));
+				self.capabilities = Some(capabilities.clone());
+				Ok(capabilities)
+			},
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Checks whether the server advertises the given capability, such as `IDLE` or `AUTH=PLAIN`.
+	/// The capability list is requested once and cached until a command that can change it, like
+	/// STARTTLS, ENABLE or a login, is run. Names are compared case-insensitively.
+	pub fn has_capability(&mut self, name: &str) -> Result<bool> {
+		if self.capabilities.is_none() {
+			try!(self.capability());
+		}
+
+		match self.capabilities {
+			Some(ref capabilities) => Ok(capabilities.iter().any(|capability| capability.eq_ignore_ascii_case(name))),
+			None => Ok(false)
+		}
+	}
+
+	/// Enables the given extensions, such as `CONDSTORE` or `QRESYNC`, returning the ones the
+	/// server actually enabled. Requires the ENABLE capability.
+	pub fn enable(&mut self, capabilities: &[&str]) -> Result<Vec<String>> {
+		try!(self.require_state("ENABLE", ConnectionState::Authenticated));
+		try!(self.require_capability("ENABLE"));
+
+		let lines = try!(self.run_command(&format!("ENABLE {}", capabilities.join(" ")).to_string()));
+		let enabled = try!(IMAPStream::parse_enabled(lines));
+		// The server may advertise different capabilities once extensions are enabled.
+		self.capabilities = None;
+		Ok(enabled)
+	}
+
+	fn parse_enabled(lines: Vec<String>) -> Result<Vec<String>> {
+		let enabled_regex = match Regex::new(r"^\* ENABLED(.*)\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		let mut enabled = Vec::new();
+		for line in lines.iter() {
+			if let Some(cap) = enabled_regex.captures(line) {
+				enabled.extend(cap.at(1).unwrap().split_whitespace().map(|x| x.to_string()));
+			}
+		}
+
+		Ok(enabled)
+	}
+
+	/// Fails with `ImapError::InvalidState` unless the connection is in the state the command
+	/// needs. Commands needing an authenticated connection can also be sent with a mailbox
+	/// selected.
+	fn require_state(&self, command: &str, state: ConnectionState) -> Result<()> {
+		let requirement = match state {
+			ConnectionState::Authenticated if self.state == ConnectionState::NotAuthenticated => "logging in first",
+			ConnectionState::Selected if self.state != ConnectionState::Selected => "a selected mailbox",
+			_ => return Ok(())
+		};
+		Err(ImapError::InvalidState(format!("Command {} requires {}", command, requirement)))
+	}
+
+	/// Fails with `ImapError::Unsupported` unless the server advertises the capability.
+	fn require_capability(&mut self, name: &str) -> Result<()> {
+		if try!(self.has_capability(name)) {
+			Ok(())
+		} else {
+			Err(ImapError::Unsupported(name.to_string()))
+		}
+	}
+
+	fn parse_capability(lines: Vec<String>) -> Result<Vec<String>> {
+		let capability_regex = match Regex::new(r"^\* CAPABILITY (.*)\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		//Check Ok
+		match IMAPStream::parse_response_ok(&lines) {
+			Ok(_) => (),
+			Err(e) => return Err(e)
+		};
+
+		for line in lines.iter() {
+			if capability_regex.is_match(line) {
+				let cap = capability_regex.captures(line).unwrap();
+				let capabilities_str = cap.at(1).unwrap();
+				return Ok(capabilities_str.split(' ').map(|x| x.to_string()).collect());
+			}
+		}
+
+		Err(ImapError::Parse("Error parsing capabilities response".to_string()))
+	}
+
+	/// Expunge permanently removes all messages that have the \Deleted flag set from the currently
+	/// selected mailbox. Returns the sequence numbers the server reported as expunged, in the
+	/// order it sent them; each is relative to the mailbox after the previous ones were removed.
+	pub fn expunge(&mut self) -> Result<Vec<u32>> {
+		try!(self.require_state("EXPUNGE", ConnectionState::Selected));
+		let lines = try!(self.run_command("EXPUNGE"));
+		try!(IMAPStream::parse_response_ok(&lines));
+		IMAPStream::parse_expunged(&lines)
+	}
+
+	/// Permanently removes the messages with the given UIDs that have the \Deleted flag set,
+	/// leaving other deleted messages alone. Returns the sequence numbers the server reported as
+	/// expunged, in the order it sent them; each is relative to the mailbox after the previous
+	/// ones were removed. Fails with `ImapError::Unsupported` if the server lacks the UIDPLUS
+	/// capability, in which case `expunge` can be used instead.
+	pub fn uid_expunge<S: Into<SequenceSet>>(&mut self, uid_set: S) -> Result<Vec<u32>> {
+		try!(self.require_state("UID EXPUNGE", ConnectionState::Selected));
+		let uid_set = try!(uid_set.into().to_argument());
+		try!(self.require_capability("UIDPLUS"));
+
+		let lines = try!(self.run_command(&format!("UID EXPUNGE {}", uid_set).to_string()));
+		try!(IMAPStream::parse_response_ok(&lines));
+		IMAPStream::parse_expunged(&lines)
+	}
+
+	fn parse_expunged(lines: &[String]) -> Result<Vec<u32>> {
+		let expunge_regex = match Regex::new(r"^\* (\d+) EXPUNGE\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let mut expunged = Vec::new();
+		for line in lines.iter() {
+			if let Some(cap) = expunge_regex.captures(line) {
+				expunged.push(try!(parse_number(cap.at(1).unwrap(), line)));
+			}
+		}
+		Ok(expunged)
+	}
+
+	/// Deletes every message in the selected mailbox by flagging them all \Deleted and expunging
+	/// them, returning the number of messages expunged. Fails with `ImapError::InvalidState`
+	/// without sending anything if the mailbox was opened read-only.
+	pub fn empty_mailbox(&mut self) -> Result<u32> {
+		try!(self.require_state("EXPUNGE", ConnectionState::Selected));
+		let exists = match self.mailbox {
+			Some(ref mailbox) if mailbox.read_only => return Err(ImapError::InvalidState("Cannot empty a mailbox opened read-only".to_string())),
+			Some(ref mailbox) => mailbox.exists,
+			None => 0
+		};
+		// STORE 1:* fails on some servers when the mailbox is empty.
+		if exists == 0 {
+			return Ok(0);
+		}
+
+		try!(self.run_command_and_check_ok("STORE 1:* +FLAGS.SILENT (\\Deleted)"));
+		Ok(try!(self.expunge()).len() as u32)
+	}
+
+	/// Check requests a checkpoint of the currently selected mailbox. New message counts the
+	/// server sends along with the reply, which can indicate that new mail arrived, are kept for
+	/// `take_unsolicited` and update `current_mailbox`.
+	pub fn check(&mut self) -> Result<()> {
+		try!(self.require_state("CHECK", ConnectionState::Selected));
+		self.run_command_and_check_ok("CHECK")
+	}
+
+	/// Close permanently removes all messages that have the \Deleted flag set from the currently
+	/// selected mailbox, and returns to the authenticated state from the selected state.
+	pub fn close(&mut self) -> Result<()> {
+		try!(self.require_state("CLOSE", ConnectionState::Selected));
+		try!(self.run_command_and_check_ok("CLOSE"));
+		self.mailbox = None;
+		self.mailbox_name = None;
+		self.state = ConnectionState::Authenticated;
+		Ok(())
+	}
+
+	/// Unselect returns to the authenticated state from the selected state without expunging any
+	/// messages. Fails with `ImapError::Unsupported` if the server lacks the UNSELECT capability.
+	pub fn unselect(&mut self) -> Result<()> {
+		try!(self.require_state("UNSELECT", ConnectionState::Selected));
+		try!(self.require_capability("UNSELECT"));
+		try!(self.run_command_and_check_ok("UNSELECT"));
+		self.mailbox = None;
+		self.mailbox_name = None;
+		self.state = ConnectionState::Authenticated;
+		Ok(())
+	}
+
+	/// Returns to the authenticated state without expunging any messages, like `unselect`, but
+	/// also on servers without UNSELECT: the mailbox is then opened again read-only with EXAMINE,
+	/// which CLOSE leaves untouched.
+	pub fn deselect(&mut self) -> Result<()> {
+		try!(self.require_state("CLOSE", ConnectionState::Selected));
+		if try!(self.has_capability("UNSELECT")) {
+			return self.unselect();
+		}
+
+		let read_only = self.mailbox.as_ref().map_or(false, |mailbox| mailbox.read_only);
+		if let (false, Some(mailbox_name)) = (read_only, self.mailbox_name.clone()) {
+			try!(self.examine(&mailbox_name));
+		}
+		self.close()
+	}
+
+	/// Copy copies the specified message to the end of the specified destination mailbox.
+	pub fn copy<S: Into<SequenceSet>>(&mut self, sequence_set: S, mailbox_name: &str) -> Result<()> {
+		try!(self.require_state("COPY", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		self.run_command_and_check_ok(&format!("COPY {} {}", sequence_set, quote_mailbox_name(mailbox_name)).to_string())
+	}
+
+	/// Like `copy`, but returns where the copies landed when the server supports UIDPLUS and
+	/// reports them with a COPYUID response code. Returns `None` for servers that don't.
+	pub fn copy_uid_plus<S: Into<SequenceSet>>(&mut self, sequence_set: S, mailbox_name: &str) -> Result<Option<CopyUid>> {
+		try!(self.require_state("COPY", ConnectionState::Selected));
+		let sequence_set = try!(sequence_set.into().to_argument());
+		let lines = try!(self.run_command(&format!("COPY {} {}", sequence_set, quote_mailbox_name(mailbox_name)).to_string()));
+
+		match try!(IMAPStream::parse_response_text(&lines)).code {
+			Some(ResponseCode::CopyUid(uid_validity, source, destination)) => {
+				match (parse_uid_set(&source), parse_uid_set(&destination)) {
+					(Some(source), Some(destination)) if source.len() == destination.len() => Ok(Some(CopyUid {
+						uid_validity: uid_validity,
+						uids: source.into_iter().zip(destination.into_iter()).collect()
+					})),
+					_ => Err(ImapError::Parse(format!("Invalid COPYUID {} {}", source, destination)))
+				}
+			},
+			_ => Ok(None)
+		}
+	}
+
+	/// Appends a message to the end of a mailbox with the given flags and internal date. `flags`
+	/// is a space-separated flag list such as `\Seen \Draft`. Returns the UID the message was
+	/// given when the server supports UIDPLUS and reports it with an APPENDUID response code.
+	pub fn append_with_date(&mut self, mailbox: &str, flags: Option<&str>, date: DateTime<FixedOffset>, message: &[u8]) -> Result<Option<u32>> {
+		try!(self.require_state("APPEND", ConnectionState::Authenticated));
+		let flags = match flags {
+			Some(flags) => format!(" ({})", flags),
+			None => String::new()
+		};
+		let command = self.create_command(format!("APPEND {}{} \"{}\" {{{}}}", quote_mailbox_name(mailbox), flags, date.format("%d-%b-%Y %H:%M:%S %z"), message.len()));
+
+		// Writing the command waits for the server to ask for the message, unless the server
+		// accepts non-synchronizing literals.
+		let ret = match self.write_command(&*command) {
+			Ok(_) => match self.connection.send(message).and_then(|_| self.connection.send(b"\r\n")) {
+				Ok(_) => self.read_response(),
+				Err(e) => Err(ImapError::Io(e))
+			},
+			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		let lines = try!(ret);
+		match try!(IMAPStream::parse_response_text(&lines)).code {
+			Some(ResponseCode::AppendUid(_, uid)) => Ok(Some(uid)),
+			_ => Ok(None)
+		}
+	}
+
+	/// Appends a message of exactly `size` bytes read from `source`, copying it to the server in
+	/// chunks instead of holding it in memory, for very large messages. `flags` is a
+	/// space-separated flag list such as `\Seen \Draft`. If `source` ends before `size` bytes, the
+	/// server is left waiting for the rest of the message, so the connection can't be used again
+	/// until it is reconnected.
+	pub fn append_stream<R: Read>(&mut self, mailbox: &str, flags: Option<&str>, size: u64, source: &mut R) -> Result<()> {
+		try!(self.require_state("APPEND", ConnectionState::Authenticated));
+		let flags = match flags {
+			Some(flags) => format!(" ({})", flags),
+			None => String::new()
+		};
+		let command = self.create_command(format!("APPEND {}{} {{{}}}", quote_mailbox_name(mailbox), flags, size));
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => match self.connection.send_from(source, size).and_then(|_| self.connection.send(b"\r\n")) {
+				Ok(_) => self.read_response(),
+				Err(e) => {
+					self.dead = true;
+					Err(ImapError::Io(e))
+				}
+			},
+			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		match ret {
+			Ok(lines) => IMAPStream::parse_response_ok(&lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	pub fn run_command_and_check_ok(&mut self, command: &str) -> Result<()> {
+		match self.run_command(command) {
+			Ok(lines) => IMAPStream::parse_response_ok(&lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Like `run_command_and_check_ok`, but returns the response code and text of the tagged OK,
+	/// such as `[APPENDUID 38505 3955] APPEND completed`.
+	pub fn run_command_and_get_completion(&mut self, command: &str) -> Result<ResponseText> {
+		match self.run_command(command) {
+			Ok(lines) => IMAPStream::parse_response_text(&lines),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Runs a command like `run_command`, returning the lines of the response as bytes.
+	fn run_command_bytes(&mut self, untagged_command: &str) -> Result<Vec<Vec<u8>>> {
+		let command = self.create_command(untagged_command.to_string());
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => self.read_response_bytes(),
+			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		return ret;
+	}
+
+	pub fn run_command(&mut self, untagged_command: &str) -> Result<Vec<String>> {
+		let command = self.create_command(untagged_command.to_string());
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => self.read_response(),
+			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		return ret;
+	}
+
+	/// Runs a command and returns its complete response as sent by the server, up to and
+	/// including the tagged status line, with literals left in place. For commands and data items
+	/// this crate doesn't model. Like `run_command`, it doesn't check the status of the response.
+	pub fn run_command_raw(&mut self, untagged_command: &str) -> Result<Vec<u8>> {
+		let command = self.create_command(untagged_command.to_string());
+
+		let ret = match self.write_command(&*command) {
+			Ok(_) => self.read_raw_response(),
+			Err(e) => Err(e)
+		};
+
+		self.advance_tag();
+
+		return ret;
+	}
+
+	fn read_raw_response(&mut self) -> Result<Vec<u8>> {
+		let start_str = format!("{}{} ", self.tag_prefix, self.tag);
+		let mut response = Vec::new();
+
+		loop {
+			let line = try!(self.read_response_line());
+			let found_tag_line = line.starts_with(start_str.as_bytes());
+			if found_tag_line {
+				self.record_alert(&String::from_utf8_lossy(&line));
+			} else {
+				self.record_unsolicited(&String::from_utf8_lossy(&line));
+			}
+			response.extend(line);
+			if found_tag_line {
+				return Ok(response);
+			}
+		}
+	}
+
+	/// Sends a command without waiting for its response and returns its tag. Several commands
+	/// can be sent this way before collecting each response with `read_response_for`. Every
+	/// pipelined response has to be collected before running any other command.
+	pub fn send_command(&mut self, untagged_command: &str) -> Result<String> {
+		let tag = format!("{}{}", self.tag_prefix, self.tag);
+		let command = self.create_command(untagged_command.to_string());
+
+		let ret = self.write_command(&*command);
+
+		self.advance_tag();
+
+		match ret {
+			Ok(_) => Ok(tag),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Reads responses until the command with the given tag completes, returning its untagged
+	/// lines followed by its tagged status line. Untagged lines are attributed to the next command
+	/// to complete, and responses to other pipelined commands are kept until they are asked for,
+	/// so completions may arrive in any order.
+	pub fn read_response_for(&mut self, tag: &str) -> Result<Vec<String>> {
+		if let Some(lines) = self.pipelined_responses.remove(tag) {
+			return Ok(lines);
+		}
+
+		loop {
+			let line = String::from_utf8_lossy(&try!(self.read_response_line())).into_owned();
+
+			if line.starts_with("* ") || line.starts_with("+") {
+				self.record_unsolicited(&line);
+				self.pipelined_untagged.push(line);
+				continue;
+			}
+
+			self.record_alert(&line);
+			let line_tag = line.split(' ').next().unwrap_or("").to_string();
+			let mut lines = ::std::mem::replace(&mut self.pipelined_untagged, Vec::new());
+			lines.push(line);
+			if line_tag == tag {
+				return Ok(lines);
+			}
+			self.pipelined_responses.insert(line_tag, lines);
+		}
+	}
+
+	/// Writes a tagged command to the server. Any `{n}` literals in the command are sent only
+	/// after the server has asked for them with a continuation request.
+	fn write_command(&mut self, command: &str) -> Result<()> {
+		if let Some(ref response_text) = self.bye {
+			return Err(ImapError::Bye(response_text.clone()));
+		}
+		if self.dead {
+			return Err(ImapError::Io(io::Error::new(io::ErrorKind::NotConnected, "The connection is no longer usable")));
+		}
+		debug!("C: {}", redact_command(command));
+
+		let literal_regex = match Regex::new(r"\{(\d+)\}\r\n") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let mut remaining = command;
+		loop {
+			let (header_end, length) = match literal_regex.captures(remaining) {
+				Some(cap) => match cap.at(1).unwrap().parse::<usize>() {
+					Ok(length) => (cap.pos(0).unwrap().1, length),
+					Err(_) => break
+				},
+				None => break
+			};
+			let literal_end = cmp::min(header_end + length, remaining.len());
+
+			if self.can_send_non_sync_literal(length) {
+				// Send the literal as {n+} and stream it right away instead of waiting for a "+".
+				let header = format!("{}+}}\r\n", &remaining[..header_end - 3]);
+				match self.connection.send(header.as_bytes()) {
+					Ok(_) => (),
+					Err(_) => return Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to write"))),
+				};
+			} else {
+				match self.connection.send(remaining[..header_end].as_bytes()) {
+					Ok(_) => (),
+					Err(_) => return Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to write"))),
+				};
+				try!(self.read_continuation());
+			}
+			match self.connection.send(remaining[header_end..literal_end].as_bytes()) {
+				Ok(_) => (),
+				Err(_) => return Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to write"))),
+			};
+
+			remaining = &remaining[literal_end..];
+		}
+
+		match self.connection.send(remaining.as_bytes()) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to write"))),
+		}
+	}
+
+	/// Whether a literal of the given length can be sent without waiting for a continuation
+	/// request: always with LITERAL+, and up to 4096 bytes with LITERAL-. Only the cached
+	/// capabilities are checked, since this is decided in the middle of writing a command.
+	fn can_send_non_sync_literal(&self, length: usize) -> bool {
+		match self.capabilities {
+			Some(ref capabilities) => capabilities.iter().any(|capability| {
+				capability.eq_ignore_ascii_case("LITERAL+") || (capability.eq_ignore_ascii_case("LITERAL-") && length <= 4096)
+			}),
+			None => false
+		}
+	}
+
+	/// Waits for the server's `+` continuation request, skipping any untagged data sent before it.
+	/// Returns the text following the `+`.
+	fn read_continuation(&mut self) -> Result<String> {
+		loop {
+			let line = try!(self.connection.read_line());
+			try!(self.check_bye(&line));
+			let line = String::from_utf8_lossy(&line).into_owned();
+			if line.starts_with("+") {
+				return Ok(line[1..].trim().to_string());
+			} else if !line.starts_with("* ") {
+				// The server completed the command instead of asking for more data.
+				return match IMAPStream::parse_response_ok(::std::slice::from_ref(&line)) {
+					Ok(_) => Err(ImapError::Parse(line)),
+					Err(e) => Err(e)
+				};
+			}
+		}
+	}
+
+	fn parse_response_ok(lines: &[String]) -> Result<()> {
+		match IMAPStream::parse_response_text(&lines) {
+			Ok(_) => Ok(()),
+			Err(e) => Err(e)
+		}
+	}
+
+	/// Checks the tagged status line like `parse_response_ok`, returning the text of an OK
+	/// response along with its response code.
+	fn parse_response_text(lines: &[String]) -> Result<ResponseText> {
+		let status_regex = match Regex::new(r"^([a-zA-Z0-9]+) (OK|NO|BAD)(?: \[([^\]]*)\])?(?: (.*?))?\r\n$") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+		let last_line = match lines.last() {
+			Some(line) => line,
+			None => return Err(ImapError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "The server sent no response")))
+		};
+
+		match status_regex.captures(last_line) {
+			Some(cap) => {
+				let response_text = ResponseText {
+					code: cap.at(3).map(ResponseCode::parse),
+					text: cap.at(4).unwrap_or("").to_string()
+				};
+				match cap.at(2).unwrap_or("") {
+					"OK" => Ok(response_text),
+					"NO" => Err(ImapError::No(response_text)),
+					_ => Err(ImapError::Bad(response_text))
+				}
+			},
+			None => Err(ImapError::Parse(last_line.to_string()))
+		}
+	}
+
+	/// Reads the response to the current command. Literals are read as part of the line that
+	/// announced them, so each returned line is a complete response, however many CRLFs it contains.
+	fn read_response(&mut self) -> Result<Vec<String>> {
+		let lines = try!(self.read_response_bytes());
+		Ok(lines.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect())
+	}
+
+	/// Reads the response to the current command like `read_response`, keeping each line's bytes
+	/// as sent so that literals holding binary data survive.
+	fn read_response_bytes(&mut self) -> Result<Vec<Vec<u8>>> {
+		let mut found_tag_line = false;
+		let start_str = format!("{}{} ", self.tag_prefix, self.tag);
+		let mut lines: Vec<Vec<u8>> = Vec::new();
+
+		while !found_tag_line {
+			let line = try!(self.read_response_line());
+
+			if line.starts_with(start_str.as_bytes()) {
+				found_tag_line = true;
+				self.record_alert(&String::from_utf8_lossy(&line));
+			} else {
+				self.record_unsolicited(&String::from_utf8_lossy(&line));
+			}
+
+			lines.push(line);
+		}
+
+		Ok(lines)
+	}
+
+	/// Returns the EXISTS, RECENT and EXPUNGE responses, untagged OK responses with a response code,
+	/// untagged NO responses and alerts received since the last call, in the order the server sent
+	/// them. Only the latest 1024 are kept between calls; the mailbox counts of
+	/// `current_mailbox` stay up to date regardless.
+	pub fn take_unsolicited(&mut self) -> Vec<Unsolicited> {
+		::std::mem::replace(&mut self.unsolicited, Vec::new())
+	}
+
+	/// Buffers the line if it is an untagged EXISTS, RECENT or EXPUNGE response, an untagged OK
+	/// with a response code or an untagged NO.
+	fn record_unsolicited(&mut self, line: &str) {
+		// Called for every untagged line, so it is matched by hand rather than with a regex.
+		if !line.starts_with("* ") || !line.ends_with("\r\n") {
+			return;
+		}
+		if line.starts_with("* OK ") || line.starts_with("* NO ") {
+			let response_text = split_response_text(&line[5..line.len() - 2]);
+			if line.starts_with("* NO ") {
+				self.push_unsolicited(Unsolicited::No(response_text));
+			} else if response_text.code.is_some() {
+				self.push_unsolicited(Unsolicited::Ok(response_text));
+			}
+			return;
+		}
+		let mut parts = line[2..line.len() - 2].splitn(2, ' ');
+		let number = match parts.next().and_then(|number| number.parse::<u32>().ok()) {
+			Some(number) => number,
+			None => return
+		};
+		let unsolicited = match parts.next() {
+			Some("EXISTS") => Unsolicited::Exists(number),
+			Some("RECENT") => Unsolicited::Recent(number),
+			Some("EXPUNGE") => Unsolicited::Expunge(number),
+			_ => return
+		};
+
+		if let Some(ref mut mailbox) = self.mailbox {
+			match unsolicited {
+				Unsolicited::Exists(exists) => mailbox.exists = exists,
+				Unsolicited::Recent(recent) => mailbox.recent = recent,
+				Unsolicited::Expunge(_) => mailbox.exists = mailbox.exists.saturating_sub(1),
+				_ => {}
+			}
+		}
+		self.push_unsolicited(unsolicited);
+	}
+
+	/// Buffers an `[ALERT]` sent on a tagged status line.
+	fn record_alert(&mut self, line: &str) {
+		let text = match line.splitn(3, ' ').nth(2) {
+			Some(text) => text.trim_end_matches("\r\n"),
+			None => return
+		};
+		let response_text = split_response_text(text);
+		if response_text.code == Some(ResponseCode::Alert) {
+			self.push_unsolicited(Unsolicited::Alert(response_text));
+		}
+	}
+
+	fn push_unsolicited(&mut self, unsolicited: Unsolicited) {
+		if self.unsolicited.len() >= MAX_UNSOLICITED {
+			self.unsolicited.remove(0);
+		}
+		self.unsolicited.push(unsolicited);
+	}
+
+	/// Reads a line along with any literals it announces and the text that follows them.
+	fn read_response_line(&mut self) -> Result<Vec<u8>> {
+		let line = try!(self.connection.read_response_line());
+		try!(self.check_bye(&line));
+		Ok(line)
+	}
+
+	/// Fails with `ImapError::Bye` if the line is an untagged BYE the server sent to close the
+	/// connection, after which every command fails without being sent. BYE is expected in response
+	/// to LOGOUT, so it is let through once the session is logged out.
+	fn check_bye(&mut self, line: &[u8]) -> Result<()> {
+		if self.logged_out || !line.starts_with(b"* BYE") {
+			return Ok(());
+		}
+
+		let bye_regex = match Regex::new(r"(?s)^\* BYE(?: \[([^\]]*)\])?(?: (.*?))?\r\n$") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let line = String::from_utf8_lossy(line).into_owned();
+		let response_text = match bye_regex.captures(&line) {
+			Some(cap) => ResponseText {
+				code: cap.at(1).map(ResponseCode::parse),
+				text: cap.at(2).unwrap_or("").to_string()
+			},
+			None => return Ok(())
+		};
+
+		self.logged_out = true;
+		self.bye = Some(response_text.clone());
+		Err(ImapError::Bye(response_text))
+	}
+
+	/// Reads the server's greeting and takes the capabilities and state it announces. A BYE
+	/// greeting fails with `ImapError::Bye`.
+	fn read_greeting(&mut self) -> Result<()> {
+		let greeting = match self.connection.read_greeting() {
+			Ok(greeting) => greeting,
+			Err(ImapError::Bye(text)) => {
+				self.logged_out = true;
+				return Err(ImapError::Bye(text));
+			},
+			Err(e) => return Err(e)
+		};
+
+		if !greeting.capabilities.is_empty() {
+			self.capabilities = Some(greeting.capabilities.clone());
+		}
+		self.state = match greeting.status {
+			GreetingStatus::PreAuth => ConnectionState::Authenticated,
+			GreetingStatus::Ok => ConnectionState::NotAuthenticated
+		};
+		self.greeting = greeting;
+		Ok(())
+	}
+
+	/// Moves on to the next tag, wrapping back to 1 instead of overflowing.
+	fn advance_tag(&mut self) {
+		self.tag = if self.tag == u32::max_value() { 1 } else { self.tag + 1 };
+	}
+
+	fn create_command(&mut self, command: String) -> String {
+		let command = format!("{}{} {}\r\n", self.tag_prefix, self.tag, command);
+		return command;
+	}
+}
+
+impl<'a> Iterator for MessageIter<'a> {
+	type Item = Result<(u32, MimeMessage)>;
+
+	fn next(&mut self) -> Option<Result<(u32, MimeMessage)>> {
+		if self.done {
+			return None;
+		}
+
+		match self.stream.read_next_fetch_literal(&self.start_str, &mut self.current_message) {
+			Ok(Some((message_number, raw_message))) => match MimeMessage::parse(&String::from_utf8_lossy(&raw_message)) {
+				Ok(message) => Some(Ok((message_number, message))),
+				Err(_) => Some(Err(ImapError::Parse(format!("Invalid RFC822 message {}", message_number))))
+			},
+			Ok(None) => {
+				self.done = true;
+				None
+			},
+			Err(e) => {
+				self.done = true;
+				Some(Err(e))
+			}
+		}
+	}
+}
+
+impl<'a> Drop for MessageIter<'a> {
+	/// Reads the rest of the response so the stream is left ready for the next command.
+	fn drop(&mut self) {
+		while let Some(_) = self.next() {}
+	}
+}
+
+impl InterruptHandle {
+	/// Shuts down the reading half of the connection, so a command blocked waiting for the server
+	/// fails promptly with `ImapError::Interrupted`. The connection can't be used afterwards,
+	/// except to `reconnect`.
+	pub fn interrupt(&self) -> Result<()> {
+		self.interrupted.store(true, Ordering::SeqCst);
+		try!(self.stream.shutdown(Shutdown::Read));
+		Ok(())
+	}
+}
+
+impl TrafficCounters {
+	/// Returns the number of bytes the stream has sent, as `IMAPStream::bytes_sent` does.
+	pub fn bytes_sent(&self) -> u64 {
+		self.sent.load(Ordering::Relaxed)
+	}
+
+	/// Returns the number of bytes the stream has received, as `IMAPStream::bytes_received` does.
+	pub fn bytes_received(&self) -> u64 {
+		self.received.load(Ordering::Relaxed)
+	}
+}
+
+/// Shows the state of the session without touching the socket.
+impl fmt::Debug for IMAPStream {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("IMAPStream")
+			.field("addr", &self.addr)
+			.field("tag", &self.tag)
+			.field("tag_prefix", &self.tag_prefix)
+			.field("state", &self.state)
+			.field("tls", &self.connection.is_tls())
+			.field("compressed", &self.connection.is_compressed())
+			.field("mailbox", &self.mailbox)
+			.field("logged_out", &self.logged_out)
+			.finish()
+	}
+}
+
+impl Drop for IMAPStream {
+	/// Sends LOGOUT if `logout` was not already called, so the server does not have to wait for
+	/// the connection to time out. The reply isn't waited for, so dropping a stream whose server
+	/// stopped answering doesn't block. Any error is ignored.
+	fn drop(&mut self) {
+		if !self.logged_out && !self.dead {
+			let _ = self.connection.get_ref().set_write_timeout(Some(Duration::from_secs(DROP_WRITE_TIMEOUT_SECS)));
+			let _ = self.send_command("LOGOUT");
+		}
+	}
+}
+
+/// Splits the text of a status response into its optional `[code]` and the human-readable rest.
+fn split_response_text(text: &str) -> ResponseText {
+	if text.starts_with('[') {
+		if let Some(end) = text.find(']') {
+			return ResponseText { code: Some(ResponseCode::parse(&text[1..end])), text: text[end + 1..].trim_start().to_string() };
+		}
+	}
+	ResponseText { code: None, text: text.to_string() }
+}
+
+/// Parses a number captured from a response line, failing with the line if it is out of range.
+fn parse_number(number: &str, line: &str) -> Result<u32> {
+	match number.parse::<u32>() {
+		Ok(number) => Ok(number),
+		Err(_) => Err(ImapError::Parse(line.to_string()))
+	}
+}
+
+/// Formats an argument as an IMAP `astring`. Plain atoms are sent as-is, anything containing
+/// spaces or specials is quoted, and text that cannot appear in a quoted string (CR, LF or
+/// 8-bit data) is sent as a `{n}` literal.
+pub(crate) fn quote_argument(s: &str) -> String {
+	if s.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0 || b >= 0x80) {
+		return format!("{{{}}}\r\n{}", s.len(), s);
+	}
+
+	let is_atom = !s.is_empty() && !s.bytes().any(|b| b <= b' ' || b == 0x7f || b"(){%*\"\\".contains(&b));
+	if is_atom {
+		return s.to_string();
+	}
+
+	let mut quoted = String::with_capacity(s.len() + 2);
+	quoted.push('"');
+	for c in s.chars() {
+		if c == '"' || c == '\\' {
+			quoted.push('\\');
+		}
+		quoted.push(c);
+	}
+	quoted.push('"');
+	quoted
+}
+
+/// Rewrites the strings in search criteria that contain 8-bit text, whether quoted or bare, as
+/// literals, the only form they can be sent in. Literals already in the criteria are kept.
+fn literalize_search_strings(criteria: &str) -> String {
+	let bytes = criteria.as_bytes();
+	let mut rewritten = String::with_capacity(criteria.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let start = i;
+		match bytes[i] {
+			b' ' | b'(' | b')' => {
+				rewritten.push(bytes[i] as char);
+				i += 1;
+			},
+			b'"' => {
+				let mut content = Vec::new();
+				i += 1;
+				while i < bytes.len() && bytes[i] != b'"' {
+					if bytes[i] == b'\\' && i + 1 < bytes.len() {
+						i += 1;
+					}
+					content.push(bytes[i]);
+					i += 1;
+				}
+				i = cmp::min(i + 1, bytes.len());
+				if content.iter().any(|&b| b >= 0x80) {
+					rewritten.push_str(&quote_argument(&String::from_utf8_lossy(&content)));
+				} else {
+					rewritten.push_str(&criteria[start..i]);
+				}
+			},
+			_ => {
+				if let Some(end) = literal_end(criteria, start) {
+					rewritten.push_str(&criteria[start..end]);
+					i = end;
+					continue;
+				}
+				while i < bytes.len() && !b" ()\"".contains(&bytes[i]) {
+					i += 1;
+				}
+				let atom = &criteria[start..i];
+				if atom.bytes().any(|b| b >= 0x80) {
+					rewritten.push_str(&quote_argument(atom));
+				} else {
+					rewritten.push_str(atom);
+				}
+			}
+		}
+	}
+	rewritten
+}
+
+/// Returns the end of the `{n}` literal, including its data, starting at the offset, if there is
+/// one.
+fn literal_end(text: &str, start: usize) -> Option<usize> {
+	let rest = &text[start..];
+	if !rest.starts_with('{') {
+		return None;
+	}
+	let header_end = match rest.find("}\r\n") {
+		Some(end) => end,
+		None => return None
+	};
+	let length = match rest[1..header_end].trim_end_matches('+').parse::<usize>() {
+		Ok(length) => length,
+		Err(_) => return None
+	};
+	let end = start + header_end + 3 + length;
+	if text.is_char_boundary(end) && end <= text.len() {
+		Some(end)
+	} else {
+		None
+	}
+}
+
+/// Expands a UID set such as `304,319:320` into the individual UIDs, in order. `None` if the set
+/// is malformed or holds more than `MAX_EXPANDED_UIDS` UIDs.
+fn parse_uid_set(set: &str) -> Option<Vec<u32>> {
+	let mut uids = Vec::new();
+	for range in set.split(',') {
+		let mut bounds = range.splitn(2, ':');
+		let start = match bounds.next().and_then(|uid| uid.parse::<u32>().ok()) {
+			Some(start) => start,
+			None => return None
+		};
+		let end = match bounds.next() {
+			Some(end) => match end.parse::<u32>() {
+				Ok(end) => end,
+				Err(_) => return None
+			},
+			None => start
+		};
+		let (low, high) = if start <= end { (start, end) } else { (end, start) };
+		if uids.len() + (high - low) as usize >= MAX_EXPANDED_UIDS {
+			return None;
+		}
+		if start <= end {
+			uids.extend(start..=end);
+		} else {
+			uids.extend((end..=start).rev());
+		}
+	}
+	Some(uids)
+}
+
+/// Formats a command for logging, hiding the password of LOGIN and the initial response of
+/// AUTHENTICATE.
+fn redact_command(command: &str) -> String {
+	let mut parts = command.trim_end().splitn(3, ' ');
+	let tag = parts.next().unwrap_or("");
+	let name = parts.next().unwrap_or("");
+	let arguments = parts.next().unwrap_or("");
+
+	if name.eq_ignore_ascii_case("LOGIN") {
+		let username_end = argument_end(arguments);
+		format!("{} {} {} <redacted>", tag, name, &arguments[..username_end])
+	} else if name.eq_ignore_ascii_case("AUTHENTICATE") && arguments.contains(' ') {
+		let mechanism_end = arguments.find(' ').unwrap();
+		format!("{} {} {} <redacted>", tag, name, &arguments[..mechanism_end])
+	} else {
+		command.trim_end().to_string()
+	}
+}
+
+/// Returns the length of the atom, quoted string or literal at the start of the arguments.
+fn argument_end(arguments: &str) -> usize {
+	let bytes = arguments.as_bytes();
+	match bytes.first() {
+		Some(&b'"') => {
+			let mut index = 1;
+			while index < bytes.len() {
+				match bytes[index] {
+					b'\\' => index += 2,
+					b'"' => return index + 1,
+					_ => index += 1
+				}
+			}
+			bytes.len()
+		},
+		Some(&b'{') => match literal_length(arguments.split("\r\n").next().unwrap_or("").as_bytes()) {
+			Some(length) => {
+				let header_end = arguments.find("\r\n").unwrap() + 2;
+				cmp::min(header_end + length, bytes.len())
+			},
+			None => bytes.len()
+		},
+		_ => arguments.find(' ').unwrap_or(bytes.len())
+	}
+}
+
+/// Turns the NO response to a LOGIN into the error for its response code, if it has one that
+/// explains why the credentials were refused.
+fn login_error(response_text: ResponseText) -> ImapError {
+	match response_text.code {
+		Some(ResponseCode::AuthenticationFailed) => ImapError::AuthFailed(response_text),
+		Some(ResponseCode::AuthorizationFailed) => ImapError::AuthorizationFailed(response_text),
+		Some(ResponseCode::Expired) => ImapError::CredentialsExpired(response_text),
+		_ => ImapError::No(response_text)
+	}
+}
+
+/// Formats a mailbox name for sending, encoding it as modified UTF-7 before quoting.
+fn quote_mailbox_name(name: &str) -> String {
+	quote_argument(&encode_utf7(name))
+}
+
+/// Removes the surrounding double quotes and backslash escapes from a quoted string. Strings
+/// that are not quoted are returned unchanged.
+fn unquote(s: &str) -> String {
+	if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+		return s.to_string();
+	}
+
+	let mut unquoted = String::with_capacity(s.len() - 2);
+	let mut escaped = false;
+	for c in s[1..s.len() - 1].chars() {
+		if c == '\\' && !escaped {
+			escaped = true;
+		} else {
+			unquoted.push(c);
+			escaped = false;
+		}
+	}
+	unquoted
+}
+
+#[cfg(feature = "openssl")]
+#[test]
+fn connect() {
+    let imap = IMAPStream::connect(("this-is-not-an-imap-server", 143), None);
+    assert!(imap.is_err());
+}
+
+#[test]
+fn connect_plain() {
+    let imap = IMAPStream::connect_plain(("this-is-not-an-imap-server", 143));
+    assert!(imap.is_err());
+}
+
+#[test]
+fn read_response_ignores_lines_prefixed_by_tag() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 NOOP", "a12 is not our tag\r\n* a1 untagged\r\na1-not-a-tag-either\r\na1 OK NOOP completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    let lines = imap.run_command("NOOP").unwrap();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3], "a1 OK NOOP completed\r\n");
+    server.finish();
+}
+
+#[test]
+fn read_greeting_waits_for_crlf_pair() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        for byte in b"* OK greeting\rwith a stray CR\nand LF\r\n".iter() {
+            stream.write_all(&[*byte]).unwrap();
+        }
+        stream.write_all(b"* 3 EXISTS\r\n").unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    assert_eq!(imap.connection.read_line().unwrap(), b"* 3 EXISTS\r\n".to_vec());
+    server.join().unwrap();
+}
+
+#[test]
+fn parse_response_ok_distinguishes_statuses() {
+    let ok = vec!["a1 OK LOGIN completed\r\n".to_string()];
+    assert!(IMAPStream::parse_response_ok(&ok).is_ok());
+
+    let no = vec!["a1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n".to_string()];
+    match IMAPStream::parse_response_ok(&no) {
+        Err(ImapError::No(text)) => {
+            assert_eq!(text.code, Some(ResponseCode::AuthenticationFailed));
+            assert_eq!(text.text, "Invalid credentials");
+        },
+        _ => panic!("expected a NO response")
+    }
+
+    let no = vec!["a1 NO [TRYCREATE] No such mailbox\r\n".to_string()];
+    match IMAPStream::parse_response_ok(&no) {
+        Err(ImapError::No(text)) => assert_eq!(text.code, Some(ResponseCode::TryCreate)),
+        _ => panic!("expected a NO response")
+    }
+
+    let bad = vec!["* 1 EXISTS\r\n".to_string(), "a1 BAD Command unknown\r\n".to_string()];
+    match IMAPStream::parse_response_ok(&bad) {
+        Err(ImapError::Bad(text)) => {
+            assert_eq!(text.code, None);
+            assert_eq!(text.text, "Command unknown");
+        },
+        _ => panic!("expected a BAD response")
+    }
+
+    let garbage = vec!["a1 WHAT\r\n".to_string()];
+    match IMAPStream::parse_response_ok(&garbage) {
+        Err(ImapError::Parse(_)) => {},
+        _ => panic!("expected a parse error")
+    }
+}
+
+#[test]
+fn quote_argument_quotes_when_needed() {
+    assert_eq!(quote_argument("INBOX"), "INBOX");
+    assert_eq!(quote_argument("My Folder"), "\"My Folder\"");
+    assert_eq!(quote_argument("Say \"hi\""), "\"Say \\\"hi\\\"\"");
+    assert_eq!(quote_argument("back\\slash"), "\"back\\\\slash\"");
+    assert_eq!(quote_argument(""), "\"\"");
+    assert_eq!(quote_argument("two\r\nlines"), "{10}\r\ntwo\r\nlines");
+}
+
+#[test]
+fn arguments_are_quoted_and_literals_wait_for_continuation() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 CREATE \"My \\\"Quoted\\\" Folder\"", "a1 OK CREATE completed\r\n"),
+        ("a2 LOGIN user {9}", "+ Ready for literal data\r\n"),
+        ("pässword", "a2 OK LOGIN completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    imap.create("My \"Quoted\" Folder").unwrap();
+    imap.login("user", "pässword").unwrap();
+    server.finish();
+}
+
+#[test]
+fn parse_list_decodes_mailbox_names() {
+    let lines = vec![
+        "* LIST (\\HasNoChildren) \"/\" \"Eingang/Gel&APY-scht\"\r\n".to_string(),
+        "* LIST (\\Noselect \\HasChildren) \".\" Archive\r\n".to_string(),
+        "* LIST () NIL {9}\r\nMy Folder\r\n".to_string(),
+        "a1 OK LIST completed\r\n".to_string()
+    ];
+    let mailboxes = IMAPStream::parse_list(lines, "LIST").unwrap();
+    assert_eq!(mailboxes.len(), 3);
+    assert_eq!(mailboxes[0].name, "Eingang/Gelöscht");
+    assert_eq!(mailboxes[0].delimiter, Some('/'));
+    assert_eq!(mailboxes[1].attributes, vec!["\\Noselect".to_string(), "\\HasChildren".to_string()]);
+    assert_eq!(mailboxes[1].name, "Archive");
+    assert_eq!(mailboxes[2].delimiter, None);
+    assert_eq!(mailboxes[2].name, "My Folder");
+}
+
+#[test]
+fn parse_list_skips_extended_data() {
+    let lines = vec![
+        "* LIST (\\HasChildren \\Sent) \"/\" \"Sent Items\" (\"CHILDINFO\" (\"SUBSCRIBED\"))\r\n".to_string(),
+        "* LIST (\\Trash) \"/\" Trash\r\n".to_string(),
+        "a1 OK LIST completed\r\n".to_string()
+    ];
+    let mailboxes = IMAPStream::parse_list(lines, "LIST").unwrap();
+    assert_eq!(mailboxes[0].name, "Sent Items");
+    assert_eq!(mailboxes[0].attributes, vec!["\\HasChildren".to_string(), "\\Sent".to_string()]);
+    assert_eq!(mailboxes[1].name, "Trash");
+}
+
+#[test]
+fn read_timeout_returns_timed_out() {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        // Never answer the command; hold the connection open until the client gives up.
+        done_rx.recv().unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain_with_timeout(addr, Duration::from_secs(5)).unwrap();
+    imap.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    match imap.noop() {
+        Err(ImapError::TimedOut) => {},
+        _ => panic!("expected the read to time out")
+    }
+    done_tx.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn parse_fetch_flags_handles_empty_and_uid() {
+    let lines = vec![
+        "* 1 FETCH (FLAGS (\\Seen \\Answered))\r\n".to_string(),
+        "* 2 FETCH (FLAGS ())\r\n".to_string(),
+        "* 3 FETCH (UID 42 FLAGS (\\Flagged $Important))\r\n".to_string(),
+        "* 4 FETCH (FLAGS (\\Deleted) UID 43)\r\n".to_string(),
+        "* 5 FETCH (FLAGS (\\Seen) X-NOTE \"see FLAGS (\\\\Draft)\")\r\n".to_string(),
+        "* 6 FETCH (UID 44)\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let flags = IMAPStream::parse_fetch_flags(lines).unwrap();
+    assert_eq!(flags.len(), 5);
+    assert_eq!(flags[&5], vec![Flag::Seen]);
+    assert_eq!(flags[&1], vec![Flag::Seen, Flag::Answered]);
+    assert!(flags[&2].is_empty());
+    assert_eq!(flags[&3], vec![Flag::Flagged, Flag::Custom("$Important".to_string())]);
+    assert_eq!(flags[&4], vec![Flag::Deleted]);
+}
+
+#[test]
+fn parse_fetch_envelope_handles_nil_and_literals() {
+    let lines = vec![
+        "* 1 FETCH (ENVELOPE (\"Wed, 17 Jul 1996 02:23:25 -0700\" {12}\r\n".to_string(),
+        "IMAP4 (test) ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) NIL NIL ((NIL NIL \"imap\" \"cac.washington.edu\")) NIL NIL NIL \"<B27397-0100000@cac.washington.edu>\"))\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let envelopes = IMAPStream::parse_fetch_envelope(lines).unwrap();
+    let envelope = &envelopes[&1];
+    assert_eq!(envelope.date, Some("Wed, 17 Jul 1996 02:23:25 -0700".to_string()));
+    assert_eq!(envelope.subject, Some("IMAP4 (test)".to_string()));
+    assert_eq!(envelope.from.len(), 1);
+    assert_eq!(envelope.from[0].name, Some("Terry Gray".to_string()));
+    assert_eq!(envelope.from[0].mailbox, Some("gray".to_string()));
+    assert_eq!(envelope.from[0].host, Some("cac.washington.edu".to_string()));
+    assert!(envelope.sender.is_empty());
+    assert_eq!(envelope.to[0].name, None);
+    assert!(envelope.cc.is_empty());
+    assert_eq!(envelope.in_reply_to, None);
+    assert_eq!(envelope.message_id, Some("<B27397-0100000@cac.washington.edu>".to_string()));
+}
+
+#[test]
+fn fetch_body_section_reads_literals() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        BufReader::new(stream.try_clone().unwrap()).read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 FETCH 1:2 BODY.PEEK[1]<0.10>\r\n");
+        stream.write_all(b"* 1 FETCH (BODY[1]<0> {10}\r\na1 OK \xff\r\nx)\r\n").unwrap();
+        stream.write_all(b"* 2 FETCH (BODY[1]<0> {3}\r\nabc FLAGS (\\Seen))\r\n").unwrap();
+        stream.write_all(b"a1 OK FETCH completed\r\n").unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    let sections = imap.fetch_body_section("1:2", "1", Some((0, 10))).unwrap();
+    assert_eq!(sections[&1], b"a1 OK \xff\r\nx".to_vec());
+    assert_eq!(sections[&2], b"abc".to_vec());
+    server.join().unwrap();
+}
+
+#[test]
+fn read_response_keeps_literals_with_their_line() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 1 BODY[TEXT]", "* 1 FETCH (BODY[TEXT] {14}\r\na1 OK fake\r\n\r\n FLAGS (\\Seen))\r\na1 OK FETCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let lines = imap.fetch("1", "BODY[TEXT]").unwrap();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "* 1 FETCH (BODY[TEXT] {14}\r\na1 OK fake\r\n\r\n FLAGS (\\Seen))\r\n");
+    assert_eq!(lines[1], "a1 OK FETCH completed\r\n");
+    server.finish();
+}
+
+#[test]
+fn fetch_messages_reads_every_message() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 1:3 RFC822", "* 1 FETCH (RFC822 {23}\r\nSubject: one\r\n\r\nfirst\r\n)\r\n* 2 FETCH (RFC822 {24}\r\nSubject: two\r\n\r\nsecond\r\n FLAGS (\\Seen))\r\n* 4 EXISTS\r\n* 3 FETCH (UID 9 RFC822 {23}\r\nSubject: six\r\n\r\nthird\r\n)\r\na1 OK FETCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let messages = imap.fetch_messages("1:3").unwrap();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[&1].body, "first\r\n");
+    assert_eq!(messages[&2].body, "second\r\n");
+    assert_eq!(messages[&3].body, "third\r\n");
+    server.finish();
+}
+
+#[test]
+fn fetch_messages_returns_no_responses() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 99 RFC822", "a1 NO [CLIENTBUG] Invalid sequence set\r\n"),
+        ("a2 NOOP", "a2 OK NOOP completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    match imap.fetch_messages("99") {
+        Err(ImapError::No(text)) => assert_eq!(text.code, Some(ResponseCode::Other("CLIENTBUG".to_string()))),
+        _ => panic!("expected a NO response")
+    }
+    imap.noop().unwrap();
+    server.finish();
+}
+
+#[test]
+fn authenticate_plain_waits_for_continuation() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 CAPABILITY", "* CAPABILITY IMAP4rev1 AUTH=PLAIN LOGINDISABLED\r\na1 OK CAPABILITY completed\r\n"),
+        ("a2 AUTHENTICATE PLAIN", "+ \r\n"),
+        ("AHVzZXIAcGFzcw==", "a2 OK AUTHENTICATE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.authenticate_plain("user", "pass").unwrap();
+    server.finish();
+}
+
+#[test]
+fn authenticate_xoauth2_reports_server_error() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 AUTHENTICATE XOAUTH2", "+ \r\n"),
+        ("dXNlcj1tZUBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB0b2tlbgEB", "+ eyJzdGF0dXMiOiI0MDEifQ==\r\n"),
+        ("", "a1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    match imap.authenticate_xoauth2("me@example.com", "token") {
+        Err(ImapError::AuthFailed(text)) => {
+            assert_eq!(text.code, Some(ResponseCode::AuthenticationFailed));
+            assert_eq!(text.text, "Invalid credentials ({\"status\":\"401\"})");
+        },
+        _ => panic!("expected a NO response")
+    }
+    server.finish();
+}
+
+#[test]
+fn has_capability_caches_until_login() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 CAPABILITY", "* CAPABILITY IMAP4rev1 AUTH=PLAIN\r\na1 OK CAPABILITY completed\r\n"),
+        ("a2 LOGIN user pass", "a2 OK LOGIN completed\r\n"),
+        ("a3 CAPABILITY", "* CAPABILITY IMAP4rev1 IDLE\r\na3 OK CAPABILITY completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert!(imap.has_capability("auth=plain").unwrap());
+    assert!(!imap.has_capability("AUTH").unwrap());
+    assert!(!imap.has_capability("IDLE").unwrap());
+    imap.login("user", "pass").unwrap();
+    assert!(imap.has_capability("IDLE").unwrap());
+    server.finish();
+}
+
+#[test]
+fn drop_logs_out_once() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut commands = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+            let mut command = String::new();
+            // A dropped stream closes without reading the reply, which may reset the connection.
+            while reader.read_line(&mut command).unwrap_or(0) > 0 {
+                let _ = stream.write_all(format!("* BYE logging out\r\n{} OK LOGOUT completed\r\n", &command[..2]).as_bytes());
+                commands.push(command.clone());
+                command.clear();
+            }
+        }
+        commands
+    });
+
+    drop(IMAPStream::connect_plain(addr).unwrap());
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.logout().unwrap();
+    drop(imap);
+    assert_eq!(server.join().unwrap(), vec!["a1 LOGOUT\r\n", "a1 LOGOUT\r\n"]);
+}
+
+#[test]
+fn fetch_messages_iter_leaves_stream_usable() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 1:2 RFC822", "* 1 FETCH (RFC822 {23}\r\nSubject: one\r\n\r\nfirst\r\n)\r\n* 2 FETCH (RFC822 {24}\r\nSubject: two\r\n\r\nsecond\r\n)\r\na1 OK FETCH completed\r\n"),
+        ("a2 NOOP", "a2 OK NOOP completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    {
+        let mut messages = imap.fetch_messages_iter("1:2").unwrap();
+        let (message_number, message) = messages.next().unwrap().unwrap();
+        assert_eq!(message_number, 1);
+        assert_eq!(message.body, "first\r\n");
+    }
+    imap.noop().unwrap();
+    server.finish();
+}
+
+#[test]
+fn parse_ids_keeps_server_order() {
+    let lines = vec![
+        "* SORT 5 3 1\r\n".to_string(),
+        "a1 OK SORT completed\r\n".to_string()
+    ];
+    assert_eq!(IMAPStream::parse_ids(lines, "SORT").unwrap(), vec![5, 3, 1]);
+
+    let empty = vec![
+        "* SORT\r\n".to_string(),
+        "a1 OK SORT completed\r\n".to_string()
+    ];
+    assert_eq!(IMAPStream::parse_ids(empty, "SORT").unwrap(), Vec::<u32>::new());
+}
+
+#[test]
+fn parse_thread_builds_nested_threads() {
+    let lines = vec![
+        "* THREAD (2)(3 6 (4 23)(44 7 96))((11)(12))\r\n".to_string(),
+        "a1 OK THREAD completed\r\n".to_string()
+    ];
+    let leaf = |id| ThreadNode { id: id, children: vec![] };
+    let threads = IMAPStream::parse_thread(lines).unwrap();
+    assert_eq!(threads, vec![
+        leaf(2),
+        ThreadNode { id: 3, children: vec![ThreadNode { id: 6, children: vec![
+            ThreadNode { id: 4, children: vec![leaf(23)] },
+            ThreadNode { id: 44, children: vec![ThreadNode { id: 7, children: vec![leaf(96)] }] }
+        ] }] },
+        ThreadNode { id: 0, children: vec![leaf(11), leaf(12)] }
+    ]);
+}
+
+#[test]
+fn unselect_requires_capability() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 CAPABILITY", "* CAPABILITY IMAP4rev1\r\na1 OK CAPABILITY completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    match imap.unselect() {
+        Err(ImapError::Unsupported(ref capability)) => assert_eq!(capability, "UNSELECT"),
+        _ => panic!("expected an unsupported error")
+    }
+    server.finish();
+}
+
+#[test]
+fn fetch_raw_keeps_8bit_bytes() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        BufReader::new(stream.try_clone().unwrap()).read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 FETCH 1 RFC822\r\n");
+        stream.write_all(b"* 1 FETCH (RFC822 {20}\r\nSubject: x\r\n\r\ncaf\xe9\r\n)\r\n").unwrap();
+        stream.write_all(b"a1 OK FETCH completed\r\n").unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    let messages = imap.fetch_raw("1").unwrap();
+    assert_eq!(messages[&1], b"Subject: x\r\n\r\ncaf\xe9\r\n".to_vec());
+    server.join().unwrap();
+}
+
+#[test]
+fn redact_command_hides_credentials() {
+    assert_eq!(redact_command("a1 LOGIN user secret\r\n"), "a1 LOGIN user <redacted>");
+    assert_eq!(redact_command("a1 LOGIN \"us \\\"er\" \"sec ret\"\r\n"), "a1 LOGIN \"us \\\"er\" <redacted>");
+    assert_eq!(redact_command("a1 LOGIN {5}\r\nusér p\r\n"), "a1 LOGIN {5}\r\nusér <redacted>");
+    assert_eq!(redact_command("a2 AUTHENTICATE PLAIN AGZvbwBiYXI=\r\n"), "a2 AUTHENTICATE PLAIN <redacted>");
+    assert_eq!(redact_command("a3 SELECT INBOX\r\n"), "a3 SELECT INBOX");
+}
+
+#[test]
+fn read_response_collects_unsolicited() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 NOOP", "* 3 EXPUNGE\r\n* 24 EXISTS\r\n* 1 RECENT\r\na1 OK NOOP completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.noop().unwrap();
+    assert_eq!(imap.take_unsolicited(), vec![Unsolicited::Expunge(3), Unsolicited::Exists(24), Unsolicited::Recent(1)]);
+    assert!(imap.take_unsolicited().is_empty());
+    server.finish();
+}
+
+#[test]
+fn reconnect_reads_a_new_greeting_and_resets_tags() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start_sessions(&[
+        ("* OK IMAP4rev1 ready\r\n", &[("a1 NOOP", "a1 OK completed\r\n")]),
+        ("* OK IMAP4rev1 ready\r\n", &[("a1 NOOP", "a1 OK completed\r\n")])
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.noop().unwrap();
+    imap.reconnect().unwrap();
+    imap.noop().unwrap();
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn parse_quota_reads_each_resource() {
+    let lines = vec![
+        "* QUOTAROOT INBOX \"\"\r\n".to_string(),
+        "* QUOTA \"\" (STORAGE 10 512 MESSAGE 3 1000)\r\n".to_string(),
+        "a1 OK GETQUOTAROOT completed\r\n".to_string()
+    ];
+    assert_eq!(IMAPStream::parse_quota(lines).unwrap(), vec![Quota {
+        root: "".to_string(),
+        resources: vec![("STORAGE".to_string(), 10, 512), ("MESSAGE".to_string(), 3, 1000)]
+    }]);
+}
+
+#[test]
+fn copy_uid_plus_maps_source_to_destination_uids() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 COPY 2:4 Archive", "a1 OK [COPYUID 38505 304,319:320 3956:3958] Done\r\n"),
+        ("a2 COPY 1 Archive", "a2 OK Done\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.copy_uid_plus("2:4", "Archive").unwrap(), Some(CopyUid {
+        uid_validity: 38505,
+        uids: vec![(304, 3956), (319, 3957), (320, 3958)]
+    }));
+    assert_eq!(imap.copy_uid_plus("1", "Archive").unwrap(), None);
+    server.finish();
+}
+
+#[test]
+fn parse_fetch_full_reads_items_around_the_literal() {
+    let lines = vec![
+        "* 1 FETCH (FLAGS (\\Seen \\Flagged) INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" RFC822 {23}\r\nSubject: one\r\n\r\nfirst\r\n)\r\n".to_string(),
+        "* 2 FETCH (RFC822 {24}\r\nSubject: two\r\n\r\nsecond\r\n FLAGS ())\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let messages = IMAPStream::parse_fetch_full(lines).unwrap();
+    assert_eq!(messages[&1].flags, vec![Flag::Seen, Flag::Flagged]);
+    assert_eq!(messages[&1].internal_date.unwrap().to_rfc3339(), "1996-07-17T02:44:25-07:00");
+    assert_eq!(messages[&1].body.body, "first\r\n");
+    assert!(messages[&2].flags.is_empty());
+    assert_eq!(messages[&2].internal_date, None);
+    assert_eq!(messages[&2].body.body, "second\r\n");
+}
+
+#[test]
+fn read_response_for_handles_out_of_order_completions() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 SELECT INBOX", ""),
+        ("a2 NOOP", "a2 OK NOOP completed\r\n* 3 EXISTS\r\na1 OK SELECT completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    let select = imap.send_command("SELECT INBOX").unwrap();
+    let noop = imap.send_command("NOOP").unwrap();
+    assert_eq!((&*select, &*noop), ("a1", "a2"));
+    assert_eq!(imap.read_response_for(&select).unwrap(), vec!["* 3 EXISTS\r\n", "a1 OK SELECT completed\r\n"]);
+    assert_eq!(imap.read_response_for(&noop).unwrap(), vec!["a2 OK NOOP completed\r\n"]);
+    server.finish();
+}
+
+#[test]
+fn parse_enabled_lists_enabled_extensions() {
+    let lines = vec![
+        "* ENABLED CONDSTORE\r\n".to_string(),
+        "a1 OK ENABLE completed\r\n".to_string()
+    ];
+    assert_eq!(IMAPStream::parse_enabled(lines).unwrap(), vec!["CONDSTORE".to_string()]);
+
+    let none = vec!["* ENABLED\r\n".to_string(), "a1 OK ENABLE completed\r\n".to_string()];
+    assert!(IMAPStream::parse_enabled(none).unwrap().is_empty());
+}
+
+#[test]
+fn parse_fetch_changed_since_reads_modseq() {
+    let lines = vec![
+        "* 4 FETCH (UID 8 MODSEQ (12121231000) FLAGS (\\Seen))\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let results = IMAPStream::parse_fetch_results(&lines).unwrap();
+    assert_eq!(results[&4].modseq, Some(12121231000));
+    assert_eq!(find_item(&results[&4].items, "UID"), Some(&Token::Atom("8".to_string())));
+}
+
+#[test]
+fn parse_select_reads_response_codes() {
+    let lines = vec![
+        "* 172 EXISTS\r\n".to_string(),
+        "* 1 RECENT\r\n".to_string(),
+        "* OK [UNSEEN 12] Message 12 is first unseen\r\n".to_string(),
+        "* OK [UIDVALIDITY 3857529045] UIDs valid\r\n".to_string(),
+        "* OK [UIDNEXT 4392] Predicted next UID\r\n".to_string(),
+        "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n".to_string(),
+        "* OK [PERMANENTFLAGS (\\Deleted \\Seen \\*)] Limited\r\n".to_string(),
+        "* OK [HIGHESTMODSEQ 715194045007] Highest\r\n".to_string(),
+        "* OK [MAILBOXID (F2212ea87-6097-4256-9d51-71338625)] Ok\r\n".to_string(),
+        "a1 OK [READ-WRITE] SELECT completed\r\n".to_string()
+    ];
+    let mailbox = IMAPStream::parse_select_or_examine(lines, false).unwrap();
+    assert_eq!(mailbox.exists, 172);
+    assert_eq!(mailbox.recent, 1);
+    assert_eq!(mailbox.unseen, Some(12));
+    assert_eq!(mailbox.uid_validity, Some(3857529045));
+    assert_eq!(mailbox.uid_next, Some(4392));
+    assert_eq!(mailbox.permanent_flags, Some("(\\Deleted \\Seen \\*)".to_string()));
+    assert_eq!(mailbox.highest_modseq, Some(715194045007));
+    assert_eq!(mailbox.mailbox_id, Some("F2212ea87-6097-4256-9d51-71338625".to_string()));
+    assert!(!mailbox.read_only);
+
+    let lines = vec![
+        "* 0 EXISTS\r\n".to_string(),
+        "* OK [NOMODSEQ] Sorry, this mailbox format doesn't support modsequences\r\n".to_string(),
+        "a1 OK SELECT completed\r\n".to_string()
+    ];
+    let mailbox = IMAPStream::parse_select_or_examine(lines, true).unwrap();
+    assert_eq!(mailbox.highest_modseq, None);
+    assert!(mailbox.read_only);
+
+    let lines = vec![
+        "* 0 EXISTS\r\n".to_string(),
+        "a1 OK [READ-ONLY] SELECT completed\r\n".to_string()
+    ];
+    assert!(IMAPStream::parse_select_or_examine(lines, false).unwrap().read_only);
+}
+
+#[test]
+fn get_acl_and_my_rights_parse_responses() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 CAPABILITY", "* CAPABILITY IMAP4rev1 ACL\r\na1 OK CAPABILITY completed\r\n"),
+        ("a2 GETACL INBOX", "* ACL INBOX Fred rwipslxetad \"Other user\" lr\r\na2 OK Getacl complete\r\n"),
+        ("a3 MYRIGHTS INBOX", "* MYRIGHTS INBOX rwiptsldaex\r\na3 OK Myrights complete\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.get_acl("INBOX").unwrap(), vec![
+        ("Fred".to_string(), "rwipslxetad".to_string()),
+        ("Other user".to_string(), "lr".to_string())
+    ]);
+    assert_eq!(imap.my_rights("INBOX").unwrap(), "rwiptsldaex");
+    server.finish();
+}
+
+#[test]
+fn tags_use_the_prefix_and_wrap_around() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap().with_tag_prefix("client");
+    server.finish();
+    imap.logged_out = true;
+    imap.tag = u32::max_value();
+    assert_eq!(imap.create_command("NOOP".to_string()), format!("client{} NOOP\r\n", u32::max_value()));
+    imap.advance_tag();
+    assert_eq!(imap.create_command("NOOP".to_string()), "client1 NOOP\r\n");
+}
+
+#[test]
+fn has_pending_data_sees_unsolicited_responses() {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (send_tx, send_rx) = mpsc::channel::<()>();
+    let (sent_tx, sent_rx) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        send_rx.recv().unwrap();
+        stream.write_all(b"* 4 EXISTS\r\n").unwrap();
+        sent_tx.send(()).unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    assert!(!imap.has_pending_data().unwrap());
+    send_tx.send(()).unwrap();
+    sent_rx.recv().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(imap.has_pending_data().unwrap());
+    imap.logged_out = true;
+    server.join().unwrap();
+}
+
+#[test]
+fn fetch_binary_reads_literal8_data() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"* OK [CAPABILITY IMAP4rev1 BINARY] ready\r\n").unwrap();
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 FETCH 1 BINARY.PEEK[2]\r\n");
+        stream.write_all(b"* 1 FETCH (BINARY[2] ~{4}\r\n\x00\xff\r\n)\r\na1 OK FETCH completed\r\n").unwrap();
+        command.clear();
+        reader.read_line(&mut command).unwrap();
+        stream.write_all(b"a2 NO [UNKNOWN-CTE] Can't decode\r\n").unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.fetch_binary("1", "2").unwrap()[&1], vec![0x00, 0xff, b'\r', b'\n']);
+    match imap.fetch_binary("2", "1") {
+        Err(ImapError::No(text)) => assert_eq!(text.code, Some(ResponseCode::UnknownCte)),
+        _ => panic!("expected a NO response")
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn fetch_headers_requests_the_named_fields() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 1 BODY.PEEK[HEADER.FIELDS (SUBJECT X-SPAM)]", "* 1 FETCH (BODY[HEADER.FIELDS (SUBJECT X-SPAM)] {27}\r\nSubject: hi\r\nX-Spam: no\r\n\r\n)\r\na1 OK FETCH completed\r\n"),
+        ("a2 FETCH 1 BODY.PEEK[HEADER]", "a2 OK FETCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.fetch_headers("1", &["SUBJECT", "X-SPAM"]).unwrap()[&1], "Subject: hi\r\nX-Spam: no\r\n\r\n");
+    assert!(imap.fetch_headers("1", &[]).unwrap().is_empty());
+    server.finish();
+}
+
+#[test]
+fn hierarchy_delimiter_is_requested_once() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 LIST \"\" \"\"", "* LIST (\\Noselect) \".\" \"\"\r\na1 OK LIST completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.hierarchy_delimiter().unwrap(), Some('.'));
+    assert_eq!(imap.hierarchy_delimiter().unwrap(), Some('.'));
+    server.finish();
+}
+
+#[test]
+fn greeting_is_parsed() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start_sessions(&[
+        ("* PREAUTH [CAPABILITY IMAP4rev1 IDLE] Welcome back\r\n", &[]),
+        ("* BYE Too many connections\r\n", &[])
+    ]);
+
+    let addr = server.addr();
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    assert_eq!(imap.greeting(), &Greeting {
+        status: GreetingStatus::PreAuth,
+        capabilities: vec!["IMAP4rev1".to_string(), "IDLE".to_string()],
+        text: "Welcome back".to_string()
+    });
+    assert_eq!(imap.state(), ConnectionState::Authenticated);
+    imap.logged_out = true;
+    match IMAPStream::connect_plain(addr) {
+        Err(ImapError::Bye(text)) => assert_eq!(text.text, "Too many connections"),
+        _ => panic!("expected a BYE error")
+    }
+    server.finish();
+}
+
+#[cfg(feature = "gmail")]
+#[test]
+fn parse_gmail_fetch_items() {
+    let lines = vec![
+        "* 1 FETCH (X-GM-LABELS (\\Inbox \"Work stuff\" Caf&AOk-) X-GM-MSGID 1278455344230334865)\r\n".to_string(),
+        "* 2 FETCH (X-GM-LABELS () X-GM-MSGID 1278455344230334866)\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let labels = IMAPStream::parse_fetch_gmail_labels(lines.clone()).unwrap();
+    assert_eq!(labels[&1], vec!["\\Inbox".to_string(), "Work stuff".to_string(), "Café".to_string()]);
+    assert!(labels[&2].is_empty());
+    let ids = IMAPStream::parse_fetch_u64(lines, "X-GM-MSGID").unwrap();
+    assert_eq!(ids[&1], 1278455344230334865);
+}
+
+#[test]
+fn compress_deflates_both_directions() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+    use connection::DeflateStream;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* OK [CAPABILITY IMAP4rev1 COMPRESS=DEFLATE] ready\r\n").unwrap();
+        let mut command = String::new();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 COMPRESS DEFLATE\r\n");
+        stream.write_all(b"a1 OK DEFLATE active\r\n").unwrap();
+
+        let mut reader = BufReader::new(DeflateStream::new(IMAPStreamTypes::Basic(stream.try_clone().unwrap())));
+        let mut writer = DeflateStream::new(IMAPStreamTypes::Basic(stream));
+        command.clear();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a2 NOOP\r\n");
+        writer.write_all(b"* 2 EXISTS\r\na2 OK NOOP completed\r\n").unwrap();
+        writer.flush().unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.compress().unwrap();
+    let lines = imap.run_command("NOOP").unwrap();
+    assert_eq!(lines, vec!["* 2 EXISTS\r\n", "a2 OK NOOP completed\r\n"]);
+    imap.logged_out = true;
+    server.join().unwrap();
+}
+
+#[test]
+fn interrupt_handle_breaks_a_blocked_read() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done_sender, done_receiver) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 NOOP\r\n");
+        // Never answer, keeping the connection open until the client is done.
+        let _ = done_receiver.recv();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    let handle = imap.interrupt_handle().unwrap();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        handle.interrupt().unwrap();
+    });
+
+    match imap.noop() {
+        Err(ImapError::Interrupted) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    imap.logged_out = true;
+    interrupter.join().unwrap();
+    done_sender.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn append_with_date_sends_the_literal_and_returns_the_uid() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 APPEND Drafts (\\Seen \\Draft) \"07-Feb-1994 21:52:25 -0800\" {15}", "+ Ready for literal data\r\n"),
+        ("Subject: hi\r\n\r\n", "a1 OK [APPENDUID 38505 3955] APPEND completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let date = DateTime::parse_from_rfc3339("1994-02-07T21:52:25-08:00").unwrap();
+    assert_eq!(imap.append_with_date("Drafts", Some("\\Seen \\Draft"), date, b"Subject: hi\r\n\r\n").unwrap(), Some(3955));
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn malformed_responses_are_errors_not_panics() {
+    match IMAPStream::parse_response_ok(&[]) {
+        Err(ImapError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let lines = vec![
+        "* 99999999999 EXISTS\r\n".to_string(),
+        "a1 OK SELECT completed\r\n".to_string()
+    ];
+    match IMAPStream::parse_select_or_examine(lines, false) {
+        Err(ImapError::Parse(line)) => assert_eq!(line, "* 99999999999 EXISTS\r\n"),
+        _ => panic!("expected a parse error")
+    }
+}
+
+#[test]
+fn select_qresync_reports_vanished_and_changed_messages() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 SELECT INBOX (QRESYNC (67890007 20050715194045000 41,43:116))", "* 314 EXISTS\r\n* OK [UIDVALIDITY 67890007] Ok\r\n* OK [HIGHESTMODSEQ 20050715194045319] Ok\r\n\
+* VANISHED (EARLIER) 41,43:45\r\n* 49 FETCH (UID 117 FLAGS (\\Seen \\Answered) MODSEQ (90060115194045001))\r\n\
+a1 OK [READ-WRITE] mailbox selected\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let mailbox = imap.select_qresync("INBOX", 67890007, 20050715194045000, Some("41,43:116")).unwrap();
+    assert_eq!(mailbox.exists, 314);
+    assert_eq!(mailbox.highest_modseq, Some(20050715194045319));
+    assert_eq!(mailbox.vanished, vec![41, 43, 44, 45]);
+    assert_eq!(mailbox.changed[&49].modseq, Some(90060115194045001));
+    assert_eq!(find_item(&mailbox.changed[&49].items, "UID"), Some(&Token::Atom("117".to_string())));
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn set_nodelay_configures_the_socket() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[]);
+
+    let addr = server.addr();
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.set_nodelay(true).unwrap();
+    assert!(imap.get_ref().nodelay().unwrap());
+    assert_eq!(imap.get_ref().peer_addr().unwrap(), addr);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn all_uids_merges_and_sorts_search_responses() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 UID SEARCH ALL", "* SEARCH 7 12 40\r\n* SEARCH 3 41\r\na1 OK SEARCH completed\r\n"),
+        ("a2 UID SEARCH ALL", "* SEARCH\r\na2 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.all_uids().unwrap(), vec![3, 7, 12, 40, 41]);
+    assert_eq!(imap.all_uids().unwrap(), Vec::<u32>::new());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn literal_plus_sends_literals_without_waiting() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 LITERAL+] ready\r\n", &[
+        ("a1 APPEND Sent \"07-Feb-1994 21:52:25 -0800\" {15+}", ""),
+        ("Subject: hi\r\n\r\n", "a1 OK APPEND completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let date = DateTime::parse_from_rfc3339("1994-02-07T21:52:25-08:00").unwrap();
+    assert_eq!(imap.append_with_date("Sent", None, date, b"Subject: hi\r\n\r\n").unwrap(), None);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn bye_mid_session_fails_the_command_and_later_ones() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done_sender, done_receiver) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 NOOP\r\n");
+        stream.write_all(b"* BYE [ALERT] Autologout; idle for too long\r\n").unwrap();
+        // Keep the connection open; the client must not wait for a tagged response.
+        let _ = done_receiver.recv();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    for _ in 0..2 {
+        match imap.noop() {
+            Err(ImapError::Bye(text)) => {
+                assert_eq!(text.code, Some(ResponseCode::Alert));
+                assert_eq!(text.text, "Autologout; idle for too long");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+    done_sender.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn read_buffer_keeps_unread_data_when_resized() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 1 RFC822", "* 1 FETCH (RFC822 {10}\r\n0123456789)\r\na1 OK FETCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.set_read_buffer_capacity(4);
+    imap.write_command("a1 FETCH 1 RFC822\r\n").unwrap();
+    assert_eq!(imap.connection.read_line().unwrap(), b"* 1 FETCH (RFC822 {10}\r\n".to_vec());
+    imap.set_read_buffer_capacity(1);
+    assert_eq!(imap.connection.read_literal(10).unwrap(), b"0123456789".to_vec());
+    imap.set_read_buffer_capacity(16);
+    assert_eq!(imap.read_response().unwrap(), vec![")\r\n", "a1 OK FETCH completed\r\n"]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn store_flags_formats_the_store_command() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 STORE 1:3 +FLAGS (\\Seen $Forwarded)", "* 1 FETCH (FLAGS (\\Seen $Forwarded))\r\na1 OK STORE completed\r\n"),
+        ("a2 STORE 2 -FLAGS (\\Deleted)", "a2 OK STORE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    imap.store_flags("1:3", FlagOp::Add, &[Flag::Seen, Flag::Custom("$Forwarded".to_string())]).unwrap();
+    imap.store_flags("2", FlagOp::Remove, &[Flag::Deleted]).unwrap();
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn search_return_uses_esearch_or_falls_back() {
+    use mock_server::MockServer;
+
+    let lines = vec![
+        "* ESEARCH (TAG \"a1\") UID COUNT 3 MIN 7 MAX 12 ALL 7,10:11\r\n".to_string(),
+        "a1 OK SEARCH completed\r\n".to_string()
+    ];
+    assert_eq!(IMAPStream::parse_esearch(lines).unwrap(), EsearchResult { count: Some(3), min: Some(7), max: Some(12), all: vec![7, 10, 11] });
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1] ready\r\n", &[
+        ("a1 SEARCH UNSEEN", "* SEARCH 9 4 6\r\na1 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.search_return("UNSEEN", &["COUNT", "MIN"]).unwrap(), EsearchResult { count: Some(3), min: Some(4), max: None, all: Vec::new() });
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn is_alive_marks_a_failed_connection_dead() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 NOOP", "a1 OK NOOP completed\r\n"),
+        ("a2 NOOP", "")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert!(imap.is_alive());
+    assert!(!imap.is_alive());
+    server.finish();
+    assert!(!imap.is_alive());
+    match imap.noop() {
+        Err(ImapError::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn fetch_messages_ordered_keeps_arrival_order() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 3,1 RFC822", "* 3 FETCH (RFC822 {16}\r\nSubject: three\r\n)\r\n* 1 FETCH (RFC822 {14}\r\nSubject: one\r\n)\r\na1 OK FETCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let messages = imap.fetch_messages_ordered("3,1").unwrap();
+    let numbers: Vec<u32> = messages.iter().map(|message| message.0).collect();
+    assert_eq!(numbers, vec![3, 1]);
+    assert_eq!(messages[0].1.headers.get_value::<String>("Subject".to_string()).unwrap(), "three");
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn login_quotes_passwords_with_spaces_and_quotes() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 LOGIN user \"correct horse\"", "a1 OK LOGIN completed\r\n"),
+        ("a2 LOGIN user \"say \\\"hi\\\"\"", "a2 OK LOGIN completed\r\n"),
+        ("a3 LOGIN user {16}", "+ Ready\r\n"),
+        ("pass", ""),
+        (" LOGOUT x1", "a3 NO Invalid credentials\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.login("user", "correct horse").unwrap();
+    imap.login("user", "say \"hi\"").unwrap();
+    assert!(imap.login("user", "pass\r\n LOGOUT x1").is_err());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn current_mailbox_follows_unsolicited_updates() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 SELECT INBOX", "* 10 EXISTS\r\n* 1 RECENT\r\na1 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a2 NOOP", "* 3 EXPUNGE\r\n* 12 EXISTS\r\n* 2 RECENT\r\n* 5 EXPUNGE\r\na2 OK NOOP completed\r\n"),
+        ("a3 CLOSE", "a3 OK CLOSE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert!(imap.current_mailbox().is_none());
+    imap.select("INBOX").unwrap();
+    assert_eq!(imap.current_mailbox().unwrap().exists, 10);
+    imap.noop().unwrap();
+    assert_eq!(imap.current_mailbox().unwrap().exists, 11);
+    assert_eq!(imap.current_mailbox().unwrap().recent, 2);
+    imap.close().unwrap();
+    assert!(imap.current_mailbox().is_none());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn parse_fetch_results_fills_common_items() {
+    let lines = vec![
+        "* 2 FETCH (UID 48 FLAGS (\\Seen) INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" RFC822.SIZE 4286 BODY[HEADER.FIELDS (SUBJECT)] {13}\r\nSubject: hi\r\n)\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let results = IMAPStream::parse_fetch_results(&lines).unwrap();
+    let result = &results[&2];
+    assert_eq!(result.uid, Some(48));
+    assert_eq!(result.flags, Some(vec![Flag::Seen]));
+    assert_eq!(result.internal_date.unwrap().to_rfc3339(), "1996-07-17T02:44:25-07:00");
+    assert_eq!(result.rfc822_size, Some(4286));
+    assert_eq!(result.envelope, None);
+    assert_eq!(result.body_sections["BODY[HEADER.FIELDS (SUBJECT)]"], b"Subject: hi\r\n".to_vec());
+}
+
+#[test]
+fn login_caches_capabilities_sent_with_the_response() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 LOGINDISABLED STARTTLS] ready\r\n", &[
+        ("a1 LOGIN user pass", "a1 OK [CAPABILITY IMAP4rev1 IDLE MOVE] Logged in\r\n"),
+        ("a2 LOGIN user pass", "* CAPABILITY IMAP4rev1 UIDPLUS\r\na2 OK Logged in\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert!(imap.has_capability("STARTTLS").unwrap());
+    imap.login("user", "pass").unwrap();
+    assert!(imap.has_capability("MOVE").unwrap());
+    assert!(!imap.has_capability("STARTTLS").unwrap());
+    imap.login("user", "pass").unwrap();
+    assert!(imap.has_capability("UIDPLUS").unwrap());
+    assert!(!imap.has_capability("MOVE").unwrap());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn fetch_body_to_streams_the_literal() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let body: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    let expected = body.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 FETCH 4 BODY.PEEK[2]\r\n");
+        stream.write_all(format!("* 4 FETCH (BODY[2] {{{}}}\r\n", body.len()).as_bytes()).unwrap();
+        stream.write_all(&body).unwrap();
+        stream.write_all(b")\r\n* 5 EXISTS\r\na1 OK FETCH completed\r\n").unwrap();
+        command.clear();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a2 NOOP\r\n");
+        stream.write_all(b"a2 OK NOOP completed\r\n").unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    let mut sink = Vec::new();
+    assert_eq!(imap.fetch_body_to("4", "2", &mut sink).unwrap(), 20000);
+    assert!(sink == expected);
+    assert_eq!(imap.take_unsolicited(), vec![Unsolicited::Exists(5)]);
+    imap.noop().unwrap();
+    imap.logged_out = true;
+    server.join().unwrap();
+}
+
+#[test]
+fn create_with_parents_creates_each_level() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 CREATE-SPECIAL-USE] ready\r\n", &[
+        ("a1 LIST \"\" \"\"", "* LIST (\\Noselect) \"/\" \"\"\r\na1 OK LIST completed\r\n"),
+        ("a2 CREATE Projects", "a2 NO [ALREADYEXISTS] Mailbox exists\r\n"),
+        ("a3 CREATE Projects/2024", "a3 OK CREATE completed\r\n"),
+        ("a4 CREATE Projects/2024/Done", "a4 OK CREATE completed\r\n"),
+        ("a5 CREATE Old (USE (\\Archive))", "a5 OK CREATE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    imap.create_with_parents("Projects/2024/Done").unwrap();
+    imap.create_special_use("Old", &["\\Archive"]).unwrap();
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn malformed_sequence_sets_are_not_sent() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 COPY 3:* Archive", "a1 OK COPY completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    match imap.fetch("1,,5", "FLAGS") {
+        Err(ImapError::InvalidArgument(_)) => {},
+        _ => panic!("expected an invalid argument error")
+    }
+    imap.copy(3.., "Archive").unwrap();
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn commands_check_the_connection_state() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 LOGIN user pass", "a1 OK LOGIN completed\r\n"),
+        ("a2 SELECT INBOX", "* 2 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen)\r\na2 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a3 CLOSE", "a3 OK CLOSE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.state(), ConnectionState::NotAuthenticated);
+    match imap.select("INBOX") {
+        Err(ImapError::InvalidState(ref message)) => assert_eq!(message, "Command SELECT requires logging in first"),
+        _ => panic!("expected an invalid state error")
+    }
+    imap.login("user", "pass").unwrap();
+    assert_eq!(imap.state(), ConnectionState::Authenticated);
+    match imap.fetch("1", "FLAGS") {
+        Err(ImapError::InvalidState(ref message)) => assert_eq!(message, "Command FETCH requires a selected mailbox"),
+        _ => panic!("expected an invalid state error")
+    }
+    imap.select("INBOX").unwrap();
+    assert_eq!(imap.state(), ConnectionState::Selected);
+    imap.close().unwrap();
+    assert_eq!(imap.state(), ConnectionState::Authenticated);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn uid_expunge_returns_expunged_sequence_numbers() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start_sessions(&[
+        ("* OK [CAPABILITY IMAP4rev1 UIDPLUS] ready\r\n", &[
+            ("a1 UID EXPUNGE 3000:3002", "* 3 EXPUNGE\r\n* 3 EXPUNGE\r\n* 5 EXPUNGE\r\na1 OK UID EXPUNGE completed\r\n")
+        ]),
+        ("* OK [CAPABILITY IMAP4rev1] ready\r\n", &[])
+    ]);
+
+    let addr = server.addr();
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.uid_expunge(3000..=3002).unwrap(), vec![3, 3, 5]);
+    imap.logged_out = true;
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    match imap.uid_expunge("1") {
+        Err(ImapError::Unsupported(ref capability)) => assert_eq!(capability, "UIDPLUS"),
+        _ => panic!("expected an unsupported error")
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn parse_fetch_sizes_ignores_other_items() {
+    let lines = vec![
+        "* 1 FETCH (RFC822.SIZE 44827)\r\n".to_string(),
+        "* 2 FETCH (UID 8 RFC822.SIZE 1024 FLAGS (\\Seen))\r\n".to_string(),
+        "* 3 FETCH (FLAGS (\\Seen))\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let sizes = IMAPStream::parse_fetch_sizes(lines).unwrap();
+    assert_eq!(sizes.len(), 2);
+    assert_eq!(sizes[&1], 44827);
+    assert_eq!(sizes[&2], 1024);
+
+    let lines = vec![
+        "* 1 FETCH (RFC822.SIZE huge)\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    match IMAPStream::parse_fetch_sizes(lines) {
+        Err(ImapError::Parse(ref message)) => assert_eq!(message, "Invalid RFC822.SIZE for message 1"),
+        _ => panic!("expected a parse error")
+    }
+}
+
+#[test]
+fn literalize_search_strings_sends_8bit_strings_as_literals() {
+    assert_eq!(literalize_search_strings("SUBJECT \"héllo\" UNSEEN"), "SUBJECT {6}\r\nhéllo UNSEEN");
+    assert_eq!(literalize_search_strings("(FROM café TO \"a \\\"b\\\"\")"), "(FROM {5}\r\ncafé TO \"a \\\"b\\\"\")");
+    assert_eq!(literalize_search_strings("BODY {6}\r\nhéllo SEEN"), "BODY {6}\r\nhéllo SEEN");
+    assert_eq!(literalize_search_strings("SUBJECT \"plain\""), "SUBJECT \"plain\"");
+}
+
+#[test]
+fn search_charset_reports_bad_charsets() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 SEARCH CHARSET KOI8-R SUBJECT test", "a1 NO [BADCHARSET (US-ASCII UTF-8)] Unsupported charset\r\n"),
+        ("a2 SEARCH CHARSET UTF-8 SUBJECT {6}", "+ Ready for literal data\r\n"),
+        ("héllo", "* SEARCH 2 7\r\na2 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    match imap.search_charset("KOI8-R", "SUBJECT test") {
+        Err(ImapError::No(ResponseText { code: Some(ResponseCode::BadCharset(ref charsets)), .. })) => assert_eq!(charsets, &vec!["US-ASCII".to_string(), "UTF-8".to_string()]),
+        _ => panic!("expected a BADCHARSET error")
+    }
+    assert_eq!(imap.search_charset("UTF-8", "SUBJECT \"héllo\"").unwrap(), vec![2, 7]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn run_command_raw_keeps_literal_bytes() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let response: &[u8] = b"* 1 FETCH (BODY[] {4}\r\n\xff\r\n\x00)\r\n* 1 FETCH (FLAGS (\\Seen))\r\na1 OK FETCH completed\r\n";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 FETCH 1 BODY[]\r\n");
+        stream.write_all(response).unwrap();
+        stream.write_all(b"* 2 EXISTS\r\n").unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    assert_eq!(imap.run_command_raw("FETCH 1 BODY[]").unwrap(), response.to_vec());
+    imap.logged_out = true;
+    server.join().unwrap();
+}
+
+#[test]
+fn from_stream_reads_the_greeting() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 IDLE] ready\r\n", &[
+        ("a1 NOOP", "a1 OK NOOP completed\r\n")
+    ]);
+
+    let addr = server.addr();
+    let mut imap = IMAPStream::from_plain_stream(TcpStream::connect(addr).unwrap()).unwrap();
+    assert_eq!(imap.greeting().capabilities, vec!["IMAP4rev1".to_string(), "IDLE".to_string()]);
+    imap.noop().unwrap();
+    match imap.reconnect() {
+        Err(ImapError::InvalidState(_)) => {},
+        _ => panic!("expected reconnecting to be refused")
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn empty_mailbox_deletes_and_expunges_everything() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 SELECT Trash", "* 2 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen \\Deleted)\r\na1 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a2 STORE 1:* +FLAGS.SILENT (\\Deleted)", "a2 OK STORE completed\r\n"),
+        ("a3 EXPUNGE", "* 1 EXPUNGE\r\n* 1 EXPUNGE\r\na3 OK EXPUNGE completed\r\n"),
+        ("a4 EXAMINE Trash", "* 1 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen \\Deleted)\r\na4 OK [READ-ONLY] EXAMINE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.select("Trash").unwrap();
+    assert_eq!(imap.empty_mailbox().unwrap(), 2);
+    assert_eq!(imap.current_mailbox().unwrap().exists, 0);
+    imap.examine("Trash").unwrap();
+    match imap.empty_mailbox() {
+        Err(ImapError::InvalidState(_)) => {},
+        _ => panic!("expected an invalid state error")
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn idle_loop_renews_idle_until_the_handler_breaks() {
+    use mock_server::MockServer;
+    use std::ops::ControlFlow;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 IDLE] ready\r\n", &[
+        ("a1 IDLE", "+ idling\r\n"),
+        ("DONE", "* 4 EXISTS\r\na1 OK IDLE terminated\r\n"),
+        ("a2 IDLE", "+ idling\r\n* 2 EXPUNGE\r\n* 5 EXISTS\r\n"),
+        ("DONE", "a2 OK IDLE terminated\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let mut seen = Vec::new();
+    imap.idle_loop_renewing(|unsolicited| {
+        seen.push(unsolicited.clone());
+        match unsolicited {
+            Unsolicited::Expunge(_) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(())
+        }
+    }, Duration::from_millis(200)).unwrap();
+    assert_eq!(seen, vec![Unsolicited::Exists(4), Unsolicited::Expunge(2)]);
+    assert_eq!(imap.take_unsolicited(), vec![Unsolicited::Exists(5)]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn run_command_and_get_completion_returns_the_tagged_text() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 EXAMINE INBOX", "* 3 EXISTS\r\na1 OK [READ-ONLY] EXAMINE completed\r\n"),
+        ("a2 NOOP", "a2 NO [ALERT] Not now\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.run_command_and_get_completion("EXAMINE INBOX").unwrap(), ResponseText {
+        code: Some(ResponseCode::ReadOnly),
+        text: "EXAMINE completed".to_string()
+    });
+    match imap.run_command_and_get_completion("NOOP") {
+        Err(ImapError::No(ref text)) => assert_eq!(text.code, Some(ResponseCode::Alert)),
+        _ => panic!("expected a NO response")
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn unread_and_message_counts_use_status() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 STATUS \"Sent Items\" (UNSEEN)", "* STATUS \"Sent Items\" (UNSEEN 5)\r\na1 OK STATUS completed\r\n"),
+        ("a2 STATUS INBOX (MESSAGES)", "* STATUS INBOX (MESSAGES 231)\r\na2 OK STATUS completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.unread_count("Sent Items").unwrap(), 5);
+    assert_eq!(imap.message_count("INBOX").unwrap(), 231);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn parse_fetch_results_reads_the_full_macro() {
+    let lines = vec![
+        "* 12 FETCH (UID 40 FLAGS (\\Seen) INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" RFC822.SIZE 4286 ENVELOPE (\"Wed, 17 Jul 1996 02:23:25 -0700\" \"IMAP4rev1 WG mtg summary and minutes\" ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) NIL NIL NIL NIL NIL NIL \"<B27397-0100000@cac.washington.edu>\") BODY (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"US-ASCII\") NIL NIL \"7BIT\" 3028 92))\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let results = IMAPStream::parse_fetch_results(&lines).unwrap();
+    let result = &results[&12];
+    assert_eq!(result.uid, Some(40));
+    assert_eq!(result.flags, Some(vec![Flag::Seen]));
+    assert_eq!(result.rfc822_size, Some(4286));
+    assert!(result.internal_date.is_some());
+    assert_eq!(result.envelope.as_ref().unwrap().subject, Some("IMAP4rev1 WG mtg summary and minutes".to_string()));
+    match result.body_structure {
+        Some(BodyStructure::Single(ref part)) => assert_eq!(part.subtype, "PLAIN"),
+        _ => panic!("expected a single part body")
+    }
+    assert!(result.body_sections.is_empty());
+    assert_eq!(FetchMacro::All.to_string(), "ALL");
+}
+
+#[test]
+fn rename_encodes_names_and_explains_inbox_refusals() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH ready\r\n", &[
+        ("a1 RENAME \"Old mail\" Caf&AOk-", "a1 OK RENAME completed\r\n"),
+        ("a2 RENAME inbox \"Archive 2016\"", "a2 NO [CANNOT] Not allowed\r\n"),
+        ("a3 RENAME INBOX Archive", "a3 NO [ALREADYEXISTS] Archive exists\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.rename("Old mail", "Café").unwrap();
+    match imap.rename("inbox", "Archive 2016") {
+        Err(ImapError::No(text)) => {
+            assert_eq!(text.code, Some(ResponseCode::Cannot));
+            assert_eq!(text.text, "The server doesn't allow renaming INBOX: Not allowed");
+        },
+        other => panic!("expected NO, got {:?}", other)
+    }
+    match imap.rename("INBOX", "Archive") {
+        Err(ImapError::No(text)) => assert_eq!(text, ResponseText { code: Some(ResponseCode::AlreadyExists), text: "Archive exists".to_string() }),
+        other => panic!("expected NO, got {:?}", other)
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn login_failures_are_told_apart() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK ready\r\n", &[
+        ("a1 LOGIN user secret", "a1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n"),
+        ("a2 LOGIN user secret", "a2 NO [AUTHORIZATIONFAILED] No such authorization-ID\r\n"),
+        ("a3 LOGIN user secret", "a3 NO [EXPIRED] That password isn't valid any more\r\n"),
+        ("a4 LOGIN user secret", "a4 NO Login disabled\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    match imap.login("user", "secret") {
+        Err(ImapError::AuthFailed(text)) => assert_eq!(text.text, "Invalid credentials"),
+        other => panic!("expected AuthFailed, got {:?}", other)
+    }
+    match imap.login("user", "secret") {
+        Err(ImapError::AuthorizationFailed(_)) => {},
+        other => panic!("expected AuthorizationFailed, got {:?}", other)
+    }
+    match imap.login("user", "secret") {
+        Err(ImapError::CredentialsExpired(_)) => {},
+        other => panic!("expected CredentialsExpired, got {:?}", other)
+    }
+    match imap.login("user", "secret") {
+        Err(ImapError::No(text)) => assert_eq!(text.text, "Login disabled"),
+        other => panic!("expected No, got {:?}", other)
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn search_all_set_feeds_fetch() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 ESEARCH] ready\r\n", &[
+        ("a1 SEARCH RETURN (ALL) UNSEEN", "* ESEARCH (TAG \"a1\") ALL 2,4:7,9,12:*\r\na1 OK SEARCH completed\r\n"),
+        ("a2 FETCH 2,4:7,9,12:* FLAGS", "a2 OK FETCH completed\r\n"),
+        ("a3 SEARCH RETURN (ALL) DELETED", "* ESEARCH (TAG \"a3\")\r\na3 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let set = imap.search_all_set("UNSEEN").unwrap().unwrap();
+    assert_eq!(set, "2,4:7,9,12:*");
+    imap.fetch(set, "FLAGS").unwrap();
+    assert_eq!(imap.search_all_set("DELETED").unwrap(), None);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn uids_since_drops_the_last_seen_message() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 UID SEARCH UID 43:*", "* SEARCH 45 44\r\na1 OK SEARCH completed\r\n"),
+        ("a2 UID SEARCH UID 46:*", "* SEARCH 45\r\na2 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.uids_since(42).unwrap(), vec![44, 45]);
+    assert!(imap.uids_since(45).unwrap().is_empty());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn debug_shows_the_session_without_reading() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[]);
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    let debug = format!("{:?}", imap);
+    assert!(debug.starts_with("IMAPStream { addr: "));
+    assert!(debug.contains("tag: 1, tag_prefix: \"a\", state: Authenticated, tls: false, compressed: false, mailbox: None"));
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn select_condstore_reads_the_highest_modseq() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH [CAPABILITY IMAP4rev1 CONDSTORE] ready\r\n", &[
+        ("a1 SELECT \"Sent Items\" (CONDSTORE)", "* 172 EXISTS\r\n* 1 RECENT\r\n* FLAGS (\\Seen \\Deleted)\r\n* OK [UIDVALIDITY 3857529045] UIDs valid\r\n* OK [HIGHESTMODSEQ 715194045007] Highest\r\na1 OK [READ-WRITE] SELECT completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.select_condstore("Sent Items").unwrap().highest_modseq, Some(715194045007));
+    imap.logged_out = true;
+    server.finish();
+
+    let server = MockServer::start("* PREAUTH [CAPABILITY IMAP4rev1] ready\r\n", &[]);
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    match imap.select_condstore("INBOX") {
+        Err(ImapError::Unsupported(capability)) => assert_eq!(capability, "CONDSTORE"),
+        other => panic!("expected Unsupported, got {:?}", other)
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn store_unchanged_since_reports_modified_messages() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 CONDSTORE] ready\r\n", &[
+        ("a1 STORE 7:12 (UNCHANGEDSINCE 320162338) +FLAGS (\\Seen)", "* 8 FETCH (MODSEQ (320162342) FLAGS (\\Seen))\r\na1 OK [MODIFIED 7,9:10] Conditional STORE failed\r\n"),
+        ("a2 STORE 3 (UNCHANGEDSINCE 320162342) FLAGS ()", "a2 OK STORE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.store_unchanged_since(7..=12, 320162338, FlagOp::Add, &[Flag::Seen]).unwrap(), vec![7, 9, 10]);
+    assert!(imap.store_unchanged_since(3, 320162342, FlagOp::Replace, &[]).unwrap().is_empty());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn store_flags_rejects_keywords_the_mailbox_does_not_allow() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 SELECT INBOX", "* 2 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen \\Deleted $Forwarded)\r\n* OK [PERMANENTFLAGS (\\Seen \\Deleted $Forwarded)] Limited\r\na1 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a2 STORE 1 +FLAGS ($forwarded \\Seen)", "a2 OK STORE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.select("INBOX").unwrap();
+    assert!(!imap.supports_custom_keywords());
+    assert_eq!(imap.current_mailbox().unwrap().permanent_flag_list(), Some(vec![Flag::Seen, Flag::Deleted, Flag::Custom("$Forwarded".to_string())]));
+    match imap.store_flags(1, FlagOp::Add, &[Flag::Custom("$Junk".to_string())]) {
+        Err(ImapError::InvalidState(message)) => assert_eq!(message, "The selected mailbox doesn't allow the keyword $Junk"),
+        other => panic!("expected InvalidState, got {:?}", other)
+    }
+    imap.store_flags(1, FlagOp::Add, &[Flag::Custom("$forwarded".to_string()), Flag::Seen]).unwrap();
+    imap.logged_out = true;
+    server.finish();
+
+    let mut mailbox = imap.current_mailbox().unwrap().clone();
+    mailbox.permanent_flags = Some("(\\Seen \\*)".to_string());
+    assert!(mailbox.allows_new_keywords());
+}
+
+#[test]
+fn append_stream_copies_the_source() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 APPEND Drafts (\\Seen) {11}", "+ Ready for literal data\r\n"),
+        ("hello world", "a1 OK APPEND completed\r\n"),
+        ("a2 APPEND Drafts {20}", "+ Ready for literal data\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.append_stream("Drafts", Some("\\Seen"), 11, &mut &b"hello world"[..]).unwrap();
+    match imap.append_stream("Drafts", None, 20, &mut &b"short"[..]) {
+        Err(ImapError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+        other => panic!("expected UnexpectedEof, got {:?}", other)
+    }
+    assert!(imap.noop().is_err());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn fetch_messages_peek_leaves_messages_unseen() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 FETCH 1:2 BODY.PEEK[]", "* 1 FETCH (BODY[] {23}\r\nSubject: one\r\n\r\nfirst\r\n)\r\n* 2 FETCH (BODY[] {24}\r\nSubject: two\r\n\r\nsecond\r\n)\r\na1 OK FETCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    let messages = imap.fetch_messages_peek(1..=2).unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[&2].headers.get_value::<String>("Subject".to_string()).unwrap(), "two");
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn idle_resilient_reconnects_and_reports_missed_messages() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start_sessions(&[
+        ("* PREAUTH [CAPABILITY IMAP4rev1 IDLE] ready\r\n", &[
+            ("a1 SELECT INBOX", "* 4 EXISTS\r\n* 0 RECENT\r\n* OK [UIDVALIDITY 3857529045] UIDs valid\r\n* OK [UIDNEXT 10] Predicted next UID\r\na1 OK [READ-WRITE] SELECT completed\r\n"),
+            ("a2 IDLE", "+ idling\r\n* 5 EXISTS\r\n"),
+            ("DONE", "a2 OK IDLE terminated\r\n"),
+            ("a3 UID SEARCH UID 10:*", "* SEARCH 10\r\na3 OK SEARCH completed\r\n"),
+            ("a4 IDLE", "+ idling\r\n")
+        ]),
+        ("* OK [CAPABILITY IMAP4rev1 IDLE] ready\r\n", &[
+            ("a1 LOGIN user secret", "a1 OK LOGIN completed\r\n"),
+            ("a2 SELECT INBOX", "* 6 EXISTS\r\n* 0 RECENT\r\n* OK [UIDVALIDITY 3857529045] UIDs valid\r\n* OK [UIDNEXT 12] Predicted next UID\r\na2 OK [READ-WRITE] SELECT completed\r\n"),
+            ("a3 UID SEARCH UID 11:*", "* SEARCH 11\r\na3 OK SEARCH completed\r\n")
+        ])
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    let mut seen = Vec::new();
+    imap.idle_resilient_with("user", "secret", "INBOX", |unsolicited| {
+        let flow = match unsolicited {
+            Unsolicited::Reconnected { .. } => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(())
+        };
+        seen.push(unsolicited);
+        flow
+    }, Duration::from_secs(60), Duration::from_millis(10)).unwrap();
+    assert!(seen.contains(&Unsolicited::Exists(5)));
+    assert_eq!(seen.last(), Some(&Unsolicited::Reconnected { exists: 6, new_uids: vec![11], uid_validity_changed: false }));
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn drop_does_not_wait_for_the_logout_reply() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (logout_tx, logout_rx) = mpsc::channel::<String>();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* PREAUTH IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        BufReader::new(stream.try_clone().unwrap()).read_line(&mut command).unwrap();
+        logout_tx.send(command).unwrap();
+        // Never answer; a drop that waited for the reply would hang here.
+        done_rx.recv().unwrap();
+    });
+
+    drop(IMAPStream::connect_plain(addr).unwrap());
+    assert_eq!(logout_rx.recv().unwrap(), "a1 LOGOUT\r\n");
+    done_tx.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn parse_uid_set_handles_the_largest_uid() {
+    assert_eq!(parse_uid_set("4294967294:4294967295"), Some(vec![4294967294, 4294967295]));
+    assert_eq!(parse_uid_set("4294967295:4294967294"), Some(vec![4294967295, 4294967294]));
+    assert_eq!(parse_uid_set("1:4294967295"), None);
+    assert_eq!(parse_uid_set("1:3,x"), None);
+}
+
+#[test]
+fn check_keeps_piggybacked_counts() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 CHECK", "* 23 EXISTS\r\n* 2 RECENT\r\na1 OK CHECK completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    imap.check().unwrap();
+    assert_eq!(imap.take_unsolicited(), vec![Unsolicited::Exists(23), Unsolicited::Recent(2)]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn expunge_returns_the_expunged_messages() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 EXPUNGE", "* 3 EXPUNGE\r\n* 3 EXPUNGE\r\n* 5 EXPUNGE\r\na1 OK EXPUNGE completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.expunge().unwrap(), vec![3, 3, 5]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn enable_clears_the_cached_capabilities() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 CAPABILITY", "* CAPABILITY IMAP4rev1 ENABLE\r\na1 OK CAPABILITY completed\r\n"),
+        ("a2 ENABLE CONDSTORE", "* ENABLED CONDSTORE\r\na2 OK ENABLE completed\r\n"),
+        ("a3 CAPABILITY", "* CAPABILITY IMAP4rev1 ENABLE CONDSTORE\r\na3 OK CAPABILITY completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.enable(&["CONDSTORE"]).unwrap(), vec!["CONDSTORE".to_string()]);
+    assert!(imap.has_capability("CONDSTORE").unwrap());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn parse_fetch_results_keeps_binary_body_sections() {
+    let lines = vec![
+        b"* 1 FETCH (UID 4 BODY[] {4}\r\n\xff\x00\r\n)\r\n".to_vec(),
+        b"a1 OK FETCH completed\r\n".to_vec()
+    ];
+    let results = IMAPStream::parse_fetch_results(&lines).unwrap();
+    assert_eq!(results[&1].uid, Some(4));
+    assert_eq!(results[&1].body_sections["BODY[]"], vec![0xff, 0x00, b'\r', b'\n']);
+}
+
+#[test]
+fn record_unsolicited_keeps_the_latest_responses() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[]);
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.record_unsolicited("* 1 EXISTS extra\r\n");
+    imap.record_unsolicited("* x EXISTS\r\n");
+    assert!(imap.take_unsolicited().is_empty());
+    for exists in 0..MAX_UNSOLICITED as u32 + 2 {
+        imap.record_unsolicited(&format!("* {} EXISTS\r\n", exists));
+    }
+    let unsolicited = imap.take_unsolicited();
+    assert_eq!(unsolicited.len(), MAX_UNSOLICITED);
+    assert_eq!(unsolicited[0], Unsolicited::Exists(2));
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn take_unsolicited_surfaces_alerts_and_warnings() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 NOOP", "* OK Still here\r\n* OK [ALERT] Maintenance at noon\r\n* NO Disk almost full\r\na1 OK [ALERT] Password expires soon\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.noop().unwrap();
+    assert_eq!(imap.take_unsolicited(), vec![
+        Unsolicited::Ok(ResponseText { code: Some(ResponseCode::Alert), text: "Maintenance at noon".to_string() }),
+        Unsolicited::No(ResponseText { code: None, text: "Disk almost full".to_string() }),
+        Unsolicited::Alert(ResponseText { code: Some(ResponseCode::Alert), text: "Password expires soon".to_string() })
+    ]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn traffic_counters_follow_the_stream_from_another_thread() {
+    use mock_server::MockServer;
+    use std::thread;
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 NOOP", "a1 OK NOOP completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    let counters = imap.traffic_counters();
+    imap.noop().unwrap();
+    let (sent, received) = thread::spawn(move || (counters.bytes_sent(), counters.bytes_received())).join().unwrap();
+    assert_eq!(sent, 9);
+    assert_eq!(received, 49);
+    assert_eq!((imap.bytes_sent(), imap.bytes_received()), (sent, received));
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[cfg(feature = "rustls-tls")]
+#[test]
+fn starttls_rustls_reports_a_refused_upgrade() {
+    use mock_server::MockServer;
+    use rustls::RootCertStore;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 STARTTLS", "a1 NO [PRIVACYREQUIRED] Not now\r\n")
+    ]);
+
+    let config = Arc::new(ClientConfig::builder().with_safe_defaults().with_root_certificates(RootCertStore::empty()).with_no_client_auth());
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    match imap.starttls_rustls("imap.example.com", config) {
+        Err(ImapError::No(ref text)) => assert_eq!(text.text, "Not now"),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    assert!(!imap.connection.is_tls());
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn authenticate_plain_with_sasl_ir_reports_refused_credentials() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1 SASL-IR AUTH=PLAIN] ready\r\n", &[
+        ("a1 AUTHENTICATE PLAIN AHVzZXIAcGFzcw==", "a1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    match imap.authenticate_plain("user", "pass") {
+        Err(ImapError::AuthFailed(text)) => assert_eq!(text.text, "Invalid credentials"),
+        other => panic!("expected AuthFailed, got {:?}", other)
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn idle_loop_after_select_reports_only_pushed_responses() {
+    use mock_server::MockServer;
+    use std::ops::ControlFlow;
+
+    let server = MockServer::start("* PREAUTH [CAPABILITY IMAP4rev1 IDLE] ready\r\n", &[
+        ("a1 SELECT INBOX", "* 3 EXISTS\r\n* 1 RECENT\r\n* OK [UIDVALIDITY 42] UIDs valid\r\n* OK [ALERT] Quota nearly full\r\na1 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a2 IDLE", "+ idling\r\n* 4 EXISTS\r\n"),
+        ("DONE", "a2 OK IDLE terminated\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.select("INBOX").unwrap().exists, 3);
+    let mut seen = Vec::new();
+    imap.idle_loop(|unsolicited| {
+        seen.push(unsolicited.clone());
+        match unsolicited {
+            Unsolicited::Exists(_) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(())
+        }
+    }).unwrap();
+    assert_eq!(seen, vec![
+        Unsolicited::Ok(ResponseText { code: Some(ResponseCode::Alert), text: "Quota nearly full".to_string() }),
+        Unsolicited::Exists(4)
+    ]);
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn search_typed_sends_8bit_text_as_utf8() {
+    use mock_server::MockServer;
+    use search::SearchCriteria;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 SEARCH UNSEEN", "* SEARCH 4\r\na1 OK SEARCH completed\r\n"),
+        ("a2 SEARCH CHARSET UTF-8 SUBJECT {6}", "+ Ready for literal data\r\n"),
+        ("héllo", "* SEARCH 2 7\r\na2 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.search_typed(&SearchCriteria::Unseen).unwrap(), vec![4]);
+    assert_eq!(imap.search_typed(&SearchCriteria::Subject("héllo".to_string())).unwrap(), vec![2, 7]);
+    match imap.search_typed(&SearchCriteria::Uid(SequenceSet::from("1 OR ALL"))) {
+        Err(ImapError::InvalidArgument(_)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    imap.logged_out = true;
+    server.finish();
+}
+
+#[test]
+fn fetch_body_to_gives_up_the_connection_after_a_timeout_inside_a_literal() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command, "a1 FETCH 1 BODY.PEEK[1]\r\n");
+        // Send only part of the literal and hold the connection open until the client gives up.
+        stream.write_all(b"* 1 FETCH (BODY[1] {10}\r\nabc").unwrap();
+        done_rx.recv().unwrap();
+    });
+
+    let mut imap = IMAPStream::connect_plain(addr).unwrap();
+    imap.state = ConnectionState::Selected;
+    imap.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let mut sink = Vec::new();
+    match imap.fetch_body_to("1", "1", &mut sink) {
+        Err(ImapError::TimedOut) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    match imap.noop() {
+        Err(ImapError::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    done_tx.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn search_all_set_without_esearch_returns_none_for_no_matches() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1] ready\r\n", &[
+        ("a1 SEARCH UNSEEN", "* SEARCH 9 2 3\r\na1 OK SEARCH completed\r\n"),
+        ("a2 SEARCH DELETED", "* SEARCH\r\na2 OK SEARCH completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    imap.state = ConnectionState::Selected;
+    assert_eq!(imap.search_all_set("UNSEEN").unwrap(), Some("2:3,9".to_string()));
+    assert_eq!(imap.search_all_set("DELETED").unwrap(), None);
+    imap.logged_out = true;
+    server.finish();
 }
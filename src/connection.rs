@@ -0,0 +1,623 @@
+//! The transport underneath an `IMAPStream`: the plain, TLS and compressed stream types, and the
+//! framing of what the server sends into CRLF-terminated lines and literals. The protocol itself,
+//! from tags to command state, is built on top of it in `client`.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::net::TcpStream;
+#[cfg(feature = "openssl")]
+use openssl::ssl::{SslContext, SslStream};
+#[cfg(feature = "rustls-tls")]
+use rustls::{ClientConnection, StreamOwned};
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use regex::Regex;
+
+use client::{Greeting, GreetingStatus};
+use error::{ImapError, ResponseCode, ResponseText, Result};
+use parser::literal_length;
+
+/// The default capacity of the buffer responses are read through.
+const DEFAULT_READ_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// A connection to the server, reading what it sends through a buffer and splitting it into
+/// lines and literals.
+pub struct Connection {
+	stream: IMAPStreamTypes,
+	interrupted: Arc<AtomicBool>,
+	read_buffer: Box<[u8]>,
+	read_pos: usize,
+	read_end: usize,
+	/// What has been read of a line that isn't complete yet, kept when a read times out.
+	partial: Vec<u8>,
+	/// Where the current line starts in `partial`, after any literals before it.
+	line_start: usize,
+	/// The bytes of a literal in `partial` still to be read.
+	literal_remaining: usize,
+	/// Shared with `TrafficCounters` handles, and kept across `replace_stream`.
+	bytes_sent: Arc<AtomicU64>,
+	bytes_received: Arc<AtomicU64>
+}
+
+impl Connection {
+	pub fn new(stream: IMAPStreamTypes) -> Connection {
+		Connection {
+			stream: stream,
+			interrupted: Arc::new(AtomicBool::new(false)),
+			read_buffer: vec![0; DEFAULT_READ_BUFFER_CAPACITY].into_boxed_slice(),
+			read_pos: 0,
+			read_end: 0,
+			partial: Vec::new(),
+			line_start: 0,
+			literal_remaining: 0,
+			bytes_sent: Arc::new(AtomicU64::new(0)),
+			bytes_received: Arc::new(AtomicU64::new(0))
+		}
+	}
+
+	/// Switches to a new stream, such as a fresh connection to the same server, dropping any data
+	/// buffered from the old one. The read buffer keeps its capacity.
+	pub fn replace_stream(&mut self, stream: IMAPStreamTypes) {
+		self.stream = stream;
+		self.interrupted = Arc::new(AtomicBool::new(false));
+		self.read_pos = 0;
+		self.read_end = 0;
+		self.partial.clear();
+		self.line_start = 0;
+		self.literal_remaining = 0;
+	}
+
+	/// Returns the TCP socket underneath any TLS or compression layer.
+	pub fn get_ref(&self) -> &TcpStream {
+		self.stream.get_ref()
+	}
+
+	/// Returns the flag an `InterruptHandle` sets before shutting down the socket, which turns the
+	/// resulting read errors into `ImapError::Interrupted`.
+	pub fn interrupted_flag(&self) -> Arc<AtomicBool> {
+		self.interrupted.clone()
+	}
+
+	/// Returns the counters of bytes sent and received, which other threads can read while this
+	/// connection is in use.
+	pub fn byte_counters(&self) -> (Arc<AtomicU64>, Arc<AtomicU64>) {
+		(self.bytes_sent.clone(), self.bytes_received.clone())
+	}
+
+	pub fn is_tls(&self) -> bool {
+		self.stream.is_tls()
+	}
+
+	pub fn is_compressed(&self) -> bool {
+		match self.stream {
+			IMAPStreamTypes::Deflate(_) => true,
+			_ => false
+		}
+	}
+
+	/// Performs the TLS handshake over the current socket, once the server has accepted STARTTLS.
+	#[cfg(feature = "openssl")]
+	pub fn upgrade_to_tls(&mut self, ssl_context: &SslContext) -> Result<()> {
+		let tcp_stream = try!(self.socket_for_tls());
+		match SslStream::connect(ssl_context, tcp_stream) {
+			Ok(ssl_stream) => {
+				self.stream = IMAPStreamTypes::Ssl(ssl_stream);
+				Ok(())
+			},
+			Err(e) => Err(ImapError::Io(io::Error::new(io::ErrorKind::Other, e)))
+		}
+	}
+
+	/// Like `upgrade_to_tls`, but with rustls. The handshake happens on the first read or write.
+	#[cfg(feature = "rustls-tls")]
+	pub fn upgrade_to_rustls(&mut self, connection: ClientConnection) -> Result<()> {
+		let tcp_stream = try!(self.socket_for_tls());
+		self.stream = IMAPStreamTypes::Rustls(StreamOwned::new(connection, tcp_stream));
+		Ok(())
+	}
+
+	/// Returns a handle to the socket to start TLS on, refusing if the server already sent more.
+	#[cfg(any(feature = "openssl", feature = "rustls-tls"))]
+	fn socket_for_tls(&self) -> Result<TcpStream> {
+		if self.read_pos < self.read_end {
+			// Anything sent before the handshake wasn't protected by TLS and can't be trusted.
+			return Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidData, "The server sent data before the TLS handshake")));
+		}
+		Ok(try!(self.stream.get_ref().try_clone()))
+	}
+
+	/// Compresses all further traffic with DEFLATE, once the server has accepted COMPRESS.
+	pub fn start_compression(&mut self) -> Result<()> {
+		// Swap in a handle to the same socket while the current stream is moved into the new one.
+		let placeholder = IMAPStreamTypes::Basic(try!(self.stream.get_ref().try_clone()));
+		let inner = ::std::mem::replace(&mut self.stream, placeholder);
+		let mut deflate_stream = DeflateStream::new(inner);
+		// Data already buffered after the OK response is compressed.
+		deflate_stream.input.extend_from_slice(&self.read_buffer[self.read_pos..self.read_end]);
+		self.read_pos = self.read_end;
+		self.stream = IMAPStreamTypes::Deflate(Box::new(deflate_stream));
+		Ok(())
+	}
+
+	/// Checks, without blocking, whether the server has sent data that hasn't been read yet, or
+	/// has closed the connection. The TLS and compression layers are asked for data they buffered
+	/// before the socket is peeked at.
+	pub fn has_pending_data(&mut self) -> Result<bool> {
+		if self.read_pos < self.read_end || try!(self.stream.has_buffered_data()) {
+			return Ok(true);
+		}
+
+		let socket = self.stream.get_ref();
+		try!(socket.set_nonblocking(true));
+		let peeked = socket.peek(&mut [0]);
+		try!(socket.set_nonblocking(false));
+
+		match peeked {
+			Ok(_) => Ok(true),
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+			Err(e) => Err(ImapError::Io(e))
+		}
+	}
+
+	/// Sets the capacity of the read buffer, keeping any data that hasn't been read yet.
+	pub fn set_read_buffer_capacity(&mut self, capacity: usize) {
+		let unread = &self.read_buffer[self.read_pos..self.read_end];
+		let mut read_buffer = vec![0; cmp::max(capacity, unread.len())];
+		read_buffer[..unread.len()].copy_from_slice(unread);
+		self.read_end = unread.len();
+		self.read_pos = 0;
+		self.read_buffer = read_buffer.into_boxed_slice();
+	}
+
+	/// Sends a line followed by CRLF and flushes it.
+	pub fn send_line(&mut self, line: &str) -> io::Result<()> {
+		try!(self.stream.write_all(line.as_bytes()));
+		self.bytes_sent.fetch_add(line.len() as u64, Ordering::Relaxed);
+		self.send(b"\r\n")
+	}
+
+	/// Writes the data and flushes it, so the server sees it before we wait for a reply.
+	pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+		try!(self.stream.write_all(data));
+		self.bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+		self.stream.flush()
+	}
+
+	/// Copies exactly `length` bytes from the source to the server, a chunk at a time, and flushes
+	/// them. Fails with `UnexpectedEof` if the source ends early.
+	pub fn send_from<R: Read>(&mut self, source: &mut R, length: u64) -> io::Result<()> {
+		let copied = try!(io::copy(&mut source.take(length), &mut self.stream));
+		self.bytes_sent.fetch_add(copied, Ordering::Relaxed);
+		if copied < length {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("The source ended after {} of {} bytes", copied, length)));
+		}
+		self.stream.flush()
+	}
+
+	/// The number of bytes sent, counted before any compression or encryption.
+	pub fn bytes_sent(&self) -> u64 {
+		self.bytes_sent.load(Ordering::Relaxed)
+	}
+
+	/// The number of bytes received, counted after any decryption or decompression.
+	pub fn bytes_received(&self) -> u64 {
+		self.bytes_received.load(Ordering::Relaxed)
+	}
+
+	/// Reads and parses the server's greeting. A BYE greeting fails with `ImapError::Bye`.
+	pub fn read_greeting(&mut self) -> Result<Greeting> {
+		let greeting_regex = match Regex::new(r"(?s)^\* (OK|PREAUTH|BYE)(?: \[([^\]]*)\])?(?: (.*?))?\r\n$") {
+    		Ok(re) => re,
+    		Err(err) => panic!("{}", err),
+		};
+
+		let line = String::from_utf8_lossy(&try!(self.read_line())).into_owned();
+		let cap = match greeting_regex.captures(&line) {
+			Some(cap) => cap,
+			None => return Err(ImapError::Parse(line.clone()))
+		};
+
+		let code = cap.at(2).map(ResponseCode::parse);
+		let text = cap.at(3).unwrap_or("").to_string();
+		let status = match cap.at(1).unwrap() {
+			"OK" => GreetingStatus::Ok,
+			"PREAUTH" => GreetingStatus::PreAuth,
+			_ => return Err(ImapError::Bye(ResponseText { code: code, text: text }))
+		};
+
+		let capabilities = match code {
+			Some(ResponseCode::Capability(capabilities)) => capabilities,
+			_ => Vec::new()
+		};
+		Ok(Greeting { status: status, capabilities: capabilities, text: text })
+	}
+
+	/// Reads a single line from the server, up to and including the terminating CRLF pair. If the
+	/// read times out, what was read is kept and the next call carries on with it.
+	pub fn read_line(&mut self) -> Result<Vec<u8>> {
+		try!(self.read_partial_line());
+		self.line_start = 0;
+		Ok(::std::mem::replace(&mut self.partial, Vec::new()))
+	}
+
+	/// Reads a line along with any literals it announces and the text that follows them. Like
+	/// `read_line`, a read that times out can be resumed without losing data, even in the
+	/// middle of a literal.
+	pub fn read_response_line(&mut self) -> Result<Vec<u8>> {
+		loop {
+			try!(self.read_partial_literal());
+			try!(self.read_partial_line());
+			match literal_length(&self.partial[self.line_start..]) {
+				Some(length) => {
+					trace!("S: <{} bytes of literal data>", length);
+					self.literal_remaining = length;
+					self.line_start = self.partial.len() + length;
+				},
+				None => break
+			}
+		}
+		self.line_start = 0;
+		Ok(::std::mem::replace(&mut self.partial, Vec::new()))
+	}
+
+	/// Reads into `partial` until the line starting at `line_start` ends with CRLF.
+	fn read_partial_line(&mut self) -> Result<()> {
+		//Carriage return
+		let cr = 0x0d;
+		//Line Feed
+		let lf = 0x0a;
+
+		while self.partial.len() < self.line_start + 2 || !self.partial.ends_with(&[cr, lf]) {
+			let byte_buffer: &mut [u8] = &mut [0];
+			match self.read(byte_buffer) {
+				Ok(0) => return Err(self.interrupted_or(ImapError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed while reading the response")))),
+				Ok(_) => {},
+				Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => return Err(ImapError::TimedOut),
+				Err(_) => return Err(self.interrupted_or(ImapError::Io(io::Error::new(io::ErrorKind::Other, "Failed to read the response")))),
+			}
+			self.partial.push(byte_buffer[0]);
+		}
+
+		trace!("S: {}", String::from_utf8_lossy(&self.partial[self.line_start..]).trim_end());
+		Ok(())
+	}
+
+	/// Reads the rest of the literal being read into `partial`, if any.
+	fn read_partial_literal(&mut self) -> Result<()> {
+		if self.literal_remaining == 0 {
+			return Ok(());
+		}
+
+		let mut chunk = [0; 8192];
+		while self.literal_remaining > 0 {
+			let wanted = cmp::min(chunk.len(), self.literal_remaining);
+			match self.read(&mut chunk[..wanted]) {
+				Ok(0) => return Err(self.interrupted_or(ImapError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed while reading a literal")))),
+				Ok(n) => {
+					self.partial.extend_from_slice(&chunk[..n]);
+					self.literal_remaining -= n;
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => return Err(ImapError::TimedOut),
+				Err(e) => return Err(self.interrupted_or(ImapError::Io(e)))
+			}
+		}
+		Ok(())
+	}
+
+	/// Reads exactly `length` bytes of literal data from the server.
+	pub fn read_literal(&mut self, length: usize) -> Result<Vec<u8>> {
+		let mut literal = Vec::with_capacity(length);
+		try!(self.copy_literal(length, &mut literal));
+		Ok(literal)
+	}
+
+	/// Reads exactly `length` bytes of literal data from the server into the sink, a chunk at a
+	/// time.
+	pub fn copy_literal<W: Write>(&mut self, length: usize, sink: &mut W) -> Result<()> {
+		let mut chunk = [0; 8192];
+		let mut read = 0;
+		while read < length {
+			let wanted = cmp::min(chunk.len(), length - read);
+			match self.read(&mut chunk[..wanted]) {
+				Ok(0) => return Err(self.interrupted_or(ImapError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed while reading a literal")))),
+				Ok(n) => {
+					try!(sink.write_all(&chunk[..n]));
+					read += n;
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => return Err(ImapError::TimedOut),
+				Err(e) => return Err(self.interrupted_or(ImapError::Io(e)))
+			}
+		}
+		trace!("S: <{} bytes of literal data>", length);
+		Ok(())
+	}
+
+	/// Reads through the read buffer. Reads at least as large as the buffer skip it.
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.read_pos == self.read_end {
+			if buf.len() >= self.read_buffer.len() {
+				let count = try!(self.stream.read(buf));
+				self.bytes_received.fetch_add(count as u64, Ordering::Relaxed);
+				return Ok(count);
+			}
+			self.read_end = try!(self.stream.read(&mut self.read_buffer));
+			self.read_pos = 0;
+			self.bytes_received.fetch_add(self.read_end as u64, Ordering::Relaxed);
+		}
+
+		let count = cmp::min(buf.len(), self.read_end - self.read_pos);
+		buf[..count].copy_from_slice(&self.read_buffer[self.read_pos..self.read_pos + count]);
+		self.read_pos += count;
+		Ok(count)
+	}
+
+	/// Replaces a read error with `ImapError::Interrupted` if the read failed because the
+	/// connection was interrupted through an `InterruptHandle`.
+	fn interrupted_or(&self, error: ImapError) -> ImapError {
+		if self.interrupted.load(Ordering::SeqCst) {
+			ImapError::Interrupted
+		} else {
+			error
+		}
+	}
+}
+
+/// The stream underneath a connection, either plain or secured with TLS, and optionally
+/// compressed.
+pub enum IMAPStreamTypes {
+	Basic(TcpStream),
+	#[cfg(feature = "openssl")]
+	Ssl(SslStream<TcpStream>),
+	#[cfg(feature = "rustls-tls")]
+	Rustls(StreamOwned<ClientConnection, TcpStream>),
+	Deflate(Box<DeflateStream>)
+}
+
+impl IMAPStreamTypes {
+	/// Returns the TCP socket underneath any TLS layer.
+	pub fn get_ref(&self) -> &TcpStream {
+		match *self {
+			IMAPStreamTypes::Basic(ref stream) => stream,
+			#[cfg(feature = "openssl")]
+			IMAPStreamTypes::Ssl(ref stream) => stream.get_ref(),
+			#[cfg(feature = "rustls-tls")]
+			IMAPStreamTypes::Rustls(ref stream) => &stream.sock,
+			IMAPStreamTypes::Deflate(ref stream) => stream.inner.get_ref(),
+		}
+	}
+
+	/// Whether the stream is secured with TLS, underneath any compression.
+	pub fn is_tls(&self) -> bool {
+		match *self {
+			IMAPStreamTypes::Basic(_) => false,
+			#[cfg(feature = "openssl")]
+			IMAPStreamTypes::Ssl(_) => true,
+			#[cfg(feature = "rustls-tls")]
+			IMAPStreamTypes::Rustls(_) => true,
+			IMAPStreamTypes::Deflate(ref stream) => stream.inner.is_tls(),
+		}
+	}
+
+	/// Whether a layer above the socket holds data that was read from it but not returned yet:
+	/// decrypted TLS records, or compressed bytes waiting to be inflated.
+	fn has_buffered_data(&mut self) -> Result<bool> {
+		match *self {
+			IMAPStreamTypes::Basic(_) => Ok(false),
+			#[cfg(feature = "openssl")]
+			IMAPStreamTypes::Ssl(ref stream) => Ok(stream.ssl().pending() > 0),
+			#[cfg(feature = "rustls-tls")]
+			IMAPStreamTypes::Rustls(ref mut stream) => match stream.conn.process_new_packets() {
+				Ok(state) => Ok(state.plaintext_bytes_to_read() > 0),
+				Err(e) => Err(ImapError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+			},
+			IMAPStreamTypes::Deflate(ref mut stream) => {
+				if stream.input_start < stream.input.len() {
+					return Ok(true);
+				}
+				stream.inner.has_buffered_data()
+			}
+		}
+	}
+}
+
+impl Read for IMAPStreamTypes {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match *self {
+			IMAPStreamTypes::Basic(ref mut stream) => stream.read(buf),
+			#[cfg(feature = "openssl")]
+			IMAPStreamTypes::Ssl(ref mut stream) => stream.read(buf),
+			#[cfg(feature = "rustls-tls")]
+			IMAPStreamTypes::Rustls(ref mut stream) => stream.read(buf),
+			IMAPStreamTypes::Deflate(ref mut stream) => stream.read(buf),
+		}
+	}
+}
+
+impl Write for IMAPStreamTypes {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match *self {
+			IMAPStreamTypes::Basic(ref mut stream) => stream.write(buf),
+			#[cfg(feature = "openssl")]
+			IMAPStreamTypes::Ssl(ref mut stream) => stream.write(buf),
+			#[cfg(feature = "rustls-tls")]
+			IMAPStreamTypes::Rustls(ref mut stream) => stream.write(buf),
+			IMAPStreamTypes::Deflate(ref mut stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			IMAPStreamTypes::Basic(ref mut stream) => stream.flush(),
+			#[cfg(feature = "openssl")]
+			IMAPStreamTypes::Ssl(ref mut stream) => stream.flush(),
+			#[cfg(feature = "rustls-tls")]
+			IMAPStreamTypes::Rustls(ref mut stream) => stream.flush(),
+			IMAPStreamTypes::Deflate(ref mut stream) => stream.flush(),
+		}
+	}
+}
+
+/// A stream compressed with raw DEFLATE in both directions, as negotiated by COMPRESS DEFLATE.
+/// Writes are only guaranteed to reach the server once the stream is flushed.
+pub struct DeflateStream {
+	inner: IMAPStreamTypes,
+	compress: Compress,
+	decompress: Decompress,
+	/// Compressed data read from the inner stream that hasn't been decompressed yet.
+	input: Vec<u8>,
+	input_start: usize
+}
+
+impl DeflateStream {
+	pub fn new(inner: IMAPStreamTypes) -> DeflateStream {
+		DeflateStream {
+			inner: inner,
+			compress: Compress::new(Compression::default(), false),
+			decompress: Decompress::new(false),
+			input: Vec::new(),
+			input_start: 0
+		}
+	}
+}
+
+impl Read for DeflateStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		loop {
+			let total_in = self.decompress.total_in();
+			let total_out = self.decompress.total_out();
+			let status = match self.decompress.decompress(&self.input[self.input_start..], buf, FlushDecompress::None) {
+				Ok(status) => status,
+				Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+			};
+			let consumed = (self.decompress.total_in() - total_in) as usize;
+			let produced = (self.decompress.total_out() - total_out) as usize;
+			self.input_start += consumed;
+
+			if produced > 0 || status == Status::StreamEnd {
+				return Ok(produced);
+			}
+			if consumed > 0 && self.input_start < self.input.len() {
+				continue;
+			}
+
+			// The decompressor needs more input.
+			let mut chunk = [0; 4096];
+			let read = try!(self.inner.read(&mut chunk));
+			if read == 0 {
+				return Ok(0);
+			}
+			self.input.drain(..self.input_start);
+			self.input_start = 0;
+			self.input.extend_from_slice(&chunk[..read]);
+		}
+	}
+}
+
+impl Write for DeflateStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let mut written = 0;
+		while written < buf.len() {
+			let mut output = Vec::with_capacity(4096);
+			let total_in = self.compress.total_in();
+			if let Err(e) = self.compress.compress_vec(&buf[written..], &mut output, FlushCompress::None) {
+				return Err(io::Error::new(io::ErrorKind::Other, e));
+			}
+			written += (self.compress.total_in() - total_in) as usize;
+			try!(self.inner.write_all(&output));
+		}
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		loop {
+			let mut output = Vec::with_capacity(4096);
+			if let Err(e) = self.compress.compress_vec(&[], &mut output, FlushCompress::Sync) {
+				return Err(io::Error::new(io::ErrorKind::Other, e));
+			}
+			let done = output.len() < output.capacity();
+			try!(self.inner.write_all(&output));
+			if done {
+				break;
+			}
+		}
+		self.inner.flush()
+	}
+}
+
+#[test]
+fn connection_frames_lines_and_literals() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK [CAPABILITY IMAP4rev1] ready\r\n* 1 FETCH (BODY[] {5}\r\nhello)\r\n", &[
+        ("a1 NOOP", "")
+    ]);
+
+    let mut connection = Connection::new(IMAPStreamTypes::Basic(TcpStream::connect(server.addr()).unwrap()));
+    let greeting = connection.read_greeting().unwrap();
+    assert_eq!(greeting.capabilities, vec!["IMAP4rev1".to_string()]);
+    assert_eq!(greeting.text, "ready");
+    assert_eq!(connection.read_line().unwrap(), b"* 1 FETCH (BODY[] {5}\r\n".to_vec());
+    assert_eq!(connection.read_literal(5).unwrap(), b"hello".to_vec());
+    assert_eq!(connection.read_line().unwrap(), b")\r\n".to_vec());
+    let (sent, received) = connection.byte_counters();
+    connection.send_line("a1 NOOP").unwrap();
+    assert_eq!(connection.bytes_received(), 66);
+    assert_eq!(connection.bytes_sent(), 9);
+    assert_eq!(received.load(Ordering::Relaxed), 66);
+    assert_eq!(sent.load(Ordering::Relaxed), 9);
+    server.finish();
+}
+
+#[test]
+fn read_response_line_resumes_after_a_timeout() {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (resume_tx, resume_rx) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"* 1 FETCH (BODY[] {10}\r\nhello").unwrap();
+        resume_rx.recv().unwrap();
+        stream.write_all(b" worl").unwrap();
+        resume_rx.recv().unwrap();
+        stream.write_all(b"d FLAGS (\\Seen)\r\n").unwrap();
+    });
+
+    let tcp_stream = TcpStream::connect(addr).unwrap();
+    tcp_stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let mut connection = Connection::new(IMAPStreamTypes::Basic(tcp_stream));
+    for _ in 0..2 {
+        match connection.read_response_line() {
+            Err(ImapError::TimedOut) => {},
+            _ => panic!("expected the read to time out")
+        }
+        resume_tx.send(()).unwrap();
+    }
+    connection.get_ref().set_read_timeout(None).unwrap();
+    assert_eq!(connection.read_response_line().unwrap(), b"* 1 FETCH (BODY[] {10}\r\nhello world FLAGS (\\Seen)\r\n".to_vec());
+    server.join().unwrap();
+}
+
+#[test]
+fn has_pending_data_sees_compressed_input() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut connection = Connection::new(IMAPStreamTypes::Basic(TcpStream::connect(listener.local_addr().unwrap()).unwrap()));
+    let (_server, _) = listener.accept().unwrap();
+    connection.start_compression().unwrap();
+    assert!(!connection.has_pending_data().unwrap());
+    if let IMAPStreamTypes::Deflate(ref mut stream) = connection.stream {
+        stream.input.extend_from_slice(b"\x2a");
+    }
+    assert!(connection.has_pending_data().unwrap());
+}
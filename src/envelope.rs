@@ -0,0 +1,106 @@
+use parser::Token;
+use rfc2047::decode_encoded_words;
+
+/// The envelope structure of a message, as returned by `FETCH ENVELOPE`. Fields the server
+/// reports as NIL are `None` or empty. Text fields hold the raw header values; use
+/// `decoded_subject` and `Address::decoded_name` for display.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+	pub date: Option<String>,
+	pub subject: Option<String>,
+	pub from: Vec<Address>,
+	pub sender: Vec<Address>,
+	pub reply_to: Vec<Address>,
+	pub to: Vec<Address>,
+	pub cc: Vec<Address>,
+	pub bcc: Vec<Address>,
+	pub in_reply_to: Option<String>,
+	pub message_id: Option<String>
+}
+
+/// An address from one of the address lists of an `Envelope`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+	pub name: Option<String>,
+	pub mailbox: Option<String>,
+	pub host: Option<String>
+}
+
+impl Envelope {
+	/// Builds an envelope from the parenthesized list following the ENVELOPE data item.
+	pub fn from_token(token: &Token) -> Option<Envelope> {
+		let fields = match token {
+			&Token::List(ref fields) if fields.len() == 10 => fields,
+			_ => return None
+		};
+
+		let mut address_lists = Vec::with_capacity(6);
+		for field in fields[2..8].iter() {
+			match address_list(field) {
+				Some(addresses) => address_lists.push(addresses),
+				None => return None
+			}
+		}
+		let mut address_lists = address_lists.into_iter();
+
+		Some(Envelope {
+			date: string_field(&fields[0]),
+			subject: string_field(&fields[1]),
+			from: address_lists.next().unwrap(),
+			sender: address_lists.next().unwrap(),
+			reply_to: address_lists.next().unwrap(),
+			to: address_lists.next().unwrap(),
+			cc: address_lists.next().unwrap(),
+			bcc: address_lists.next().unwrap(),
+			in_reply_to: string_field(&fields[8]),
+			message_id: string_field(&fields[9])
+		})
+	}
+
+	/// Returns the subject with RFC 2047 encoded-words decoded.
+	pub fn decoded_subject(&self) -> Option<String> {
+		self.subject.as_ref().map(|subject| decode_encoded_words(subject))
+	}
+}
+
+impl Address {
+	/// Returns the display name with RFC 2047 encoded-words decoded.
+	pub fn decoded_name(&self) -> Option<String> {
+		self.name.as_ref().map(|name| decode_encoded_words(name))
+	}
+}
+
+fn string_field(token: &Token) -> Option<String> {
+	token.to_string_lossy().map(|s| s.into_owned())
+}
+
+fn address_list(token: &Token) -> Option<Vec<Address>> {
+	let items = match token.as_list() {
+		Some(items) => items,
+		None => return None
+	};
+
+	let mut addresses = Vec::with_capacity(items.len());
+	for item in items.iter() {
+		match item.as_list() {
+			Some(parts) if parts.len() == 4 => addresses.push(Address {
+				name: string_field(&parts[0]),
+				mailbox: string_field(&parts[2]),
+				host: string_field(&parts[3])
+			}),
+			_ => return None
+		}
+	}
+	Some(addresses)
+}
+
+#[test]
+fn envelope_decodes_encoded_words() {
+    use parser::parse_tokens;
+
+    let tokens = parse_tokens("(\"Mon, 7 Feb 1994 21:52:25 -0800\" \"=?UTF-8?Q?Gr=C3=BC=C3=9Fe?=\" ((\"=?ISO-8859-1?Q?Andr=E9?=\" NIL \"andre\" \"example.com\")) NIL NIL NIL NIL NIL NIL \"<1@example.com>\")\r\n").unwrap();
+    let envelope = Envelope::from_token(&tokens[0]).unwrap();
+    assert_eq!(envelope.subject, Some("=?UTF-8?Q?Gr=C3=BC=C3=9Fe?=".to_string()));
+    assert_eq!(envelope.decoded_subject(), Some("Grüße".to_string()));
+    assert_eq!(envelope.from[0].decoded_name(), Some("André".to_string()));
+}
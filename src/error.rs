@@ -0,0 +1,241 @@
+use std::io::Error as IoError;
+use std::error::Error;
+use std::fmt;
+use std::result;
+
+/// A convenience wrapper around `Result` for `ImapError`.
+pub type Result<T> = result::Result<T, ImapError>;
+
+/// The human-readable part of a status response, along with its optional response code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseText {
+	/// The response code found in square brackets, e.g. `ALERT` or `TRYCREATE`.
+	pub code: Option<ResponseCode>,
+	/// The text following the status and response code.
+	pub text: String
+}
+
+/// A machine-readable response code sent in square brackets at the start of a status response's
+/// text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseCode {
+	/// The text is an alert that must be shown to the user.
+	Alert,
+	/// A message could not be parsed by the server.
+	Parse,
+	/// The target mailbox does not exist; the command may succeed after creating it.
+	TryCreate,
+	/// The mailbox to create already exists.
+	AlreadyExists,
+	/// The operation violates a rule of the server and can never succeed, such as renaming INBOX
+	/// on a server that doesn't allow it.
+	Cannot,
+	/// The selected mailbox is read-only.
+	ReadOnly,
+	/// The selected mailbox is read-write.
+	ReadWrite,
+	/// The unique identifier validity value of the mailbox.
+	UidValidity(u32),
+	/// The flags the client can change permanently.
+	PermanentFlags(Vec<String>),
+	/// The server's capabilities.
+	Capability(Vec<String>),
+	/// The server doesn't support the charset of a SEARCH. Lists the charsets it supports, if it
+	/// sent them.
+	BadCharset(Vec<String>),
+	/// The server can't decode the content transfer encoding of a part fetched with BINARY.
+	UnknownCte,
+	/// The UID validity of the destination mailbox of a COPY, and the UID sets of the source
+	/// messages and of their copies, in the same order.
+	CopyUid(u32, String, String),
+	/// The UID validity of the mailbox a message was appended to, and the UID it was given.
+	AppendUid(u32, u32),
+	/// Authentication failed, e.g. because the credentials were wrong.
+	AuthenticationFailed,
+	/// The credentials were right, but don't allow acting as the requested authorization identity.
+	AuthorizationFailed,
+	/// The credentials were right, but have expired.
+	Expired,
+	/// The messages a conditional STORE left alone because they changed after the given
+	/// mod-sequence.
+	Modified(String),
+	/// Any other code, as sent by the server, e.g. `NOPERM`.
+	Other(String)
+}
+
+impl ResponseCode {
+	/// Parses the text found between the square brackets.
+	pub fn parse(code: &str) -> ResponseCode {
+		let mut parts = code.splitn(2, ' ');
+		let name = parts.next().unwrap_or("").to_uppercase();
+		let argument = parts.next().unwrap_or("").trim();
+
+		match &*name {
+			"ALERT" => ResponseCode::Alert,
+			"PARSE" => ResponseCode::Parse,
+			"TRYCREATE" => ResponseCode::TryCreate,
+			"ALREADYEXISTS" => ResponseCode::AlreadyExists,
+			"CANNOT" => ResponseCode::Cannot,
+			"READ-ONLY" => ResponseCode::ReadOnly,
+			"READ-WRITE" => ResponseCode::ReadWrite,
+			"UNKNOWN-CTE" => ResponseCode::UnknownCte,
+			"AUTHENTICATIONFAILED" => ResponseCode::AuthenticationFailed,
+			"AUTHORIZATIONFAILED" => ResponseCode::AuthorizationFailed,
+			"EXPIRED" => ResponseCode::Expired,
+			"UIDVALIDITY" => match argument.parse::<u32>() {
+				Ok(validity) => ResponseCode::UidValidity(validity),
+				Err(_) => ResponseCode::Other(code.to_string())
+			},
+			"PERMANENTFLAGS" => ResponseCode::PermanentFlags(argument.trim_matches(|c| c == '(' || c == ')').split_whitespace().map(|flag| flag.to_string()).collect()),
+			"BADCHARSET" => ResponseCode::BadCharset(argument.trim_matches(|c| c == '(' || c == ')').split_whitespace().map(|charset| charset.to_string()).collect()),
+			"CAPABILITY" => ResponseCode::Capability(argument.split_whitespace().map(|capability| capability.to_string()).collect()),
+			"COPYUID" => {
+				let arguments: Vec<&str> = argument.split_whitespace().collect();
+				match (arguments.len(), arguments.get(0).and_then(|validity| validity.parse::<u32>().ok())) {
+					(3, Some(validity)) => ResponseCode::CopyUid(validity, arguments[1].to_string(), arguments[2].to_string()),
+					_ => ResponseCode::Other(code.to_string())
+				}
+			},
+			"MODIFIED" if !argument.is_empty() => ResponseCode::Modified(argument.to_string()),
+			"APPENDUID" => {
+				let arguments: Vec<&str> = argument.split_whitespace().collect();
+				match (arguments.len(), arguments.get(0).and_then(|validity| validity.parse::<u32>().ok()), arguments.get(1).and_then(|uid| uid.parse::<u32>().ok())) {
+					(2, Some(validity), Some(uid)) => ResponseCode::AppendUid(validity, uid),
+					_ => ResponseCode::Other(code.to_string())
+				}
+			},
+			_ => ResponseCode::Other(code.to_string())
+		}
+	}
+}
+
+/// A set of errors that can occur in the IMAP client.
+#[derive(Debug)]
+pub enum ImapError {
+	/// An `io::Error` that occurred while talking to the server.
+	Io(IoError),
+	/// The server responded with NO, meaning the command failed.
+	No(ResponseText),
+	/// The server responded with BAD, meaning the command was not understood or was invalid.
+	Bad(ResponseText),
+	/// The server sent a response that could not be parsed.
+	Parse(String),
+	/// The connection or a read from the server timed out.
+	TimedOut,
+	/// The server sent BYE and is closing the connection.
+	Bye(ResponseText),
+	/// The server does not advertise the named capability, which the command needs.
+	Unsupported(String),
+	/// The command was interrupted through an `InterruptHandle`.
+	Interrupted,
+	/// The command can't be sent in the connection's current state, e.g. FETCH before a mailbox
+	/// is selected. It was not sent to the server.
+	InvalidState(String),
+	/// An argument passed by the caller is malformed, e.g. a sequence set such as `1:x`. The
+	/// command was not sent to the server.
+	InvalidArgument(String),
+	/// Logging in failed because the credentials were wrong (`AUTHENTICATIONFAILED`).
+	AuthFailed(ResponseText),
+	/// The credentials were right, but the user isn't allowed to log in as the requested identity
+	/// (`AUTHORIZATIONFAILED`).
+	AuthorizationFailed(ResponseText),
+	/// The credentials were right, but have expired (`EXPIRED`).
+	CredentialsExpired(ResponseText)
+}
+
+impl From<IoError> for ImapError {
+	fn from(err: IoError) -> ImapError {
+		ImapError::Io(err)
+	}
+}
+
+impl fmt::Display for ResponseCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ResponseCode::Alert => write!(f, "ALERT"),
+			ResponseCode::Parse => write!(f, "PARSE"),
+			ResponseCode::TryCreate => write!(f, "TRYCREATE"),
+			ResponseCode::AlreadyExists => write!(f, "ALREADYEXISTS"),
+			ResponseCode::Cannot => write!(f, "CANNOT"),
+			ResponseCode::ReadOnly => write!(f, "READ-ONLY"),
+			ResponseCode::ReadWrite => write!(f, "READ-WRITE"),
+			ResponseCode::UnknownCte => write!(f, "UNKNOWN-CTE"),
+			ResponseCode::AuthenticationFailed => write!(f, "AUTHENTICATIONFAILED"),
+			ResponseCode::AuthorizationFailed => write!(f, "AUTHORIZATIONFAILED"),
+			ResponseCode::Expired => write!(f, "EXPIRED"),
+			ResponseCode::UidValidity(validity) => write!(f, "UIDVALIDITY {}", validity),
+			ResponseCode::PermanentFlags(ref flags) => write!(f, "PERMANENTFLAGS ({})", flags.join(" ")),
+			ResponseCode::Capability(ref capabilities) => write!(f, "CAPABILITY {}", capabilities.join(" ")),
+			ResponseCode::BadCharset(ref charsets) if charsets.is_empty() => write!(f, "BADCHARSET"),
+			ResponseCode::BadCharset(ref charsets) => write!(f, "BADCHARSET ({})", charsets.join(" ")),
+			ResponseCode::CopyUid(validity, ref source, ref destination) => write!(f, "COPYUID {} {} {}", validity, source, destination),
+			ResponseCode::AppendUid(validity, uid) => write!(f, "APPENDUID {} {}", validity, uid),
+			ResponseCode::Modified(ref set) => write!(f, "MODIFIED {}", set),
+			ResponseCode::Other(ref code) => write!(f, "{}", code)
+		}
+	}
+}
+
+impl fmt::Display for ResponseText {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.code {
+			Some(ref code) => write!(f, "[{}] {}", code, self.text),
+			None => write!(f, "{}", self.text)
+		}
+	}
+}
+
+impl fmt::Display for ImapError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ImapError::Io(ref e) => fmt::Display::fmt(e, f),
+			ImapError::No(ref text) => write!(f, "No Response: {}", text),
+			ImapError::Bad(ref text) => write!(f, "Bad Response: {}", text),
+			ImapError::Parse(ref line) => write!(f, "Invalid Response: {}", line),
+			ImapError::TimedOut => write!(f, "Timed out waiting for the server"),
+			ImapError::Bye(ref text) => write!(f, "Server closed the connection: {}", text),
+			ImapError::Unsupported(ref capability) => write!(f, "Server does not support {}", capability),
+			ImapError::Interrupted => write!(f, "Interrupted while waiting for the server"),
+			ImapError::InvalidState(ref message) => write!(f, "{}", message),
+			ImapError::InvalidArgument(ref message) => write!(f, "Invalid argument: {}", message),
+			ImapError::AuthFailed(ref text) => write!(f, "Authentication failed: {}", text),
+			ImapError::AuthorizationFailed(ref text) => write!(f, "Authorization failed: {}", text),
+			ImapError::CredentialsExpired(ref text) => write!(f, "Credentials expired: {}", text)
+		}
+	}
+}
+
+impl Error for ImapError {
+	fn description(&self) -> &str {
+		match *self {
+			ImapError::Io(_) => "IO error",
+			ImapError::No(_) => "No Response",
+			ImapError::Bad(_) => "Bad Response",
+			ImapError::Parse(_) => "Unable to parse response",
+			ImapError::TimedOut => "Timed out",
+			ImapError::Bye(_) => "Server closed the connection",
+			ImapError::Unsupported(_) => "Unsupported by the server",
+			ImapError::Interrupted => "Interrupted",
+			ImapError::InvalidState(_) => "Invalid state for the command",
+			ImapError::InvalidArgument(_) => "Invalid argument",
+			ImapError::AuthFailed(_) => "Authentication failed",
+			ImapError::AuthorizationFailed(_) => "Authorization failed",
+			ImapError::CredentialsExpired(_) => "Credentials expired"
+		}
+	}
+}
+
+#[test]
+fn response_code_parses_known_codes() {
+    assert_eq!(ResponseCode::parse("TRYCREATE"), ResponseCode::TryCreate);
+    assert_eq!(ResponseCode::parse("ALREADYEXISTS"), ResponseCode::AlreadyExists);
+    assert_eq!(ResponseCode::parse("CANNOT"), ResponseCode::Cannot);
+    assert_eq!(ResponseCode::parse("UIDVALIDITY 3857529045"), ResponseCode::UidValidity(3857529045));
+    assert_eq!(ResponseCode::parse("PERMANENTFLAGS (\\Deleted \\Seen \\*)"), ResponseCode::PermanentFlags(vec!["\\Deleted".to_string(), "\\Seen".to_string(), "\\*".to_string()]));
+    assert_eq!(ResponseCode::parse("CAPABILITY IMAP4rev1 IDLE"), ResponseCode::Capability(vec!["IMAP4rev1".to_string(), "IDLE".to_string()]));
+    assert_eq!(ResponseCode::parse("BADCHARSET (US-ASCII UTF-8)"), ResponseCode::BadCharset(vec!["US-ASCII".to_string(), "UTF-8".to_string()]));
+    assert_eq!(ResponseCode::parse("APPENDUID 38505 3955"), ResponseCode::AppendUid(38505, 3955));
+    assert_eq!(ResponseCode::parse("MODIFIED 7,9:11"), ResponseCode::Modified("7,9:11".to_string()));
+    assert_eq!(ResponseCode::parse("AUTHENTICATIONFAILED"), ResponseCode::AuthenticationFailed);
+    assert_eq!(ResponseCode::parse("NOPERM"), ResponseCode::Other("NOPERM".to_string()));
+}
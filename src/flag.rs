@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+
+use error::ImapError;
+
+/// A message flag. The system flags have their own variants; keywords such as `$Forwarded` and
+/// any other flags are kept as sent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Flag {
+	Seen,
+	Answered,
+	Flagged,
+	Deleted,
+	Draft,
+	Recent,
+	/// A keyword or any other flag, including its backslash if it has one.
+	Custom(String)
+}
+
+/// How `IMAPStream::store_flags` changes the flags of a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlagOp {
+	/// Replaces the flags of the message.
+	Replace,
+	/// Adds the flags to those of the message.
+	Add,
+	/// Removes the flags from those of the message.
+	Remove
+}
+
+impl fmt::Display for Flag {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Flag::Seen => write!(f, "\\Seen"),
+			Flag::Answered => write!(f, "\\Answered"),
+			Flag::Flagged => write!(f, "\\Flagged"),
+			Flag::Deleted => write!(f, "\\Deleted"),
+			Flag::Draft => write!(f, "\\Draft"),
+			Flag::Recent => write!(f, "\\Recent"),
+			Flag::Custom(ref flag) => write!(f, "{}", flag)
+		}
+	}
+}
+
+impl FromStr for Flag {
+	type Err = ImapError;
+
+	/// Parses a flag as sent by the server. System flags are matched case-insensitively. Fails
+	/// for text that can't be a flag, such as an empty string or one containing spaces.
+	fn from_str(flag: &str) -> Result<Flag, ImapError> {
+		if flag.is_empty() || flag.bytes().any(|b| b <= b' ' || b >= 0x7f || b"(){%\"]".contains(&b)) {
+			return Err(ImapError::Parse(format!("Invalid flag: {}", flag)));
+		}
+
+		let system_flags = [
+			("\\Seen", Flag::Seen),
+			("\\Answered", Flag::Answered),
+			("\\Flagged", Flag::Flagged),
+			("\\Deleted", Flag::Deleted),
+			("\\Draft", Flag::Draft),
+			("\\Recent", Flag::Recent)
+		];
+		for &(ref name, ref system_flag) in system_flags.iter() {
+			if flag.eq_ignore_ascii_case(name) {
+				return Ok(system_flag.clone());
+			}
+		}
+		Ok(Flag::Custom(flag.to_string()))
+	}
+}
+
+#[test]
+fn flags_round_trip() {
+    assert_eq!("\\seen".parse::<Flag>().unwrap(), Flag::Seen);
+    assert_eq!("$Forwarded".parse::<Flag>().unwrap(), Flag::Custom("$Forwarded".to_string()));
+    assert_eq!("\\*".parse::<Flag>().unwrap().to_string(), "\\*");
+    assert_eq!(Flag::Deleted.to_string(), "\\Deleted");
+    assert!("".parse::<Flag>().is_err());
+    assert!("two words".parse::<Flag>().is_err());
+}
@@ -3,7 +3,32 @@
 
 //! imap is a IMAP client for Rust.
 
+extern crate base64;
+extern crate chrono;
+extern crate email;
+extern crate flate2;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "openssl")]
 extern crate openssl;
 extern crate regex;
+#[cfg(feature = "rustls-tls")]
+extern crate rustls;
 
+#[cfg(feature = "openssl")]
+pub mod builder;
+pub mod bodystructure;
 pub mod client;
+mod connection;
+#[cfg(test)]
+mod mock_server;
+pub mod envelope;
+pub mod error;
+pub mod flag;
+pub mod parser;
+pub mod pool;
+pub mod rfc2047;
+pub mod search;
+pub mod sequence;
+pub mod thread;
+pub mod utf7;
@@ -0,0 +1,88 @@
+//! A scriptable IMAP server for tests. It accepts a connection, sends a greeting, and then answers
+//! each command with a canned response, asserting that the client sent what the script expected.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread::{self, JoinHandle};
+
+pub struct MockServer {
+	addr: SocketAddr,
+	handle: JoinHandle<()>
+}
+
+impl MockServer {
+	/// Starts a server on a free local port. The script pairs each command the client is expected
+	/// to send, without its CRLF, with the response to send back, which must end with a CRLF. A
+	/// command with CRLFs inside, such as literal data, is read over as many lines. An empty
+	/// response sends nothing, e.g. for a command the client pipelines or sends a literal after.
+	pub fn start(greeting: &str, script: &[(&str, &str)]) -> MockServer {
+		MockServer::start_sessions(&[(greeting, script)])
+	}
+
+	/// Like `start`, but accepts a connection for each session in turn, e.g. to test reconnecting.
+	/// Each connection is closed once its script has been played.
+	pub fn start_sessions(sessions: &[(&str, &[(&str, &str)])]) -> MockServer {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let sessions: Vec<(String, Vec<(String, String)>)> = sessions.iter().map(|&(greeting, script)| {
+			(greeting.to_string(), script.iter().map(|&(command, response)| (command.to_string(), response.to_string())).collect())
+		}).collect();
+
+		let handle = thread::spawn(move || {
+			for (greeting, script) in sessions {
+				let (mut stream, _) = listener.accept().unwrap();
+				let mut reader = BufReader::new(stream.try_clone().unwrap());
+				stream.write_all(greeting.as_bytes()).unwrap();
+				for (expected, response) in script {
+					let mut command = String::new();
+					for _ in 0..expected.matches("\r\n").count() + 1 {
+						reader.read_line(&mut command).unwrap();
+					}
+					if command.ends_with("\r\n") {
+						let length = command.len() - 2;
+						command.truncate(length);
+					}
+					assert_eq!(command, expected);
+					stream.write_all(response.as_bytes()).unwrap();
+				}
+			}
+		});
+
+		MockServer { addr: addr, handle: handle }
+	}
+
+	/// The address to connect the client to.
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Waits for the server to play the whole script, failing the test if the client sent anything
+	/// unexpected.
+	pub fn finish(self) {
+		if let Err(e) = self.handle.join() {
+			panic!("mock server failed: {:?}", e.downcast_ref::<String>());
+		}
+	}
+}
+
+#[test]
+fn mock_server_replays_the_script() {
+    use client::IMAPStream;
+    use flag::{Flag, FlagOp};
+
+    let server = MockServer::start("* PREAUTH IMAP4rev1 ready\r\n", &[
+        ("a1 SELECT INBOX", "* 3 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen \\Deleted)\r\na1 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a2 SEARCH UNSEEN", "* SEARCH 2 3\r\na2 OK SEARCH completed\r\n"),
+        ("a3 STORE 2:3 +FLAGS (\\Seen)", "* 2 FETCH (FLAGS (\\Seen))\r\n* 3 FETCH (FLAGS (\\Seen))\r\na3 OK STORE completed\r\n"),
+        ("a4 FETCH 2 FLAGS", "* 2 FETCH (FLAGS (\\Seen))\r\na4 OK FETCH completed\r\n"),
+        ("a5 LOGOUT", "* BYE Logging out\r\na5 OK LOGOUT completed\r\n")
+    ]);
+
+    let mut imap = IMAPStream::connect_plain(server.addr()).unwrap();
+    assert_eq!(imap.select("INBOX").unwrap().exists, 3);
+    assert_eq!(imap.search("UNSEEN").unwrap(), vec![2, 3]);
+    imap.store_flags(2..=3, FlagOp::Add, &[Flag::Seen]).unwrap();
+    assert_eq!(imap.fetch(2, "FLAGS").unwrap().len(), 2);
+    imap.logout().unwrap();
+    server.finish();
+}
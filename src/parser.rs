@@ -0,0 +1,353 @@
+//! A small parser for the structured data carried by IMAP responses: atoms, quoted strings,
+//! literals, NIL and parenthesized lists.
+
+use chrono::{DateTime, FixedOffset};
+use std::borrow::Cow;
+use std::str;
+
+use error::{ImapError, Result};
+
+/// How deeply lists may nest before a response is rejected, so a hostile server can't overflow
+/// the stack. Real responses, even deeply nested BODYSTRUCTUREs, stay far below it.
+const MAX_NESTING: usize = 256;
+
+/// A single piece of response data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+	Atom(String),
+	QuotedString(String),
+	/// The data of a literal, kept as bytes since it may not be text.
+	Literal(Vec<u8>),
+	List(Vec<Token>),
+	Nil
+}
+
+impl Token {
+	/// Returns the text of an atom, quoted string or literal. NIL and lists have no text, and
+	/// neither does a literal that isn't valid UTF-8.
+	pub fn as_str(&self) -> Option<&str> {
+		match *self {
+			Token::Atom(ref s) | Token::QuotedString(ref s) => Some(s),
+			Token::Literal(ref data) => str::from_utf8(data).ok(),
+			_ => None
+		}
+	}
+
+	/// Returns the bytes of an atom, quoted string or literal.
+	pub fn as_bytes(&self) -> Option<&[u8]> {
+		match *self {
+			Token::Atom(ref s) | Token::QuotedString(ref s) => Some(s.as_bytes()),
+			Token::Literal(ref data) => Some(data),
+			_ => None
+		}
+	}
+
+	/// Like `as_str`, but replaces invalid UTF-8 in a literal instead of giving up on it.
+	pub fn to_string_lossy(&self) -> Option<Cow<'_, str>> {
+		self.as_bytes().map(String::from_utf8_lossy)
+	}
+
+	/// Returns the items of a list. NIL is treated as an empty list.
+	pub fn as_list(&self) -> Option<&[Token]> {
+		match *self {
+			Token::List(ref items) => Some(items),
+			Token::Nil => Some(&[]),
+			_ => None
+		}
+	}
+}
+
+/// Parses the untagged `* n FETCH (...)` responses out of a command's response lines, returning
+/// the message number and data items of each. Other untagged responses are skipped.
+pub fn parse_fetch_responses<L: AsRef<[u8]>>(lines: &[L]) -> Result<Vec<(u32, Vec<Token>)>> {
+	let mut response = Vec::new();
+	for line in lines.iter() {
+		response.extend_from_slice(line.as_ref());
+	}
+	let mut parser = Parser::new(&response);
+	let mut fetches = Vec::new();
+
+	while !parser.at_end() {
+		if !parser.consume(b"* ") {
+			// A tagged status line ends the response.
+			break;
+		}
+
+		let start = parser.pos;
+		let number = parser.read_atom();
+		parser.skip_spaces();
+		let kind = parser.read_atom();
+		parser.skip_spaces();
+
+		match (number.parse::<u32>(), kind.to_uppercase() == "FETCH") {
+			(Ok(number), true) => {
+				let items = match try!(parser.read_token()) {
+					Token::List(items) => items,
+					_ => return Err(parser.error_from(start))
+				};
+				parser.skip_spaces();
+				if !parser.consume(b"\r\n") {
+					return Err(parser.error_from(start));
+				}
+				fetches.push((number, items));
+			},
+			_ => parser.skip_line()
+		}
+	}
+
+	Ok(fetches)
+}
+
+/// Parses the data of a response, such as the lists following `* THREAD`, into a sequence of
+/// tokens. The data may end with the line's CRLF.
+pub fn parse_tokens<D: AsRef<[u8]> + ?Sized>(data: &D) -> Result<Vec<Token>> {
+	let mut parser = Parser::new(data.as_ref());
+	let mut tokens = Vec::new();
+
+	loop {
+		parser.skip_spaces();
+		if parser.at_end() || parser.consume(b"\r\n") {
+			return Ok(tokens);
+		}
+		tokens.push(try!(parser.read_token()));
+	}
+}
+
+/// Finds the value following the named data item in a FETCH response's item list.
+pub fn find_item<'a>(items: &'a [Token], name: &str) -> Option<&'a Token> {
+	let mut iter = items.iter();
+	while let Some(item) = iter.next() {
+		let value = iter.next();
+		if let Some(item_name) = item.as_str() {
+			if item_name.eq_ignore_ascii_case(name) {
+				return value;
+			}
+		}
+	}
+	None
+}
+
+struct Parser<'a> {
+	input: &'a [u8],
+	pos: usize,
+	/// The number of lists the parser is inside of.
+	depth: usize
+}
+
+impl<'a> Parser<'a> {
+	fn new(input: &'a [u8]) -> Parser<'a> {
+		Parser { input: input, pos: 0, depth: 0 }
+	}
+
+	fn at_end(&self) -> bool {
+		self.pos >= self.input.len()
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.input.get(self.pos).cloned()
+	}
+
+	fn consume(&mut self, expected: &[u8]) -> bool {
+		if self.input[self.pos..].starts_with(expected) {
+			self.pos += expected.len();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn skip_spaces(&mut self) {
+		while self.peek() == Some(b' ') {
+			self.pos += 1;
+		}
+	}
+
+	/// Skips to the start of the next line, jumping over a literal announced at the end of it.
+	fn skip_line(&mut self) {
+		loop {
+			let line_start = self.pos;
+			while !self.at_end() && !self.consume(b"\r\n") {
+				self.pos += 1;
+			}
+			match literal_length(&self.input[line_start..self.pos]) {
+				Some(length) => self.pos = ::std::cmp::min(self.pos + length, self.input.len()),
+				None => return
+			}
+		}
+	}
+
+	fn error_from(&self, start: usize) -> ImapError {
+		let end = self.input[start..].iter().position(|&b| b == b'\n').map(|p| start + p + 1).unwrap_or(self.input.len());
+		ImapError::Parse(String::from_utf8_lossy(&self.input[start..end]).into_owned())
+	}
+
+	fn read_token(&mut self) -> Result<Token> {
+		let start = self.pos;
+		match self.peek() {
+			Some(b'(') => {
+				if self.depth == MAX_NESTING {
+					return Err(self.error_from(start));
+				}
+				self.pos += 1;
+				self.depth += 1;
+				let mut items = Vec::new();
+				loop {
+					self.skip_spaces();
+					match self.peek() {
+						Some(b')') => {
+							self.pos += 1;
+							self.depth -= 1;
+							return Ok(Token::List(items));
+						},
+						Some(_) => items.push(try!(self.read_token())),
+						None => return Err(self.error_from(start))
+					}
+				}
+			},
+			Some(b'"') => {
+				self.pos += 1;
+				let mut bytes = Vec::new();
+				loop {
+					match self.peek() {
+						Some(b'\\') => {
+							self.pos += 1;
+							match self.peek() {
+								Some(escaped) => bytes.push(escaped),
+								None => return Err(self.error_from(start))
+							}
+						},
+						Some(b'"') => {
+							self.pos += 1;
+							return Ok(Token::QuotedString(String::from_utf8_lossy(&bytes).into_owned()));
+						},
+						Some(b'\r') | Some(b'\n') | None => return Err(self.error_from(start)),
+						Some(b) => bytes.push(b)
+					}
+					self.pos += 1;
+				}
+			},
+			Some(b'~') if self.input[self.pos..].starts_with(b"~{") => {
+				// A literal8, which may carry binary data.
+				self.pos += 1;
+				self.read_token()
+			},
+			Some(b'{') => {
+				let header_end = match self.input[self.pos..].windows(3).position(|w| w == b"}\r\n") {
+					Some(offset) => self.pos + offset,
+					None => return Err(self.error_from(start))
+				};
+				let length = match String::from_utf8_lossy(&self.input[self.pos + 1..header_end]).parse::<usize>() {
+					Ok(length) => length,
+					Err(_) => return Err(self.error_from(start))
+				};
+				let data_start = header_end + 3;
+				if data_start + length > self.input.len() {
+					return Err(self.error_from(start));
+				}
+				self.pos = data_start + length;
+				Ok(Token::Literal(self.input[data_start..self.pos].to_vec()))
+			},
+			Some(_) => {
+				let atom = self.read_atom();
+				if atom.is_empty() {
+					Err(self.error_from(start))
+				} else if atom.eq_ignore_ascii_case("NIL") {
+					Ok(Token::Nil)
+				} else {
+					Ok(Token::Atom(atom))
+				}
+			},
+			None => Err(self.error_from(start))
+		}
+	}
+
+	/// Reads an atom. Section specifiers like `BODY[HEADER.FIELDS (FROM)]<0>` are read whole,
+	/// including any spaces and parentheses inside the brackets.
+	fn read_atom(&mut self) -> String {
+		let start = self.pos;
+		let mut in_section = false;
+		let mut in_partial = false;
+		while let Some(b) = self.peek() {
+			match b {
+				b'[' if !in_partial => in_section = true,
+				b']' if in_section => in_section = false,
+				b'<' if !in_section && self.pos > start && self.input[self.pos - 1] == b']' => in_partial = true,
+				b'>' if in_partial => in_partial = false,
+				b'\r' | b'\n' => break,
+				b' ' | b'(' | b')' | b'"' | b'{' if !in_section && !in_partial => break,
+				_ => {}
+			}
+			self.pos += 1;
+		}
+		String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+	}
+}
+
+/// Parses an IMAP `date-time` such as `17-Jul-1996 02:44:25 -0700`, as used by INTERNALDATE.
+/// Single-digit days may be padded with a space.
+pub fn parse_date_time(date_time: &str) -> Result<DateTime<FixedOffset>> {
+	match DateTime::parse_from_str(date_time.trim_start(), "%d-%b-%Y %H:%M:%S %z") {
+		Ok(parsed) => Ok(parsed),
+		Err(_) => Err(ImapError::Parse(format!("Invalid date-time: {}", date_time)))
+	}
+}
+
+/// Returns the announced length if the line ends with a `{n}` literal header.
+pub fn literal_length(line: &[u8]) -> Option<usize> {
+	let line = if line.ends_with(b"\r\n") { &line[..line.len() - 2] } else { line };
+	if !line.ends_with(b"}") {
+		return None;
+	}
+	let open = match line.iter().rposition(|&b| b == b'{') {
+		Some(open) => open,
+		None => return None
+	};
+	String::from_utf8_lossy(&line[open + 1..line.len() - 1]).parse::<usize>().ok()
+}
+
+#[test]
+fn parse_fetch_responses_handles_nesting_and_literals() {
+    let lines = vec![
+        "* 12 FETCH (FLAGS (\\Seen) BODY[HEADER.FIELDS (FROM)] {11}\r\n".to_string(),
+        "From: a\r\n\r\n UID 7 X NIL)\r\n".to_string(),
+        "* 3 EXISTS\r\n".to_string(),
+        "* 13 FETCH (X \"quoted \\\"string\\\"\")\r\n".to_string(),
+        "a1 OK FETCH completed\r\n".to_string()
+    ];
+    let fetches = parse_fetch_responses(&lines).unwrap();
+    assert_eq!(fetches.len(), 2);
+    assert_eq!(fetches[0].0, 12);
+    assert_eq!(find_item(&fetches[0].1, "FLAGS"), Some(&Token::List(vec![Token::Atom("\\Seen".to_string())])));
+    assert_eq!(find_item(&fetches[0].1, "BODY[HEADER.FIELDS (FROM)]"), Some(&Token::Literal(b"From: a\r\n\r\n".to_vec())));
+    assert_eq!(find_item(&fetches[0].1, "UID"), Some(&Token::Atom("7".to_string())));
+    assert_eq!(find_item(&fetches[0].1, "X"), Some(&Token::Nil));
+    assert_eq!(find_item(&fetches[1].1, "X"), Some(&Token::QuotedString("quoted \"string\"".to_string())));
+}
+
+#[test]
+fn parse_date_time_handles_padded_days() {
+    let date = parse_date_time("17-Jul-1996 02:44:25 -0700").unwrap();
+    assert_eq!(date.to_rfc3339(), "1996-07-17T02:44:25-07:00");
+    let date = parse_date_time(" 7-Jul-1996 02:44:25 +0200").unwrap();
+    assert_eq!(date.to_rfc3339(), "1996-07-07T02:44:25+02:00");
+    assert!(parse_date_time("17-Foo-1996 02:44:25 -0700").is_err());
+}
+
+#[test]
+fn parse_tokens_reads_literal8() {
+    let tokens = parse_tokens(&b"BINARY[1] ~{3}\r\n\xff\x00a NIL\r\n"[..]).unwrap();
+    assert_eq!(tokens, vec![Token::Atom("BINARY[1]".to_string()), Token::Literal(vec![0xff, 0x00, b'a']), Token::Nil]);
+    assert_eq!(tokens[1].as_str(), None);
+    assert_eq!(tokens[1].to_string_lossy().unwrap(), "\u{fffd}\u{0}a");
+}
+
+#[test]
+fn parse_tokens_rejects_excessive_nesting() {
+    let nested = format!("{}{}\r\n", "(".repeat(MAX_NESTING), ")".repeat(MAX_NESTING));
+    assert!(parse_tokens(&nested).is_ok());
+    let hostile = format!("{}\r\n", "(".repeat(100000));
+    match parse_tokens(&hostile) {
+        Err(ImapError::Parse(_)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
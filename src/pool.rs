@@ -0,0 +1,197 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use client::{ConnectionState, IMAPStream};
+use error::Result;
+
+/// A fixed-size pool of logged in connections for servers handling concurrent requests.
+/// Connections are handed out by `get` and returned to the pool when the returned guard is
+/// dropped. A connection found dead when handed out is reconnected and logged in again with the
+/// credentials the pool was created with.
+///
+/// When a connection is returned, any selected mailbox is deselected without expunging, and
+/// unsolicited responses left over from its last user are discarded.
+pub struct ImapPool {
+	idle: Mutex<Vec<IMAPStream>>,
+	available: Condvar,
+	username: String,
+	password: String
+}
+
+/// A connection borrowed from an `ImapPool`, which it returns to when dropped.
+#[derive(Debug)]
+pub struct PooledConnection<'a> {
+	pool: &'a ImapPool,
+	stream: Option<IMAPStream>
+}
+
+impl ImapPool {
+	/// Opens `size` connections with `connect` and logs each of them in. `connect` returns a
+	/// connection that isn't logged in yet, e.g. from `IMAPStream::connect`.
+	pub fn new<F: FnMut() -> Result<IMAPStream>>(size: usize, username: &str, password: &str, mut connect: F) -> Result<ImapPool> {
+		let mut streams = Vec::with_capacity(size);
+		for _ in 0..size {
+			let mut stream = try!(connect());
+			try!(stream.login(username, password));
+			streams.push(stream);
+		}
+
+		Ok(ImapPool {
+			idle: Mutex::new(streams),
+			available: Condvar::new(),
+			username: username.to_string(),
+			password: password.to_string()
+		})
+	}
+
+	/// Takes an idle connection from the pool, waiting for one to be returned if they are all in
+	/// use. The connection is checked with `is_alive` first and replaced with a new one if it is
+	/// dead; if that fails too, the error is returned and the connection is kept in the pool to be
+	/// retried by the next call.
+	pub fn get(&self) -> Result<PooledConnection<'_>> {
+		let mut stream = {
+			let mut idle = self.lock_idle();
+			loop {
+				match idle.pop() {
+					Some(stream) => break stream,
+					None => idle = match self.available.wait(idle) {
+						Ok(idle) => idle,
+						Err(poisoned) => poisoned.into_inner()
+					}
+				}
+			}
+		};
+
+		if !stream.is_alive() {
+			let revived = match stream.reconnect() {
+				Ok(()) => stream.login(&self.username, &self.password),
+				Err(e) => Err(e)
+			};
+			if let Err(e) = revived {
+				self.put_back(stream);
+				return Err(e);
+			}
+		}
+
+		Ok(PooledConnection { pool: self, stream: Some(stream) })
+	}
+
+	/// Returns the number of connections that aren't in use.
+	pub fn idle_count(&self) -> usize {
+		self.lock_idle().len()
+	}
+
+	fn put_back(&self, mut stream: IMAPStream) {
+		reset(&mut stream);
+		self.lock_idle().push(stream);
+		self.available.notify_one();
+	}
+
+	fn lock_idle(&self) -> MutexGuard<'_, Vec<IMAPStream>> {
+		match self.idle.lock() {
+			Ok(idle) => idle,
+			Err(poisoned) => poisoned.into_inner()
+		}
+	}
+}
+
+/// Leaves a connection the way `get` hands it out. A failure is left for `is_alive` to notice
+/// on the next `get`.
+fn reset(stream: &mut IMAPStream) {
+	if stream.state() == ConnectionState::Selected {
+		let _ = stream.deselect();
+	}
+	stream.take_unsolicited();
+}
+
+/// Shows the username and the number of idle connections, but never the password.
+impl fmt::Debug for ImapPool {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ImapPool")
+			.field("username", &self.username)
+			.field("password", &"<redacted>")
+			.field("idle", &self.idle_count())
+			.finish()
+	}
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+	type Target = IMAPStream;
+
+	fn deref(&self) -> &IMAPStream {
+		self.stream.as_ref().unwrap()
+	}
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+	fn deref_mut(&mut self) -> &mut IMAPStream {
+		self.stream.as_mut().unwrap()
+	}
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+	fn drop(&mut self) {
+		if let Some(stream) = self.stream.take() {
+			self.pool.put_back(stream);
+		}
+	}
+}
+
+#[test]
+fn get_replaces_dead_connections() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start_sessions(&[
+        ("* OK IMAP4rev1 ready\r\n", &[
+            ("a1 LOGIN user pass", "a1 OK LOGIN completed\r\n"),
+            ("a2 NOOP", "a2 OK NOOP completed\r\n"),
+            // Close the connection without answering.
+            ("a3 NOOP", "")
+        ]),
+        ("* OK IMAP4rev1 ready\r\n", &[
+            ("a1 LOGIN user pass", "a1 OK LOGIN completed\r\n"),
+            ("a2 LOGOUT", "* BYE Logging out\r\na2 OK LOGOUT completed\r\n")
+        ])
+    ]);
+
+    let addr = server.addr();
+    let pool = ImapPool::new(1, "user", "pass", || IMAPStream::connect_plain(addr)).unwrap();
+    {
+        let connection = pool.get().unwrap();
+        assert_eq!(pool.idle_count(), 0);
+        assert!(connection.current_mailbox().is_none());
+    }
+    assert_eq!(pool.idle_count(), 1);
+    assert_eq!(format!("{:?}", pool), "ImapPool { username: \"user\", password: \"<redacted>\", idle: 1 }");
+    drop(pool.get().unwrap());
+    drop(pool);
+    server.finish();
+}
+
+#[test]
+fn returned_connections_are_deselected() {
+    use mock_server::MockServer;
+
+    let server = MockServer::start("* OK IMAP4rev1 ready\r\n", &[
+        ("a1 LOGIN user pass", "a1 OK LOGIN completed\r\n"),
+        ("a2 NOOP", "a2 OK NOOP completed\r\n"),
+        ("a3 SELECT INBOX", "* 3 EXISTS\r\n* 0 RECENT\r\na3 OK [READ-WRITE] SELECT completed\r\n"),
+        ("a4 CAPABILITY", "* CAPABILITY IMAP4rev1\r\na4 OK CAPABILITY completed\r\n"),
+        ("a5 EXAMINE INBOX", "* 3 EXISTS\r\n* 0 RECENT\r\na5 OK [READ-ONLY] EXAMINE completed\r\n"),
+        ("a6 CLOSE", "a6 OK CLOSE completed\r\n"),
+        ("a7 NOOP", "a7 OK NOOP completed\r\n"),
+        ("a8 LOGOUT", "* BYE Logging out\r\na8 OK LOGOUT completed\r\n")
+    ]);
+
+    let addr = server.addr();
+    let pool = ImapPool::new(1, "user", "pass", || IMAPStream::connect_plain(addr)).unwrap();
+    pool.get().unwrap().select("INBOX").unwrap();
+    {
+        let connection = pool.get().unwrap();
+        assert_eq!(connection.state(), ConnectionState::Authenticated);
+        assert!(connection.current_mailbox().is_none());
+    }
+    drop(pool);
+    server.finish();
+}
@@ -0,0 +1,148 @@
+//! Decoding of the `=?charset?encoding?text?=` encoded-words of RFC 2047, used for non-ASCII
+//! subjects and display names in message headers and envelopes.
+
+/// Decodes the encoded-words in a header value. Both the `B` and `Q` encodings are supported
+/// with the UTF-8, US-ASCII and ISO-8859-1 charsets. Whitespace between adjacent encoded-words is
+/// dropped. Encoded-words that can't be decoded are left as they were received.
+pub fn decode_encoded_words(text: &str) -> String {
+	let mut decoded = String::with_capacity(text.len());
+	// Consecutive encoded-words in the same charset are decoded together, since a multi-byte
+	// character may be split across them.
+	let mut pending: Option<(String, Vec<u8>, String)> = None;
+	let mut rest = text;
+
+	while let Some(start) = rest.find("=?") {
+		let (charset, bytes, length) = match parse_encoded_word(&rest[start..]) {
+			Some(word) => word,
+			None => {
+				flush(&mut decoded, &mut pending);
+				decoded.push_str(&rest[..start + 2]);
+				rest = &rest[start + 2..];
+				continue;
+			}
+		};
+
+		let between = &rest[..start];
+		let adjacent = pending.is_some() && between.chars().all(char::is_whitespace);
+		if !adjacent {
+			flush(&mut decoded, &mut pending);
+			decoded.push_str(between);
+		}
+
+		let raw = &rest[start..start + length];
+		match pending {
+			Some((ref pending_charset, ref mut pending_bytes, ref mut pending_raw)) if *pending_charset == charset => {
+				pending_bytes.extend(bytes);
+				pending_raw.push_str(raw);
+			},
+			_ => {
+				flush(&mut decoded, &mut pending);
+				pending = Some((charset, bytes, raw.to_string()));
+			}
+		}
+		rest = &rest[start + length..];
+	}
+
+	flush(&mut decoded, &mut pending);
+	decoded.push_str(rest);
+	decoded
+}
+
+/// Parses the encoded-word at the start of the text, returning its lowercased charset, its
+/// decoded bytes and its length.
+fn parse_encoded_word(text: &str) -> Option<(String, Vec<u8>, usize)> {
+	let mut parts = text[2..].splitn(3, '?');
+	let charset = match parts.next() {
+		Some(charset) if !charset.is_empty() && !charset.contains(char::is_whitespace) => charset,
+		_ => return None
+	};
+	let encoding = match parts.next() {
+		Some(encoding) => encoding,
+		None => return None
+	};
+	let rest = match parts.next() {
+		Some(rest) => rest,
+		None => return None
+	};
+	let encoded_end = match rest.find("?=") {
+		Some(end) => end,
+		None => return None
+	};
+	let encoded = &rest[..encoded_end];
+	if encoded.contains(|c: char| c == '?' || c.is_whitespace()) {
+		return None;
+	}
+
+	let bytes = if encoding.eq_ignore_ascii_case("B") {
+		match base64::decode(encoded).or_else(|_| base64::decode_config(encoded.trim_end_matches('='), base64::STANDARD_NO_PAD)) {
+			Ok(bytes) => bytes,
+			Err(_) => return None
+		}
+	} else if encoding.eq_ignore_ascii_case("Q") {
+		match decode_q(encoded) {
+			Some(bytes) => bytes,
+			None => return None
+		}
+	} else {
+		return None;
+	};
+
+	let length = 2 + charset.len() + 1 + encoding.len() + 1 + encoded_end + 2;
+	// A language may follow the charset, as in `utf-8*en`.
+	let charset = charset.splitn(2, '*').next().unwrap().to_lowercase();
+	Some((charset, bytes, length))
+}
+
+/// Decodes the Q encoding: quoted-printable, with underscores standing for spaces.
+fn decode_q(encoded: &str) -> Option<Vec<u8>> {
+	let bytes = encoded.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut index = 0;
+	while index < bytes.len() {
+		match bytes[index] {
+			b'_' => decoded.push(b' '),
+			b'=' => {
+				let hex = match encoded.get(index + 1..index + 3) {
+					Some(hex) => hex,
+					None => return None
+				};
+				match u8::from_str_radix(hex, 16) {
+					Ok(byte) => decoded.push(byte),
+					Err(_) => return None
+				}
+				index += 2;
+			},
+			byte => decoded.push(byte)
+		}
+		index += 1;
+	}
+	Some(decoded)
+}
+
+/// Converts the bytes of a charset to a string. Returns `None` for unsupported charsets.
+fn decode_charset(charset: &str, bytes: &[u8]) -> Option<String> {
+	match charset {
+		"utf-8" | "utf8" | "us-ascii" | "ascii" => String::from_utf8(bytes.to_vec()).ok(),
+		"iso-8859-1" | "iso8859-1" | "latin1" => Some(bytes.iter().map(|&b| b as char).collect()),
+		_ => None
+	}
+}
+
+fn flush(decoded: &mut String, pending: &mut Option<(String, Vec<u8>, String)>) {
+	if let Some((charset, bytes, raw)) = pending.take() {
+		match decode_charset(&charset, &bytes) {
+			Some(text) => decoded.push_str(&text),
+			None => decoded.push_str(&raw)
+		}
+	}
+}
+
+#[test]
+fn decode_encoded_words_handles_both_encodings() {
+    assert_eq!(decode_encoded_words("=?UTF-8?B?SGVsbG8gV8O2cmxk?="), "Hello Wörld");
+    assert_eq!(decode_encoded_words("=?iso-8859-1?q?Andr=E9_Pirard?= <pirard@example.com>"), "André Pirard <pirard@example.com>");
+    assert_eq!(decode_encoded_words("Re: =?utf-8?Q?caf=C3?= =?utf-8?Q?=A9?= time"), "Re: café time");
+    assert_eq!(decode_encoded_words("=?US-ASCII*EN?Q?Keith_Moore?="), "Keith Moore");
+    assert_eq!(decode_encoded_words("=?koi8-r?B?8NLJ18XU?= plain"), "=?koi8-r?B?8NLJ18XU?= plain");
+    assert_eq!(decode_encoded_words("a =? b ?= c"), "a =? b ?= c");
+}
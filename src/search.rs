@@ -0,0 +1,121 @@
+use chrono::NaiveDate;
+
+use client::quote_argument;
+use error::Result;
+use sequence::SequenceSet;
+
+/// Search criteria for the SEARCH command. Use `And` to require several criteria at once.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchCriteria {
+	All,
+	Answered,
+	Unanswered,
+	Deleted,
+	Undeleted,
+	Draft,
+	Flagged,
+	Unflagged,
+	New,
+	Recent,
+	Seen,
+	Unseen,
+	/// Messages with the given keyword flag set.
+	Keyword(String),
+	From(String),
+	To(String),
+	Cc(String),
+	Bcc(String),
+	Subject(String),
+	Body(String),
+	/// Messages containing the string in their header or body.
+	Text(String),
+	/// Messages with the named header field containing the string.
+	Header(String, String),
+	/// Messages whose internal date is on or after the date.
+	Since(NaiveDate),
+	/// Messages whose internal date is before the date.
+	Before(NaiveDate),
+	/// Messages whose internal date is the date.
+	On(NaiveDate),
+	/// Messages whose Date header is on or after the date.
+	SentSince(NaiveDate),
+	/// Messages whose Date header is before the date.
+	SentBefore(NaiveDate),
+	/// Messages larger than the given number of octets.
+	Larger(u32),
+	/// Messages smaller than the given number of octets.
+	Smaller(u32),
+	/// Messages with a UID in the given set, e.g. `1:100`.
+	Uid(SequenceSet),
+	And(Vec<SearchCriteria>),
+	Or(Box<SearchCriteria>, Box<SearchCriteria>),
+	Not(Box<SearchCriteria>)
+}
+
+impl SearchCriteria {
+	/// Renders the criteria as the arguments of a SEARCH command. Strings are quoted, or sent as
+	/// literals when they contain 8-bit data or line breaks. Fails with
+	/// `ImapError::InvalidArgument` if a UID set is empty or malformed.
+	pub fn render(&self) -> Result<String> {
+		Ok(match *self {
+			SearchCriteria::All => "ALL".to_string(),
+			SearchCriteria::Answered => "ANSWERED".to_string(),
+			SearchCriteria::Unanswered => "UNANSWERED".to_string(),
+			SearchCriteria::Deleted => "DELETED".to_string(),
+			SearchCriteria::Undeleted => "UNDELETED".to_string(),
+			SearchCriteria::Draft => "DRAFT".to_string(),
+			SearchCriteria::Flagged => "FLAGGED".to_string(),
+			SearchCriteria::Unflagged => "UNFLAGGED".to_string(),
+			SearchCriteria::New => "NEW".to_string(),
+			SearchCriteria::Recent => "RECENT".to_string(),
+			SearchCriteria::Seen => "SEEN".to_string(),
+			SearchCriteria::Unseen => "UNSEEN".to_string(),
+			SearchCriteria::Keyword(ref keyword) => format!("KEYWORD {}", quote_argument(keyword)),
+			SearchCriteria::From(ref s) => format!("FROM {}", quote_argument(s)),
+			SearchCriteria::To(ref s) => format!("TO {}", quote_argument(s)),
+			SearchCriteria::Cc(ref s) => format!("CC {}", quote_argument(s)),
+			SearchCriteria::Bcc(ref s) => format!("BCC {}", quote_argument(s)),
+			SearchCriteria::Subject(ref s) => format!("SUBJECT {}", quote_argument(s)),
+			SearchCriteria::Body(ref s) => format!("BODY {}", quote_argument(s)),
+			SearchCriteria::Text(ref s) => format!("TEXT {}", quote_argument(s)),
+			SearchCriteria::Header(ref name, ref s) => format!("HEADER {} {}", quote_argument(name), quote_argument(s)),
+			SearchCriteria::Since(date) => format!("SINCE {}", format_date(date)),
+			SearchCriteria::Before(date) => format!("BEFORE {}", format_date(date)),
+			SearchCriteria::On(date) => format!("ON {}", format_date(date)),
+			SearchCriteria::SentSince(date) => format!("SENTSINCE {}", format_date(date)),
+			SearchCriteria::SentBefore(date) => format!("SENTBEFORE {}", format_date(date)),
+			SearchCriteria::Larger(size) => format!("LARGER {}", size),
+			SearchCriteria::Smaller(size) => format!("SMALLER {}", size),
+			SearchCriteria::Uid(ref set) => format!("UID {}", try!(set.to_argument())),
+			SearchCriteria::And(ref criteria) => {
+				if criteria.is_empty() {
+					return Ok("ALL".to_string());
+				}
+				let rendered: Vec<String> = try!(criteria.iter().map(SearchCriteria::render).collect());
+				format!("({})", rendered.join(" "))
+			},
+			SearchCriteria::Or(ref a, ref b) => format!("OR {} {}", try!(a.render()), try!(b.render())),
+			SearchCriteria::Not(ref criteria) => format!("NOT {}", try!(criteria.render()))
+		})
+	}
+}
+
+/// Formats a date as an IMAP `date`, e.g. `01-Feb-2024`.
+fn format_date(date: NaiveDate) -> String {
+	date.format("%d-%b-%Y").to_string()
+}
+
+#[test]
+fn render_quotes_strings_and_formats_dates() {
+    let criteria = SearchCriteria::And(vec![
+        SearchCriteria::Unseen,
+        SearchCriteria::From("Jane Doe".to_string()),
+        SearchCriteria::Since(NaiveDate::from_ymd_opt(2024, 2, 1).unwrap()),
+        SearchCriteria::Or(Box::new(SearchCriteria::Larger(1000)), Box::new(SearchCriteria::Not(Box::new(SearchCriteria::Subject("a\"b".to_string())))))
+    ]);
+    assert_eq!(criteria.render().unwrap(), "(UNSEEN FROM \"Jane Doe\" SINCE 01-Feb-2024 OR LARGER 1000 NOT SUBJECT \"a\\\"b\")");
+    assert_eq!(SearchCriteria::Subject("héllo".to_string()).render().unwrap(), "SUBJECT {6}\r\nhéllo");
+    assert_eq!(SearchCriteria::Uid(SequenceSet::from(1..=100)).render().unwrap(), "UID 1:100");
+    assert!(SearchCriteria::Uid(SequenceSet::from("1 OR ALL")).render().is_err());
+    assert!(SearchCriteria::Not(Box::new(SearchCriteria::Uid(SequenceSet::from("1\r\n")))).render().is_err());
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::ops::{RangeFrom, RangeInclusive};
+use std::str::FromStr;
+
+use error::{ImapError, Result};
+
+/// One end of a range in a sequence set: a message sequence number or UID, or `*` for the last
+/// message in the mailbox.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequenceNumber {
+	Number(u32),
+	Last
+}
+
+/// A set of message sequence numbers or UIDs, such as `1:4,7,10:*`, built from numbers and
+/// ranges. Sets converted from text are parsed when they are built and rejected when they are
+/// sent, so a malformed set is caught before it reaches the server.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SequenceSet {
+	ranges: Vec<(SequenceNumber, SequenceNumber)>,
+	invalid: Option<String>
+}
+
+impl SequenceSet {
+	/// Returns an empty set. Numbers and ranges are added with `push`.
+	pub fn new() -> SequenceSet {
+		SequenceSet::default()
+	}
+
+	/// Returns the set `1:*` of every message in the mailbox.
+	pub fn all() -> SequenceSet {
+		SequenceSet::from(1..)
+	}
+
+	/// Returns the set `*` of the last message in the mailbox.
+	pub fn last() -> SequenceSet {
+		SequenceSet { ranges: vec![(SequenceNumber::Last, SequenceNumber::Last)], invalid: None }
+	}
+
+	/// Adds a number, a range or another set to the set.
+	pub fn push<S: Into<SequenceSet>>(&mut self, other: S) {
+		let other = other.into();
+		self.ranges.extend(other.ranges);
+		if self.invalid.is_none() {
+			self.invalid = other.invalid;
+		}
+	}
+
+	/// Returns true if the set has no numbers in it.
+	pub fn is_empty(&self) -> bool {
+		self.ranges.is_empty()
+	}
+
+	/// Renders the set as a command argument, failing if it is empty or malformed.
+	pub fn to_argument(&self) -> Result<String> {
+		if let Some(ref error) = self.invalid {
+			return Err(ImapError::InvalidArgument(error.clone()));
+		}
+		if self.is_empty() {
+			return Err(ImapError::InvalidArgument("Empty sequence set".to_string()));
+		}
+		Ok(self.to_string())
+	}
+
+	fn invalid(error: String) -> SequenceSet {
+		SequenceSet { ranges: Vec::new(), invalid: Some(error) }
+	}
+}
+
+impl fmt::Display for SequenceNumber {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			SequenceNumber::Number(number) => write!(f, "{}", number),
+			SequenceNumber::Last => write!(f, "*")
+		}
+	}
+}
+
+impl fmt::Display for SequenceSet {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (i, &(start, end)) in self.ranges.iter().enumerate() {
+			if i > 0 {
+				try!(write!(f, ","));
+			}
+			if start == end {
+				try!(write!(f, "{}", start));
+			} else {
+				try!(write!(f, "{}:{}", start, end));
+			}
+		}
+		Ok(())
+	}
+}
+
+impl FromStr for SequenceNumber {
+	type Err = ImapError;
+
+	/// Parses `*` or a non-zero number without leading zeros.
+	fn from_str(number: &str) -> Result<SequenceNumber> {
+		if number == "*" {
+			return Ok(SequenceNumber::Last);
+		}
+		if number.starts_with('0') || !number.bytes().all(|b| b.is_ascii_digit()) {
+			return Err(ImapError::InvalidArgument(format!("Invalid sequence number: {:?}", number)));
+		}
+		match number.parse::<u32>() {
+			Ok(number) => Ok(SequenceNumber::Number(number)),
+			Err(_) => Err(ImapError::InvalidArgument(format!("Invalid sequence number: {:?}", number)))
+		}
+	}
+}
+
+impl FromStr for SequenceSet {
+	type Err = ImapError;
+
+	/// Parses a sequence set as sent in a command, such as `2,4:7,9,12:*`.
+	fn from_str(set: &str) -> Result<SequenceSet> {
+		let mut ranges = Vec::new();
+		for item in set.split(',') {
+			let mut bounds = item.splitn(2, ':');
+			let start = try!(bounds.next().unwrap().parse::<SequenceNumber>().map_err(|_| ImapError::InvalidArgument(format!("Invalid sequence set: {:?}", set))));
+			let end = match bounds.next() {
+				Some(end) => try!(end.parse::<SequenceNumber>().map_err(|_| ImapError::InvalidArgument(format!("Invalid sequence set: {:?}", set)))),
+				None => start
+			};
+			ranges.push((start, end));
+		}
+		Ok(SequenceSet { ranges: ranges, invalid: None })
+	}
+}
+
+impl From<u32> for SequenceSet {
+	fn from(number: u32) -> SequenceSet {
+		SequenceSet::from(number..=number)
+	}
+}
+
+impl From<RangeInclusive<u32>> for SequenceSet {
+	fn from(range: RangeInclusive<u32>) -> SequenceSet {
+		if *range.start() == 0 || *range.end() == 0 {
+			return SequenceSet::invalid("Sequence numbers start at 1".to_string());
+		}
+		SequenceSet { ranges: vec![(SequenceNumber::Number(*range.start()), SequenceNumber::Number(*range.end()))], invalid: None }
+	}
+}
+
+/// `n..` is the range `n:*` from a number to the last message.
+impl From<RangeFrom<u32>> for SequenceSet {
+	fn from(range: RangeFrom<u32>) -> SequenceSet {
+		if range.start == 0 {
+			return SequenceSet::invalid("Sequence numbers start at 1".to_string());
+		}
+		SequenceSet { ranges: vec![(SequenceNumber::Number(range.start), SequenceNumber::Last)], invalid: None }
+	}
+}
+
+/// Parses the text, keeping any error until the set is sent.
+impl<'a> From<&'a str> for SequenceSet {
+	fn from(set: &'a str) -> SequenceSet {
+		match set.parse::<SequenceSet>() {
+			Ok(set) => set,
+			Err(ImapError::InvalidArgument(error)) => SequenceSet::invalid(error),
+			Err(e) => SequenceSet::invalid(e.to_string())
+		}
+	}
+}
+
+impl<'a> From<&'a String> for SequenceSet {
+	fn from(set: &'a String) -> SequenceSet {
+		SequenceSet::from(&**set)
+	}
+}
+
+impl From<String> for SequenceSet {
+	fn from(set: String) -> SequenceSet {
+		SequenceSet::from(&*set)
+	}
+}
+
+/// Collapses runs of consecutive numbers into ranges, so `[1, 2, 3, 5]` becomes `1:3,5`. The
+/// numbers are expected in ascending order.
+impl<'a> From<&'a [u32]> for SequenceSet {
+	fn from(numbers: &'a [u32]) -> SequenceSet {
+		let mut set = SequenceSet::new();
+		let mut iter = numbers.iter().cloned().peekable();
+		while let Some(start) = iter.next() {
+			let mut end = start;
+			while end.checked_add(1).map_or(false, |next| iter.peek() == Some(&next)) {
+				end = iter.next().unwrap();
+			}
+			set.push(start..=end);
+		}
+		set
+	}
+}
+
+#[test]
+fn sequence_sets_render_and_validate() {
+    let mut set = SequenceSet::from(1..=4);
+    set.push(7);
+    set.push(10..);
+    set.push(SequenceSet::last());
+    assert_eq!(set.to_argument().unwrap(), "1:4,7,10:*,*");
+    assert_eq!(SequenceSet::from("2,4:7,9,12:*").to_argument().unwrap(), "2,4:7,9,12:*");
+    assert_eq!(SequenceSet::all().to_string(), "1:*");
+    assert!(SequenceSet::from("1,,5").to_argument().is_err());
+    assert!(SequenceSet::from("").to_argument().is_err());
+    assert!(SequenceSet::from("0:3").to_argument().is_err());
+    assert!(SequenceSet::from("1:x").to_argument().is_err());
+    assert!(SequenceSet::from(0).to_argument().is_err());
+    assert!(SequenceSet::new().to_argument().is_err());
+    assert_eq!(SequenceSet::from(&[2, 4, 5, 6, 7, 9][..]).to_string(), "2,4:7,9");
+    assert_eq!(SequenceSet::from(&[4294967294, 4294967295][..]).to_string(), "4294967294:4294967295");
+    match SequenceSet::from("1:x").to_argument() {
+        Err(ImapError::InvalidArgument(ref message)) => assert_eq!(message, "Invalid sequence set: \"1:x\""),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
@@ -0,0 +1,85 @@
+use parser::Token;
+
+/// A message in a conversation thread, as returned by the THREAD command, along with the
+/// messages that reply to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadNode {
+	/// The message sequence number. A thread whose root message is missing from the mailbox has
+	/// a root with an id of 0, so its replies are still grouped together.
+	pub id: u32,
+	pub children: Vec<ThreadNode>
+}
+
+impl ThreadNode {
+	/// Builds a thread from one of the parenthesized lists of a THREAD response, such as
+	/// `(3 6 (4)(22))`.
+	pub fn from_token(token: &Token) -> Option<ThreadNode> {
+		match token.as_list() {
+			Some(items) if !items.is_empty() => from_items(items),
+			_ => None
+		}
+	}
+}
+
+/// Drops the tree without recursing, so dropping a long reply chain can't overflow the stack.
+impl Drop for ThreadNode {
+	fn drop(&mut self) {
+		let mut pending = ::std::mem::replace(&mut self.children, Vec::new());
+		while let Some(mut node) = pending.pop() {
+			pending.extend(node.children.drain(..));
+		}
+	}
+}
+
+/// Builds a thread from the items of a list: a chain of message numbers, each the parent of the
+/// next, optionally followed by the branches under the last one. The chain is built from its
+/// end, since a long reply chain would nest too deeply to build recursively.
+fn from_items(items: &[Token]) -> Option<ThreadNode> {
+	if let Token::List(_) = items[0] {
+		return branches(items).map(|children| ThreadNode { id: 0, children: children });
+	}
+
+	let chain_length = items.iter().take_while(|item| match **item { Token::Atom(_) => true, _ => false }).count();
+	if chain_length == 0 {
+		return None;
+	}
+	let mut children = match branches(&items[chain_length..]) {
+		Some(children) => children,
+		None => return None
+	};
+	for item in items[..chain_length].iter().rev() {
+		let id = match *item {
+			Token::Atom(ref id) => match id.parse::<u32>() {
+				Ok(id) => id,
+				Err(_) => return None
+			},
+			_ => return None
+		};
+		children = vec![ThreadNode { id: id, children: children }];
+	}
+	children.pop()
+}
+
+fn branches(items: &[Token]) -> Option<Vec<ThreadNode>> {
+	let mut children = Vec::with_capacity(items.len());
+	for item in items.iter() {
+		match ThreadNode::from_token(item) {
+			Some(child) => children.push(child),
+			None => return None
+		}
+	}
+	Some(children)
+}
+
+#[test]
+fn from_token_builds_long_reply_chains() {
+    let ids: Vec<String> = (1..100001).map(|id| id.to_string()).collect();
+    let chain = ThreadNode::from_token(&Token::List(ids.into_iter().map(Token::Atom).collect())).unwrap();
+    let mut node = &chain;
+    let mut length = 1;
+    while let Some(child) = node.children.first() {
+        node = child;
+        length += 1;
+    }
+    assert_eq!((chain.id, node.id, length), (1, 100000, 100000));
+}
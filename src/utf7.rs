@@ -0,0 +1,117 @@
+//! Modified UTF-7 encoding of international mailbox names, as described in RFC 3501 section 5.1.3.
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Encodes a mailbox name into IMAP's modified UTF-7.
+pub fn encode_utf7(name: &str) -> String {
+	let mut encoded = String::with_capacity(name.len());
+	let mut pending: Vec<u16> = Vec::new();
+
+	for c in name.chars() {
+		if (' '..='~').contains(&c) {
+			flush_utf16(&mut encoded, &mut pending);
+			if c == '&' {
+				encoded.push_str("&-");
+			} else {
+				encoded.push(c);
+			}
+		} else {
+			let mut buf = [0u16; 2];
+			pending.extend_from_slice(c.encode_utf16(&mut buf));
+		}
+	}
+	flush_utf16(&mut encoded, &mut pending);
+
+	encoded
+}
+
+/// Decodes a mailbox name from IMAP's modified UTF-7. Segments that are not valid modified
+/// base64 are left as they were received.
+pub fn decode_utf7(raw: &str) -> String {
+	let mut decoded = String::with_capacity(raw.len());
+	let mut rest = raw;
+
+	while let Some(start) = rest.find('&') {
+		decoded.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		let end = match rest.find('-') {
+			Some(end) => end,
+			None => break
+		};
+		let segment = &rest[1..end];
+		if segment.is_empty() {
+			decoded.push('&');
+		} else {
+			match decode_base64_utf16(segment) {
+				Some(text) => decoded.push_str(&text),
+				None => decoded.push_str(&rest[..end + 1])
+			}
+		}
+		rest = &rest[end + 1..];
+	}
+	decoded.push_str(rest);
+
+	decoded
+}
+
+fn flush_utf16(encoded: &mut String, pending: &mut Vec<u16>) {
+	if pending.is_empty() {
+		return;
+	}
+
+	let mut bits: u32 = 0;
+	let mut bit_count = 0;
+	encoded.push('&');
+	for unit in pending.iter() {
+		bits = (bits << 16) | *unit as u32;
+		bit_count += 16;
+		while bit_count >= 6 {
+			bit_count -= 6;
+			encoded.push(BASE64_ALPHABET[((bits >> bit_count) & 0x3f) as usize] as char);
+		}
+	}
+	if bit_count > 0 {
+		encoded.push(BASE64_ALPHABET[((bits << (6 - bit_count)) & 0x3f) as usize] as char);
+	}
+	encoded.push('-');
+
+	pending.clear();
+}
+
+fn decode_base64_utf16(segment: &str) -> Option<String> {
+	let mut bits: u32 = 0;
+	let mut bit_count = 0;
+	let mut units: Vec<u16> = Vec::new();
+
+	for b in segment.bytes() {
+		let value = match BASE64_ALPHABET.iter().position(|&a| a == b) {
+			Some(value) => value as u32,
+			None => return None
+		};
+		bits = (bits << 6) | value;
+		bit_count += 6;
+		if bit_count >= 16 {
+			bit_count -= 16;
+			units.push(((bits >> bit_count) & 0xffff) as u16);
+		}
+	}
+
+	String::from_utf16(&units).ok()
+}
+
+#[test]
+fn utf7_round_trip() {
+    let names = ["Eingang/Gelöscht", "Entwürfe", "~peter/mail/台北/日本語", "Tom & Jerry", "INBOX", "Привет, мир"];
+    for name in names.iter() {
+        assert_eq!(decode_utf7(&encode_utf7(name)), *name);
+    }
+}
+
+#[test]
+fn utf7_known_encodings() {
+    assert_eq!(encode_utf7("~peter/mail/台北/日本語"), "~peter/mail/&U,BTFw-/&ZeVnLIqe-");
+    assert_eq!(encode_utf7("Eingang/Gelöscht"), "Eingang/Gel&APY-scht");
+    assert_eq!(encode_utf7("Tom & Jerry"), "Tom &- Jerry");
+    assert_eq!(decode_utf7("Tom &- Jerry"), "Tom & Jerry");
+}